@@ -0,0 +1,44 @@
+// 大きなソースを字句解析する時間を計る。cargo bench --bench scanner で実行する
+// 字句解析器のソースを直接取り込むので、ここで使わない部分の警告は出さない
+#![allow(dead_code)]
+
+#[path = "../src/compiler/diagnostic.rs"]
+mod diagnostic;
+#[path = "../src/compiler/scanner.rs"]
+mod scanner;
+#[path = "../src/compiler/token.rs"]
+mod token;
+
+use scanner::Scanner;
+use std::time::Instant;
+
+const SOURCES: [&str; 4] = [
+    include_str!("../sample_script/class.rox"),
+    include_str!("../sample_script/closure.rox"),
+    include_str!("../sample_script/generator.rox"),
+    include_str!("../src/vm/prelude.rox"),
+];
+// キーワードと識別子だけが並ぶソース
+const WORDS: &str = "var value return result while width for format fun function this thing ";
+const ITERATIONS: u32 = 10;
+
+fn bench(name: &str, source: &str) {
+    let mut tokens = 0;
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        tokens = Scanner::new(source).tokenize().unwrap().len();
+    }
+    let elapsed = start.elapsed() / ITERATIONS;
+    println!(
+        "{}: {} bytes, {} tokens, {:?}/iter",
+        name,
+        source.len(),
+        tokens,
+        elapsed
+    );
+}
+
+fn main() {
+    bench("sample scripts", &SOURCES.join("\n").repeat(2000));
+    bench("identifiers", &WORDS.repeat(100000));
+}
@@ -0,0 +1,48 @@
+// 小さなスクリプトを実行する時間を計る。cargo bench --bench vm で実行する
+use std::{process::Command, time::Instant};
+
+const SCRIPTS: [(&str, &str); 5] = [
+    (
+        "fib",
+        "fun fib(n) { if (n < 2) { return n; } return fib(n - 2) + fib(n - 1); } print fib(25);",
+    ),
+    (
+        "loop",
+        "var i = 0; var sum = 0; while (i < 3000000) { sum = sum + i; i = i + 1; } print sum;",
+    ),
+    (
+        "local loop",
+        "fun f() { var i = 0; var sum = 0; while (i < 3000000) { sum = sum + i; i = i + 1; } print sum; } f();",
+    ),
+    (
+        "method",
+        "class A { fun get() { return 1; } } class B < A { } class C < B { fun inc(n) { return n + this.get(); } } \
+         fun f() { var c = C(); var i = 0; while (i < 300000) { i = c.inc(i); } print i; } f();",
+    ),
+    (
+        "property",
+        "class P { fun init() { this.a = 0; this.b = 1; this.c = 2; this.d = 3; } } \
+         fun f() { var p = P(); var i = 0; while (i < 300000) { p.a = p.a + p.d; i = i + 1; } print p.a; } f();",
+    ),
+];
+const ITERATIONS: u32 = 5;
+
+fn main() {
+    let dir = std::env::temp_dir();
+    for (name, source) in SCRIPTS {
+        let path = dir.join(format!("rox_bench_{}.rox", name.replace(' ', "_")));
+        std::fs::write(&path, source).unwrap();
+        let start = Instant::now();
+        for _ in 0..ITERATIONS {
+            let output = Command::new(env!("CARGO_BIN_EXE_rox"))
+                .arg("--no-prelude")
+                .arg("-i")
+                .arg(&path)
+                .output()
+                .unwrap();
+            assert!(output.status.success(), "{} failed.", name);
+        }
+        println!("{}: {:?}/iter", name, start.elapsed() / ITERATIONS);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
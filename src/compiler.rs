@@ -1,24 +1,28 @@
 use self::{
-    ast::{ExpressionNode, StatementNode},
+    ast::{ExpressionNode, Program, StatementNode},
+    diagnostic::Diagnostic,
     object::{FunctionObject, FunctionType},
-    scope::{Local, Upvalue},
+    parser::Parser,
+    scope::{Local, Loop, Upvalue},
 };
 use crate::vm::{
     chunk::{
-        OP_ADD, OP_ARRAY, OP_CALL, OP_CLASS, OP_CLOSE_UPVALUE, OP_CLOSURE, OP_CONSTANT, OP_COUNTUP,
-        OP_DEFINE_GLOBAL, OP_DIVIDE, OP_EQUAL, OP_FALSE, OP_GET_GLOBAL, OP_GET_LOCAL, OP_GET_PROP,
-        OP_GET_SUPER, OP_GET_UPVALUE, OP_GREATER, OP_INDEX_CALL, OP_INDEX_SET, OP_INHERIT,
-        OP_CONSTANT0, OP_INVOKE, OP_JUMP, OP_JUMP_IF_FALSE, OP_JUMP_IF_RANGE_END, OP_LESS,
-        OP_LOOP, OP_METHOD, OP_MULTIPLY, OP_NEGATIVE, OP_NOT, OP_NULL, OP_POP, OP_POW, OP_PRINT,
-        OP_RANGE, OP_REM, OP_RETURN, OP_SET_GLOBAL, OP_SET_LOCAL, OP_SET_PROP, OP_SET_UPVALUE,
-        OP_SUBTRACT, OP_SUPER_INVOKE, OP_TRUE,
+        OP_ADD, OP_ARRAY, OP_ARRAY_PUSH, OP_CALL, OP_CALL_NAMED, OP_CLASS, OP_CLOSE_UPVALUE,
+        OP_CLOSURE, OP_CONSTANT, OP_CONSTANT0, OP_CONSTANT_SMALL, OP_CONTAINS, OP_COUNTUP,
+        OP_DEFINE_GLOBAL, OP_DIVIDE, OP_EQUAL, OP_FALSE, OP_GET_GLOBAL, OP_GET_ITER, OP_GET_LOCAL,
+        OP_GET_PROP, OP_GET_SUPER, OP_GET_UPVALUE, OP_GREATER, OP_INDEX_CALL, OP_INDEX_SET,
+        OP_INHERIT, OP_INVOKE, OP_JUMP, OP_JUMP_IF_FALSE, OP_JUMP_IF_RANGE_END, OP_LESS, OP_LOOP,
+        OP_METHOD, OP_MULTIPLY, OP_NEGATIVE, OP_NOT, OP_NULL, OP_POP, OP_POW, OP_PRINT, OP_RANGE,
+        OP_REM, OP_RETURN, OP_SET_GLOBAL, OP_SET_LOCAL, OP_SET_PROP, OP_SET_UPVALUE, OP_SUBTRACT,
+        OP_SUPER_INVOKE, OP_TRUE, OP_YIELD,
     },
     value::Value,
 };
 use core::panic;
-use std::{cell::RefCell, rc::Rc};
+use std::{cell::RefCell, fs, path::PathBuf, rc::Rc};
 
 pub mod ast;
+pub mod diagnostic;
 pub mod object;
 pub mod parser;
 pub mod scanner;
@@ -27,6 +31,11 @@ pub mod token;
 
 const LOCAL_MAX: usize = 256;
 const UPVALUE_MAX: usize = 32;
+// コンパイラが内部で使うローカル変数。識別子に "@" は使えないので、ユーザーの変数とは衝突しない
+const RANGE_COUNTER_LOCAL: &str = "@range_counter";
+const RANGE_LOCAL: &str = "@range";
+const RESULT_LOCAL: &str = "@result";
+const RETURN_LOCAL: &str = "@return";
 
 #[derive(Debug, PartialEq, PartialOrd)]
 pub struct ClassCompiler {
@@ -43,7 +52,7 @@ impl ClassCompiler {
     }
 }
 
-#[derive(Debug, PartialEq, PartialOrd)]
+#[derive(Debug)]
 pub struct Compiler {
     pub enclosing: Option<Rc<RefCell<Compiler>>>,
     pub function: FunctionObject,
@@ -52,6 +61,24 @@ pub struct Compiler {
     pub upvalues: [Upvalue; UPVALUE_MAX],
     pub scope_depth: i32,
     pub local_count: usize,
+    // 現在コンパイル中の文の行。命令と一緒にチャンクへ記録する
+    pub line: u32,
+    // defer された文。return の直前に登録と逆順で出力する
+    pub deferred: Vec<StatementNode>,
+    // defer を書ける関数本体のスコープの深さ
+    pub body_depth: Option<i32>,
+    // 読まれないままスコープを抜けたローカル変数を警告する
+    pub warn_unused: bool,
+    // コンパイル中のファイル。末尾が現在のファイルで、import の相対パスの基準になる
+    pub importing: Vec<PathBuf>,
+    // import 済みのファイル。同じファイルは2回読み込まない
+    pub imported: Vec<PathBuf>,
+    // コンパイル中のループ。末尾が一番内側で、break の飛び先になる
+    pub loops: Vec<Loop>,
+    // プレリュードや import したファイルなど、実行するプログラム以外のソースをコンパイルしている
+    pub external: bool,
+    // 読まれないローカル変数の警告。入れ子の関数の分もトップレベルのコンパイラに集める
+    pub warnings: Vec<Diagnostic>,
 }
 
 impl Compiler {
@@ -63,6 +90,10 @@ impl Compiler {
     ) -> Self {
         let locals = vec![Local::new("", 0); LOCAL_MAX];
         let upvalues = vec![Upvalue::new(0, false); UPVALUE_MAX];
+        let (warn_unused, external) = match &enclosing {
+            Some(enclosing) => (enclosing.borrow().warn_unused, enclosing.borrow().external),
+            None => (false, false),
+        };
         let mut compiler = Self {
             enclosing,
             function: FunctionObject::new(name, arity),
@@ -71,6 +102,15 @@ impl Compiler {
             upvalues: upvalues.try_into().unwrap(),
             scope_depth: 0,
             local_count: 0,
+            line: 0,
+            deferred: Vec::new(),
+            body_depth: None,
+            warn_unused,
+            importing: Vec::new(),
+            imported: Vec::new(),
+            loops: Vec::new(),
+            external,
+            warnings: Vec::new(),
         };
         let local = match &compiler.function_type {
             FunctionType::Function => Local::new("", 0),
@@ -82,6 +122,53 @@ impl Compiler {
         compiler
     }
 
+    // ソース全体を1つのスクリプトとしてコンパイルする。最後に null を返すので、呼び出して実行できる。
+    // プレリュードや include するファイルに使うので、実行時エラーの位置は呼び出し側の行にする
+    pub fn compile_script(name: &str, source: &str) -> Result<FunctionObject, String> {
+        let program = Parser::new(source)
+            .parse()
+            .map_err(|e| format!("{}: {}", name, e))?;
+        Self::compile(name, program, true).map_err(|e| e.message)
+    }
+
+    // ソースをコンパイルして、入れ子の関数も含めた逆アセンブル結果を返す。
+    // --disassemble の出力はこれで作るので、生成されるバイトコードを文字列で比較できる
+    pub fn compile_to_disassembly(source: &str) -> Result<String, String> {
+        let function = Self::compile_script("__main__", source)?;
+        Ok(function.chunk.disassembly(&function.name))
+    }
+
+    pub fn compile_program(name: &str, program: Program) -> Result<FunctionObject, Diagnostic> {
+        Self::compile(name, program, false)
+    }
+
+    // エラーにはエラーになった文の行を付ける
+    fn compile(name: &str, program: Program, external: bool) -> Result<FunctionObject, Diagnostic> {
+        let compiler = Rc::new(RefCell::new(Compiler::new(
+            name,
+            FunctionType::Script,
+            0,
+            None,
+        )));
+        compiler.borrow_mut().external = external;
+        let class_compiler = Rc::new(RefCell::new(ClassCompiler::new()));
+        for stmt in program.stmts {
+            Self::compile_stmt(Rc::clone(&compiler), class_compiler.clone(), stmt).map_err(
+                |e| {
+                    Diagnostic::new(format!("{}: {}", name, e))
+                        .at_line(compiler.borrow().line as i32)
+                },
+            )?;
+        }
+        Self::emit_byte(Rc::clone(&compiler), OP_NULL);
+        Self::emit_byte(Rc::clone(&compiler), OP_RETURN);
+        if external {
+            compiler.borrow_mut().function.chunk.mark_external(0);
+        }
+        let function = compiler.borrow().function.clone();
+        Ok(function)
+    }
+
     fn function(
         compiler: Rc<RefCell<Compiler>>,
         class_compiler: Rc<RefCell<ClassCompiler>>,
@@ -89,7 +176,7 @@ impl Compiler {
         name: ExpressionNode,
         params: Vec<ExpressionNode>,
         body: Box<StatementNode>,
-    ) {
+    ) -> Result<(), String> {
         let name = match name {
             ExpressionNode::Identifer(name) => name,
             _ => todo!(),
@@ -100,17 +187,36 @@ impl Compiler {
             params.len() as i32,
             Some(Rc::clone(&compiler)),
         )));
+        new_compiler.borrow_mut().line = compiler.borrow().line;
         Self::begin_scope(Rc::clone(&new_compiler));
         for param in &params {
             let param_name = match param {
                 ExpressionNode::Identifer(name) => name,
                 _ => todo!(),
             };
+            new_compiler
+                .borrow_mut()
+                .function
+                .params
+                .push(param_name.clone());
             if Self::get_scope_depth(Rc::clone(&new_compiler)) > 0 {
-                Self::add_local(Rc::clone(&new_compiler), param_name).unwrap();
+                Self::add_local(Rc::clone(&new_compiler), param_name)?;
+            }
+        }
+        match *body {
+            // 関数本体のブロックは、defer された文を実行してからスコープを閉じる
+            StatementNode::Block { stmts, .. } => {
+                Self::begin_scope(Rc::clone(&new_compiler));
+                let body_depth = Self::get_scope_depth(Rc::clone(&new_compiler));
+                new_compiler.borrow_mut().body_depth = Some(body_depth);
+                for stmt in stmts {
+                    Self::compile_stmt(Rc::clone(&new_compiler), class_compiler.clone(), stmt)?;
+                }
+                Self::emit_deferred(Rc::clone(&new_compiler), class_compiler.clone())?;
+                Self::end_scope(Rc::clone(&new_compiler));
             }
+            body => Self::compile_stmt(Rc::clone(&new_compiler), class_compiler.clone(), body)?,
         }
-        Self::compile_stmt(Rc::clone(&new_compiler), class_compiler.clone(), *body);
 
         match &ftype {
             FunctionType::Init => Self::emit_bytes(Rc::clone(&new_compiler), OP_GET_LOCAL, 0),
@@ -119,26 +225,7 @@ impl Compiler {
         Self::emit_byte(Rc::clone(&new_compiler), OP_RETURN);
         Self::end_scope(Rc::clone(&new_compiler));
 
-        let index = compiler
-            .borrow_mut()
-            .function
-            .chunk
-            .add_constant(Value::Function(Rc::new(
-                new_compiler.borrow().function.clone(),
-            )));
-        Self::emit_bytes(Rc::clone(&compiler), OP_CLOSURE, index);
-
-        {
-            let range = 0..new_compiler.borrow().function.upvalue_count;
-            let upvalues = &new_compiler.borrow().upvalues;
-            for index in range {
-                Self::emit_bytes(
-                    Rc::clone(&compiler),
-                    if upvalues[index].is_local { 1u8 } else { 0u8 },
-                    upvalues[index].index as u8,
-                );
-            }
-        }
+        Self::emit_closure(Rc::clone(&compiler), Rc::clone(&new_compiler));
 
         let index = compiler
             .borrow_mut()
@@ -154,18 +241,249 @@ impl Compiler {
             FunctionType::Method => Self::emit_bytes(Rc::clone(&compiler), OP_METHOD, index),
             FunctionType::Init => Self::emit_bytes(Rc::clone(&compiler), OP_METHOD, index),
         };
+        Ok(())
+    }
+
+    // for 文のループ開始部分。ループの先頭位置と終了時のジャンプ位置を返す
+    fn begin_for(
+        compiler: Rc<RefCell<Compiler>>,
+        class_compiler: Rc<RefCell<ClassCompiler>>,
+        name: ExpressionNode,
+        range: ExpressionNode,
+    ) -> Result<(usize, usize), String> {
+        // 独自実装で自信なし。より良いやり方確認要
+        Self::begin_scope(Rc::clone(&compiler));
+        Self::emit_byte(Rc::clone(&compiler), OP_CONSTANT0);
+        Self::add_local(Rc::clone(&compiler), RANGE_COUNTER_LOCAL)?;
+
+        // 繰り返し対象は最初に1度だけ評価する
+        Self::compile_exp(Rc::clone(&compiler), class_compiler.clone(), range)?;
+        Self::emit_byte(Rc::clone(&compiler), OP_GET_ITER);
+        Self::add_local(Rc::clone(&compiler), RANGE_LOCAL)?;
+
+        let start_loop = {
+            let chunk = &compiler.borrow().function.chunk;
+            chunk.get_instruction_len()
+        };
+
+        if let Some(index) = Self::get_local(Rc::clone(&compiler), RANGE_COUNTER_LOCAL) {
+            Self::emit_bytes(Rc::clone(&compiler), OP_GET_LOCAL, index);
+        }
+        if let Some(index) = Self::get_local(Rc::clone(&compiler), RANGE_LOCAL) {
+            Self::emit_bytes(Rc::clone(&compiler), OP_GET_LOCAL, index);
+        }
+        let exit_jump = Self::emit_jump(Rc::clone(&compiler), OP_JUMP_IF_RANGE_END);
+        if let Some(index) = Self::get_local(Rc::clone(&compiler), RANGE_COUNTER_LOCAL) {
+            Self::emit_bytes(Rc::clone(&compiler), OP_COUNTUP, index);
+        }
+        // -- ローカル変数定義 --
+        let name = match name {
+            ExpressionNode::Identifer(name) => name,
+            _ => todo!(),
+        };
+        Self::add_local(Rc::clone(&compiler), name)?;
+        // -- ローカル変数定義 --
+        Ok((start_loop, exit_jump))
+    }
+
+    fn end_for(
+        compiler: Rc<RefCell<Compiler>>,
+        start_loop: usize,
+        exit_jump: usize,
+    ) -> Result<(), String> {
+        // ループ変数は繰り返しごとに積み直すので、クロージャに捕捉されていればここで閉じる
+        let is_captured = {
+            let compiler = compiler.borrow();
+            compiler.locals[compiler.local_count - 1].is_captured
+        };
+        if is_captured {
+            Self::emit_byte(Rc::clone(&compiler), OP_CLOSE_UPVALUE);
+        } else {
+            Self::emit_byte(Rc::clone(&compiler), OP_POP);
+        }
+        Self::emit_loop(Rc::clone(&compiler), start_loop)?;
+        Self::patch_jump(Rc::clone(&compiler), exit_jump)?;
+        Self::end_scope(Rc::clone(&compiler));
+        Ok(())
     }
 
+    // 内包表記は引数なしの関数として即時に呼び出す。
+    // 式の途中でスタックに値が積まれていてもローカル変数の位置がずれないようにするため。
+    fn comprehension(
+        compiler: Rc<RefCell<Compiler>>,
+        class_compiler: Rc<RefCell<ClassCompiler>>,
+        element: ExpressionNode,
+        loops: Vec<(ExpressionNode, ExpressionNode)>,
+        condition: Option<Box<ExpressionNode>>,
+    ) -> Result<(), String> {
+        let new_compiler = Rc::new(RefCell::new(Compiler::new(
+            "__comprehension__",
+            FunctionType::Function,
+            0,
+            Some(Rc::clone(&compiler)),
+        )));
+        new_compiler.borrow_mut().line = compiler.borrow().line;
+        Self::begin_scope(Rc::clone(&new_compiler));
+        Self::emit_bytes(Rc::clone(&new_compiler), OP_ARRAY, 0);
+        Self::add_local(Rc::clone(&new_compiler), RESULT_LOCAL)?;
+
+        let mut jumps = Vec::new();
+        for (name, iterable) in loops {
+            jumps.push(Self::begin_for(
+                Rc::clone(&new_compiler),
+                class_compiler.clone(),
+                name,
+                iterable,
+            )?);
+        }
+        let skip_jump = match condition {
+            Some(condition) => {
+                Self::compile_exp(Rc::clone(&new_compiler), class_compiler.clone(), *condition)?;
+                let skip_jump = Self::emit_jump(Rc::clone(&new_compiler), OP_JUMP_IF_FALSE);
+                Self::emit_byte(Rc::clone(&new_compiler), OP_POP);
+                Some(skip_jump)
+            }
+            None => None,
+        };
+        if let Some(index) = Self::get_local(Rc::clone(&new_compiler), RESULT_LOCAL) {
+            Self::emit_bytes(Rc::clone(&new_compiler), OP_GET_LOCAL, index);
+        }
+        Self::compile_exp(Rc::clone(&new_compiler), class_compiler.clone(), element)?;
+        Self::emit_byte(Rc::clone(&new_compiler), OP_ARRAY_PUSH);
+        if let Some(skip_jump) = skip_jump {
+            let end_jump = Self::emit_jump(Rc::clone(&new_compiler), OP_JUMP);
+            Self::patch_jump(Rc::clone(&new_compiler), skip_jump)?;
+            Self::emit_byte(Rc::clone(&new_compiler), OP_POP);
+            Self::patch_jump(Rc::clone(&new_compiler), end_jump)?;
+        }
+        for (start_loop, exit_jump) in jumps.into_iter().rev() {
+            Self::end_for(Rc::clone(&new_compiler), start_loop, exit_jump)?;
+        }
+
+        if let Some(index) = Self::get_local(Rc::clone(&new_compiler), RESULT_LOCAL) {
+            Self::emit_bytes(Rc::clone(&new_compiler), OP_GET_LOCAL, index);
+        }
+        Self::emit_byte(Rc::clone(&new_compiler), OP_RETURN);
+        Self::end_scope(Rc::clone(&new_compiler));
+
+        Self::emit_closure(Rc::clone(&compiler), new_compiler);
+        Self::emit_bytes(Rc::clone(&compiler), OP_CALL, 0);
+        Ok(())
+    }
+
+    // import したファイルのトップレベルの文を、そのままスクリプトのグローバルとしてコンパイルする
+    fn import(
+        compiler: Rc<RefCell<Compiler>>,
+        class_compiler: Rc<RefCell<ClassCompiler>>,
+        path: String,
+    ) -> Result<(), String> {
+        if compiler.borrow().function_type != FunctionType::Script
+            || Self::get_scope_depth(Rc::clone(&compiler)) > 0
+        {
+            return Err("\"import\" can only be used at the top level of a script.".to_string());
+        }
+
+        let resolved = match compiler.borrow().importing.last() {
+            Some(current) => match current.parent() {
+                Some(dir) => dir.join(&path),
+                None => PathBuf::from(&path),
+            },
+            None => PathBuf::from(&path),
+        };
+        let resolved = fs::canonicalize(&resolved)
+            .map_err(|e| format!("Can't import \"{}\".({})", path, e))?;
+        if compiler.borrow().importing.contains(&resolved) {
+            return Err(format!("Import cycle detected.({})", resolved.display()));
+        }
+        if compiler.borrow().imported.contains(&resolved) {
+            return Ok(());
+        }
+
+        let source = fs::read_to_string(&resolved)
+            .map_err(|e| format!("Can't import \"{}\".({})", path, e))?;
+        let program = Parser::new(&source)
+            .parse()
+            .map_err(|e| format!("{}: {}", resolved.display(), e))?;
+
+        let line = compiler.borrow().line;
+        let external = compiler.borrow().external;
+        let start = compiler.borrow().function.chunk.get_instruction_len();
+        compiler.borrow_mut().importing.push(resolved.clone());
+        compiler.borrow_mut().external = true;
+        let mut result = Ok(());
+        for stmt in program.stmts {
+            result = Self::compile_stmt(Rc::clone(&compiler), class_compiler.clone(), stmt);
+            if result.is_err() {
+                break;
+            }
+        }
+        compiler.borrow_mut().importing.pop();
+        compiler.borrow_mut().external = external;
+        // エラーの場合も import 文の行に戻す
+        compiler.borrow_mut().line = line;
+        if let Err(e) = result {
+            return Err(format!("{}: {}", resolved.display(), e));
+        }
+        compiler.borrow_mut().function.chunk.mark_external(start);
+        compiler.borrow_mut().imported.push(resolved);
+        Ok(())
+    }
+
+    fn emit_closure(compiler: Rc<RefCell<Compiler>>, new_compiler: Rc<RefCell<Compiler>>) {
+        if new_compiler.borrow().external {
+            new_compiler.borrow_mut().function.chunk.mark_external(0);
+        }
+        let index = compiler
+            .borrow_mut()
+            .function
+            .chunk
+            .add_constant(Value::Function(Rc::new(
+                new_compiler.borrow().function.clone(),
+            )));
+        Self::emit_bytes(Rc::clone(&compiler), OP_CLOSURE, index);
+
+        let range = 0..new_compiler.borrow().function.upvalue_count;
+        let upvalues = &new_compiler.borrow().upvalues;
+        for index in range {
+            Self::emit_bytes(
+                Rc::clone(&compiler),
+                if upvalues[index].is_local { 1u8 } else { 0u8 },
+                upvalues[index].index as u8,
+            );
+        }
+    }
+
+    // エラーになった場合は、その文の行を外側の関数のコンパイラにも伝える。
+    // トップレベルのコンパイラの line を見れば、どの行でエラーになったか分かる
     pub fn compile_stmt(
         compiler: Rc<RefCell<Compiler>>,
         class_compiler: Rc<RefCell<ClassCompiler>>,
         stmt: StatementNode,
-    ) {
+    ) -> Result<(), String> {
+        let result = Self::compile_stmt_node(Rc::clone(&compiler), class_compiler, stmt);
+        if result.is_err() {
+            let line = compiler.borrow().line;
+            let mut enclosing = compiler.borrow().enclosing.clone();
+            while let Some(outer) = enclosing {
+                outer.borrow_mut().line = line;
+                enclosing = outer.borrow().enclosing.clone();
+            }
+        }
+        result
+    }
+
+    fn compile_stmt_node(
+        compiler: Rc<RefCell<Compiler>>,
+        class_compiler: Rc<RefCell<ClassCompiler>>,
+        stmt: StatementNode,
+    ) -> Result<(), String> {
+        compiler.borrow_mut().line = stmt.line() as u32;
         match stmt {
             StatementNode::Class {
                 name: class_name,
                 body: class_body,
                 super_class,
+                ..
             } => {
                 let name = match class_name {
                     ExpressionNode::Identifer(name) => name,
@@ -178,7 +496,7 @@ impl Compiler {
                     .add_constant(Value::String(Rc::new(name.clone())));
 
                 if Self::get_scope_depth(Rc::clone(&compiler)) > 0 {
-                    Self::add_local(Rc::clone(&compiler), &name).unwrap();
+                    Self::add_local(Rc::clone(&compiler), &name)?;
                     Self::emit_bytes(Rc::clone(&compiler), OP_CLASS, index);
                 } else {
                     Self::emit_bytes(Rc::clone(&compiler), OP_CLASS, index);
@@ -207,7 +525,7 @@ impl Compiler {
                         {
                             Self::emit_bytes(Rc::clone(&compiler), OP_GET_LOCAL, index);
                         } else if let Some(index) =
-                            Self::get_upvalue(Rc::clone(&compiler), &super_class_name)
+                            Self::get_upvalue(Rc::clone(&compiler), &super_class_name)?
                         {
                             Self::emit_bytes(Rc::clone(&compiler), OP_GET_UPVALUE, index);
                         } else {
@@ -221,13 +539,14 @@ impl Compiler {
 
                         // ------
                         Self::begin_scope(Rc::clone(&compiler));
-                        Self::add_local(Rc::clone(&compiler), "super").unwrap();
+                        Self::add_local(Rc::clone(&compiler), "super")?;
                         // ------
 
                         // namedVariable
                         if let Some(index) = Self::get_local(Rc::clone(&compiler), &name) {
                             Self::emit_bytes(Rc::clone(&compiler), OP_GET_LOCAL, index);
-                        } else if let Some(index) = Self::get_upvalue(Rc::clone(&compiler), &name) {
+                        } else if let Some(index) = Self::get_upvalue(Rc::clone(&compiler), &name)?
+                        {
                             Self::emit_bytes(Rc::clone(&compiler), OP_GET_UPVALUE, index);
                         } else {
                             let index = compiler
@@ -246,7 +565,7 @@ impl Compiler {
 
                 if let Some(index) = Self::get_local(Rc::clone(&compiler), &name) {
                     Self::emit_bytes(Rc::clone(&compiler), OP_GET_LOCAL, index);
-                } else if let Some(index) = Self::get_upvalue(Rc::clone(&compiler), &name) {
+                } else if let Some(index) = Self::get_upvalue(Rc::clone(&compiler), &name)? {
                     Self::emit_bytes(Rc::clone(&compiler), OP_GET_UPVALUE, index);
                 } else {
                     let index = compiler
@@ -258,13 +577,15 @@ impl Compiler {
                 }
 
                 match *class_body {
-                    StatementNode::Block { stmts } => {
+                    StatementNode::Block { stmts, .. } => {
                         for stmt in stmts {
+                            compiler.borrow_mut().line = stmt.line() as u32;
                             match stmt {
                                 StatementNode::Fun {
                                     name: method_name,
                                     params: method_params,
                                     body: method_body,
+                                    ..
                                 } => {
                                     let ftype: FunctionType = match &method_name {
                                         ExpressionNode::Identifer(n) => {
@@ -283,7 +604,7 @@ impl Compiler {
                                         method_name,
                                         method_params,
                                         method_body,
-                                    );
+                                    )?;
                                 }
                                 invalid => panic!("invalid node. {}", invalid),
                             }
@@ -300,40 +621,20 @@ impl Compiler {
                 name,
                 range,
                 consequence,
+                ..
             } => {
-                // 独自実装で自信なし。より良いやり方確認要
-                Self::begin_scope(Rc::clone(&compiler));
-                Self::emit_byte(Rc::clone(&compiler), OP_CONSTANT0);
-                Self::add_local(Rc::clone(&compiler), "__range_counter__").unwrap();
-
-                let start_loop = {
-                    let chunk = &compiler.borrow().function.chunk;
-                    chunk.get_instruction_len()
-                };
-
-                if let Some(index) = Self::get_local(Rc::clone(&compiler), "__range_counter__") {
-                    Self::emit_bytes(Rc::clone(&compiler), OP_GET_LOCAL, index);
-                }
-                Self::compile_exp(Rc::clone(&compiler), class_compiler.clone(), range);
-                let exit_jump = Self::emit_jump(Rc::clone(&compiler), OP_JUMP_IF_RANGE_END);
-                if let Some(index) = Self::get_local(Rc::clone(&compiler), "__range_counter__") {
-                    Self::emit_bytes(Rc::clone(&compiler), OP_COUNTUP, index);
-                }
-                // -- ローカル変数定義 --
-                let name = match name {
-                    ExpressionNode::Identifer(name) => name,
-                    _ => todo!(),
-                };
-                Self::add_local(Rc::clone(&compiler), name).unwrap();
-                // -- ローカル変数定義 --
-
-                Self::compile_stmt(Rc::clone(&compiler), class_compiler.clone(), *consequence);
-                Self::emit_byte(Rc::clone(&compiler), OP_POP);
-                Self::emit_loop(Rc::clone(&compiler), start_loop).unwrap();
-                Self::patch_jump(Rc::clone(&compiler), exit_jump).unwrap();
-                Self::end_scope(Rc::clone(&compiler));
+                // break ではカウンタ、繰り返し対象、ループ変数も含めて片付けるため、
+                // for のスコープに入る前の深さでループを始める
+                Self::begin_loop(Rc::clone(&compiler));
+                let (start_loop, exit_jump) =
+                    Self::begin_for(Rc::clone(&compiler), class_compiler.clone(), name, range)?;
+                Self::compile_stmt(Rc::clone(&compiler), class_compiler.clone(), *consequence)?;
+                Self::end_for(Rc::clone(&compiler), start_loop, exit_jump)?;
+                Self::end_loop(Rc::clone(&compiler))?;
             }
-            StatementNode::Fun { name, params, body } => {
+            StatementNode::Fun {
+                name, params, body, ..
+            } => {
                 Self::function(
                     compiler.clone(),
                     class_compiler.clone(),
@@ -341,46 +642,95 @@ impl Compiler {
                     name,
                     params,
                     body,
-                );
+                )?;
             }
             StatementNode::If {
                 condition: condtion,
                 consequence,
                 alternative: alternatives,
+                ..
             } => {
-                Self::compile_exp(Rc::clone(&compiler), class_compiler.clone(), condtion);
+                Self::compile_exp(Rc::clone(&compiler), class_compiler.clone(), condtion)?;
 
                 let then_jump = Self::emit_jump(Rc::clone(&compiler), OP_JUMP_IF_FALSE);
                 Self::emit_byte(Rc::clone(&compiler), OP_POP);
-                Self::compile_stmt(Rc::clone(&compiler), class_compiler.clone(), *consequence);
+                Self::compile_stmt(Rc::clone(&compiler), class_compiler.clone(), *consequence)?;
                 let else_jump = Self::emit_jump(Rc::clone(&compiler), OP_JUMP);
-                Self::patch_jump(Rc::clone(&compiler), then_jump).unwrap();
+                Self::patch_jump(Rc::clone(&compiler), then_jump)?;
                 Self::emit_byte(Rc::clone(&compiler), OP_POP);
                 match alternatives {
                     Some(alternatives) => Self::compile_stmt(
                         Rc::clone(&compiler),
                         class_compiler.clone(),
                         *alternatives,
-                    ),
+                    )?,
                     None => {}
                 };
-                Self::patch_jump(Rc::clone(&compiler), else_jump).unwrap();
+                Self::patch_jump(Rc::clone(&compiler), else_jump)?;
             }
-            StatementNode::Return { value } => {
+            StatementNode::Return { value, .. } => {
                 match value {
                     Some(exp) => {
-                        Self::compile_exp(Rc::clone(&compiler), class_compiler.clone(), exp)
+                        Self::compile_exp(Rc::clone(&compiler), class_compiler.clone(), exp)?
                     }
                     None => Self::compile_exp(
                         Rc::clone(&compiler),
                         class_compiler.clone(),
                         ExpressionNode::NullLiteral,
-                    ),
+                    )?,
+                }
+                // 戻り値はスタックに積んだまま defer された文を実行するので、
+                // その位置をローカル変数として確保し、defer 内のローカル変数と重ならないようにする
+                if !compiler.borrow().deferred.is_empty() {
+                    Self::begin_scope(Rc::clone(&compiler));
+                    Self::add_local(Rc::clone(&compiler), RETURN_LOCAL)?;
+                    Self::emit_deferred(Rc::clone(&compiler), class_compiler.clone())?;
+                    // 戻り値は OP_RETURN で使うので、取り除かずにスコープだけ閉じる
+                    compiler.borrow_mut().scope_depth -= 1;
+                    compiler.borrow_mut().local_count -= 1;
                 }
                 Self::emit_byte(Rc::clone(&compiler), OP_RETURN);
             }
-            StatementNode::Var { name, value } => {
-                Self::compile_exp(Rc::clone(&compiler), class_compiler.clone(), value);
+            StatementNode::Import { path, .. } => {
+                Self::import(Rc::clone(&compiler), class_compiler.clone(), path)?;
+            }
+            StatementNode::Break { .. } => {
+                Self::emit_break(Rc::clone(&compiler))?;
+            }
+            StatementNode::Defer { body, .. } => {
+                let body_depth = compiler.borrow().body_depth;
+                if body_depth != Some(Self::get_scope_depth(Rc::clone(&compiler))) {
+                    return Err(
+                        "\"defer\" can only be used at the top level of a function body."
+                            .to_string(),
+                    );
+                }
+                compiler.borrow_mut().deferred.push(*body);
+            }
+            StatementNode::Yield { value, .. } => {
+                match compiler.borrow().function_type {
+                    FunctionType::Function | FunctionType::Method => {}
+                    _ => {
+                        return Err(
+                            "\"yield\" can only be used in functions and methods.".to_string()
+                        )
+                    }
+                }
+                compiler.borrow_mut().function.is_generator = true;
+                match value {
+                    Some(exp) => {
+                        Self::compile_exp(Rc::clone(&compiler), class_compiler.clone(), exp)?
+                    }
+                    None => Self::compile_exp(
+                        Rc::clone(&compiler),
+                        class_compiler.clone(),
+                        ExpressionNode::NullLiteral,
+                    )?,
+                }
+                Self::emit_byte(Rc::clone(&compiler), OP_YIELD);
+            }
+            StatementNode::Var { name, value, .. } => {
+                Self::compile_exp(Rc::clone(&compiler), class_compiler.clone(), value)?;
 
                 let name = match name {
                     ExpressionNode::Identifer(name) => name,
@@ -388,8 +738,8 @@ impl Compiler {
                 };
 
                 if Self::get_scope_depth(Rc::clone(&compiler)) > 0 {
-                    Self::add_local(Rc::clone(&compiler), name).unwrap();
-                    return;
+                    Self::add_local(Rc::clone(&compiler), name)?;
+                    return Ok(());
                 }
                 let index = compiler
                     .borrow_mut()
@@ -401,56 +751,64 @@ impl Compiler {
             StatementNode::While {
                 condition: condtion,
                 consequence,
+                ..
             } => {
                 let start_loop = {
                     let chunk = &compiler.borrow().function.chunk;
                     chunk.get_instruction_len()
                 };
-                Self::compile_exp(Rc::clone(&compiler), class_compiler.clone(), condtion);
+                Self::compile_exp(Rc::clone(&compiler), class_compiler.clone(), condtion)?;
 
                 let exit_jump = Self::emit_jump(Rc::clone(&compiler), OP_JUMP_IF_FALSE);
                 Self::emit_byte(Rc::clone(&compiler), OP_POP);
-                Self::compile_stmt(Rc::clone(&compiler), class_compiler.clone(), *consequence);
-                Self::emit_loop(Rc::clone(&compiler), start_loop).unwrap();
-                Self::patch_jump(Rc::clone(&compiler), exit_jump).unwrap();
+                Self::begin_loop(Rc::clone(&compiler));
+                Self::compile_stmt(Rc::clone(&compiler), class_compiler.clone(), *consequence)?;
+                Self::emit_loop(Rc::clone(&compiler), start_loop)?;
+                Self::patch_jump(Rc::clone(&compiler), exit_jump)?;
                 Self::emit_byte(Rc::clone(&compiler), OP_POP);
+                // 条件の値は本体の前に取り除いているので、break は条件を捨てる OP_POP の後ろへ飛ぶ
+                Self::end_loop(Rc::clone(&compiler))?;
             }
-            StatementNode::Block { stmts } => {
+            StatementNode::Block { stmts, .. } => {
                 Self::begin_scope(Rc::clone(&compiler));
                 for stmt in stmts {
-                    Self::compile_stmt(Rc::clone(&compiler), class_compiler.clone(), stmt);
+                    Self::compile_stmt(Rc::clone(&compiler), class_compiler.clone(), stmt)?;
                 }
                 Self::end_scope(Rc::clone(&compiler));
             }
-            StatementNode::Print { expression } => {
-                Self::compile_exp(Rc::clone(&compiler), class_compiler.clone(), expression);
-                Self::emit_byte(Rc::clone(&compiler), OP_PRINT);
+            StatementNode::Print { expressions, .. } => {
+                let count = expressions.len() as u8;
+                for exp in expressions {
+                    Self::compile_exp(Rc::clone(&compiler), class_compiler.clone(), exp)?;
+                }
+                Self::emit_bytes(Rc::clone(&compiler), OP_PRINT, count);
             }
-            StatementNode::ExpStmt { expression } => {
-                Self::compile_exp(Rc::clone(&compiler), class_compiler.clone(), expression);
+            StatementNode::ExpStmt { expression, .. } => {
+                Self::compile_exp(Rc::clone(&compiler), class_compiler.clone(), expression)?;
                 Self::emit_byte(Rc::clone(&compiler), OP_POP);
             }
         }
+        Ok(())
     }
 
     pub fn compile_exp(
         compiler: Rc<RefCell<Compiler>>,
         class_compiler: Rc<RefCell<ClassCompiler>>,
         expression: ExpressionNode,
-    ) {
+    ) -> Result<(), String> {
         match expression {
             ExpressionNode::Identifer(name) => {
                 if name == "this" && class_compiler.borrow().enclosing.is_none() {
-                    panic!("identifer \"this\". but no class.");
+                    return Err("\"this\" can only be used inside a class.".to_string());
                 }
 
                 if let Some(index) = Self::get_local(Rc::clone(&compiler), &name) {
                     Self::emit_bytes(Rc::clone(&compiler), OP_GET_LOCAL, index);
-                    return;
+                    return Ok(());
                 }
-                if let Some(index) = Self::get_upvalue(Rc::clone(&compiler), &name) {
+                if let Some(index) = Self::get_upvalue(Rc::clone(&compiler), &name)? {
                     Self::emit_bytes(Rc::clone(&compiler), OP_GET_UPVALUE, index);
-                    return;
+                    return Ok(());
                 }
                 let index = compiler
                     .borrow_mut()
@@ -475,6 +833,13 @@ impl Compiler {
                     .add_constant(Value::Float(value));
                 Self::emit_bytes(compiler, OP_CONSTANT, index);
             }
+            // 小さな整数は定数表を使わない
+            ExpressionNode::IntegerLiteral(0) => {
+                Self::emit_byte(compiler, OP_CONSTANT0);
+            }
+            ExpressionNode::IntegerLiteral(value @ 1..=255) => {
+                Self::emit_bytes(compiler, OP_CONSTANT_SMALL, value as u8);
+            }
             ExpressionNode::IntegerLiteral(value) => {
                 let index = compiler
                     .borrow_mut()
@@ -490,21 +855,32 @@ impl Compiler {
                     Self::emit_byte(Rc::clone(&compiler), OP_FALSE);
                 }
             }
+            ExpressionNode::Comprehension {
+                element,
+                loops,
+                condition,
+            } => Self::comprehension(
+                Rc::clone(&compiler),
+                class_compiler.clone(),
+                *element,
+                loops,
+                condition,
+            )?,
             ExpressionNode::ArrayLiteral(value) => {
                 let length = value.len();
                 for exp in value {
-                    Self::compile_exp(Rc::clone(&compiler), class_compiler.clone(), exp);
+                    Self::compile_exp(Rc::clone(&compiler), class_compiler.clone(), exp)?;
                 }
                 Self::emit_bytes(Rc::clone(&compiler), OP_ARRAY, length as u8);
             }
             ExpressionNode::RangeLiteral { start, end } => {
-                Self::compile_exp(Rc::clone(&compiler), class_compiler.clone(), *start);
-                Self::compile_exp(Rc::clone(&compiler), class_compiler.clone(), *end);
+                Self::compile_exp(Rc::clone(&compiler), class_compiler.clone(), *start)?;
+                Self::compile_exp(Rc::clone(&compiler), class_compiler.clone(), *end)?;
                 Self::emit_byte(Rc::clone(&compiler), OP_RANGE);
             }
             ExpressionNode::NullLiteral => Self::emit_byte(compiler, OP_NULL),
             ExpressionNode::Prefix { ope, right } => {
-                Self::compile_exp(Rc::clone(&compiler), class_compiler.clone(), *right);
+                Self::compile_exp(Rc::clone(&compiler), class_compiler.clone(), *right)?;
                 match ope.as_str() {
                     "-" => Self::emit_byte(Rc::clone(&compiler), OP_NEGATIVE),
                     "!" => Self::emit_byte(Rc::clone(&compiler), OP_NOT),
@@ -512,8 +888,8 @@ impl Compiler {
                 }
             }
             ExpressionNode::Infix { ope, left, right } => {
-                Self::compile_exp(Rc::clone(&compiler), class_compiler.clone(), *left);
-                Self::compile_exp(Rc::clone(&compiler), class_compiler.clone(), *right);
+                Self::compile_exp(Rc::clone(&compiler), class_compiler.clone(), *left)?;
+                Self::compile_exp(Rc::clone(&compiler), class_compiler.clone(), *right)?;
                 match ope.as_str() {
                     "+" => Self::emit_byte(Rc::clone(&compiler), OP_ADD),
                     "-" => Self::emit_byte(Rc::clone(&compiler), OP_SUBTRACT),
@@ -536,11 +912,12 @@ impl Compiler {
                         Self::emit_byte(Rc::clone(&compiler), OP_GREATER);
                         Self::emit_byte(Rc::clone(&compiler), OP_NOT);
                     }
+                    "in" => Self::emit_byte(Rc::clone(&compiler), OP_CONTAINS),
                     _ => {}
                 }
             }
             ExpressionNode::GetProperty { left, right } => {
-                Self::compile_exp(Rc::clone(&compiler), class_compiler.clone(), *left);
+                Self::compile_exp(Rc::clone(&compiler), class_compiler.clone(), *left)?;
                 match &*right {
                     ExpressionNode::Identifer(name) => {
                         let index = compiler
@@ -554,11 +931,12 @@ impl Compiler {
                 }
             }
             ExpressionNode::GetSuperProperty { left: _, right } => {
+                Self::check_super(class_compiler.clone())?;
                 // namedVariable
                 let this_name = "this".to_string();
                 if let Some(index) = Self::get_local(Rc::clone(&compiler), &this_name) {
                     Self::emit_bytes(Rc::clone(&compiler), OP_GET_LOCAL, index);
-                } else if let Some(index) = Self::get_upvalue(Rc::clone(&compiler), &this_name) {
+                } else if let Some(index) = Self::get_upvalue(Rc::clone(&compiler), &this_name)? {
                     Self::emit_bytes(Rc::clone(&compiler), OP_GET_UPVALUE, index);
                 } else {
                     let index = compiler
@@ -582,7 +960,7 @@ impl Compiler {
                         if let Some(index) = Self::get_local(Rc::clone(&compiler), &super_name) {
                             Self::emit_bytes(Rc::clone(&compiler), OP_GET_LOCAL, index);
                         } else if let Some(index) =
-                            Self::get_upvalue(Rc::clone(&compiler), &super_name)
+                            Self::get_upvalue(Rc::clone(&compiler), &super_name)?
                         {
                             Self::emit_bytes(Rc::clone(&compiler), OP_GET_UPVALUE, index);
                         } else {
@@ -602,17 +980,42 @@ impl Compiler {
             ExpressionNode::SetProperty { left: _, right: _ } => {
                 // nop
             }
+            // 名前付き引数があればメソッドを取り出してから呼び出す
+            ExpressionNode::InvokeMethod {
+                left,
+                right,
+                arguments,
+            } if Self::has_named_argument(&arguments) => {
+                Self::compile_exp(
+                    Rc::clone(&compiler),
+                    class_compiler.clone(),
+                    ExpressionNode::GetProperty { left, right },
+                )?;
+                Self::call(Rc::clone(&compiler), class_compiler.clone(), arguments)?;
+            }
+            ExpressionNode::InvokeSuperMethod {
+                left,
+                right,
+                arguments,
+            } if Self::has_named_argument(&arguments) => {
+                Self::compile_exp(
+                    Rc::clone(&compiler),
+                    class_compiler.clone(),
+                    ExpressionNode::GetSuperProperty { left, right },
+                )?;
+                Self::call(Rc::clone(&compiler), class_compiler.clone(), arguments)?;
+            }
             ExpressionNode::InvokeMethod {
                 left,
                 right,
                 arguments,
             } => {
-                Self::compile_exp(Rc::clone(&compiler), class_compiler.clone(), *left);
+                Self::compile_exp(Rc::clone(&compiler), class_compiler.clone(), *left)?;
                 match &*right {
                     ExpressionNode::Identifer(name) => {
                         let len = arguments.len() as u8;
                         for arg in arguments {
-                            Self::compile_exp(Rc::clone(&compiler), class_compiler.clone(), arg);
+                            Self::compile_exp(Rc::clone(&compiler), class_compiler.clone(), arg)?;
                         }
 
                         let index = compiler
@@ -631,11 +1034,12 @@ impl Compiler {
                 right,
                 arguments,
             } => {
+                Self::check_super(class_compiler.clone())?;
                 // namedVariable
                 let this_name = "this".to_string();
                 if let Some(index) = Self::get_local(Rc::clone(&compiler), &this_name) {
                     Self::emit_bytes(Rc::clone(&compiler), OP_GET_LOCAL, index);
-                } else if let Some(index) = Self::get_upvalue(Rc::clone(&compiler), &this_name) {
+                } else if let Some(index) = Self::get_upvalue(Rc::clone(&compiler), &this_name)? {
                     Self::emit_bytes(Rc::clone(&compiler), OP_GET_UPVALUE, index);
                 } else {
                     let index = compiler
@@ -650,7 +1054,7 @@ impl Compiler {
                     ExpressionNode::Identifer(name) => {
                         let len = arguments.len() as u8;
                         for arg in arguments {
-                            Self::compile_exp(Rc::clone(&compiler), class_compiler.clone(), arg);
+                            Self::compile_exp(Rc::clone(&compiler), class_compiler.clone(), arg)?;
                         }
 
                         let index = compiler
@@ -664,7 +1068,7 @@ impl Compiler {
                         if let Some(index) = Self::get_local(Rc::clone(&compiler), &super_name) {
                             Self::emit_bytes(Rc::clone(&compiler), OP_GET_LOCAL, index);
                         } else if let Some(index) =
-                            Self::get_upvalue(Rc::clone(&compiler), &super_name)
+                            Self::get_upvalue(Rc::clone(&compiler), &super_name)?
                         {
                             Self::emit_bytes(Rc::clone(&compiler), OP_GET_UPVALUE, index);
                         } else {
@@ -684,37 +1088,38 @@ impl Compiler {
             }
             ExpressionNode::Logical { ope, left, right } => match ope.as_str() {
                 "and" => {
-                    Self::compile_exp(Rc::clone(&compiler), class_compiler.clone(), *left);
+                    Self::compile_exp(Rc::clone(&compiler), class_compiler.clone(), *left)?;
                     let end_jump = Self::emit_jump(Rc::clone(&compiler), OP_JUMP_IF_FALSE);
                     Self::emit_byte(Rc::clone(&compiler), OP_POP);
-                    Self::compile_exp(Rc::clone(&compiler), class_compiler.clone(), *right);
-                    Self::patch_jump(Rc::clone(&compiler), end_jump).unwrap();
+                    Self::compile_exp(Rc::clone(&compiler), class_compiler.clone(), *right)?;
+                    Self::patch_jump(Rc::clone(&compiler), end_jump)?;
                 }
                 "or" => {
-                    Self::compile_exp(Rc::clone(&compiler), class_compiler.clone(), *left);
+                    Self::compile_exp(Rc::clone(&compiler), class_compiler.clone(), *left)?;
                     let else_jump = Self::emit_jump(Rc::clone(&compiler), OP_JUMP_IF_FALSE);
                     let end_jump = Self::emit_jump(Rc::clone(&compiler), OP_JUMP);
 
-                    Self::patch_jump(Rc::clone(&compiler), else_jump).unwrap();
+                    Self::patch_jump(Rc::clone(&compiler), else_jump)?;
                     Self::emit_byte(Rc::clone(&compiler), OP_POP);
-                    Self::compile_exp(Rc::clone(&compiler), class_compiler.clone(), *right);
-                    Self::patch_jump(Rc::clone(&compiler), end_jump).unwrap();
+                    Self::compile_exp(Rc::clone(&compiler), class_compiler.clone(), *right)?;
+                    Self::patch_jump(Rc::clone(&compiler), end_jump)?;
                 }
                 _ => {}
             },
             ExpressionNode::Assign { ope, left, right } => match ope.as_str() {
                 "=" => match *left {
                     ExpressionNode::Identifer(name) => {
-                        Self::compile_exp(Rc::clone(&compiler), class_compiler.clone(), *right);
+                        Self::compile_exp(Rc::clone(&compiler), class_compiler.clone(), *right)?;
 
                         let name = name.clone();
-                        if let Some(index) = Self::get_local(Rc::clone(&compiler), &name) {
+                        if let Some(index) = Self::find_local(Rc::clone(&compiler), &name, false) {
                             Self::emit_bytes(Rc::clone(&compiler), OP_SET_LOCAL, index);
-                            return;
+                            return Ok(());
                         }
-                        if let Some(index) = Self::get_upvalue(Rc::clone(&compiler), &name) {
+                        if let Some(index) = Self::find_upvalue(Rc::clone(&compiler), &name, false)?
+                        {
                             Self::emit_bytes(Rc::clone(&compiler), OP_SET_UPVALUE, index);
-                            return;
+                            return Ok(());
                         }
                         let index = compiler
                             .borrow_mut()
@@ -727,7 +1132,11 @@ impl Compiler {
                         left: prop_left,
                         right: prop_right,
                     } => {
-                        Self::compile_exp(Rc::clone(&compiler), class_compiler.clone(), *prop_left);
+                        Self::compile_exp(
+                            Rc::clone(&compiler),
+                            class_compiler.clone(),
+                            *prop_left,
+                        )?;
                         match &*prop_right {
                             ExpressionNode::Identifer(name) => {
                                 let index = compiler
@@ -739,19 +1148,19 @@ impl Compiler {
                                     Rc::clone(&compiler),
                                     class_compiler.clone(),
                                     *right,
-                                );
+                                )?;
                                 Self::emit_bytes(Rc::clone(&compiler), OP_SET_PROP, index);
                             }
                             _ => todo!(),
                         }
                     }
                     ExpressionNode::IndexCall { array, index } => {
-                        Self::compile_exp(Rc::clone(&compiler), class_compiler.clone(), *array);
-                        Self::compile_exp(Rc::clone(&compiler), class_compiler.clone(), *index);
-                        Self::compile_exp(Rc::clone(&compiler), class_compiler.clone(), *right);
+                        Self::compile_exp(Rc::clone(&compiler), class_compiler.clone(), *array)?;
+                        Self::compile_exp(Rc::clone(&compiler), class_compiler.clone(), *index)?;
+                        Self::compile_exp(Rc::clone(&compiler), class_compiler.clone(), *right)?;
                         Self::emit_byte(Rc::clone(&compiler), OP_INDEX_SET);
                     }
-                    invalid => panic!("invalid node {:?}", invalid),
+                    invalid => unreachable!("invalid assignment target {:?}", invalid),
                 },
                 _ => {}
             },
@@ -759,24 +1168,68 @@ impl Compiler {
                 function,
                 arguments,
             } => {
-                Self::compile_exp(Rc::clone(&compiler), class_compiler.clone(), *function);
-
-                let len = arguments.len() as u8;
-                for arg in arguments {
-                    Self::compile_exp(Rc::clone(&compiler), class_compiler.clone(), arg);
-                }
-                Self::emit_bytes(Rc::clone(&compiler), OP_CALL, len);
+                Self::compile_exp(Rc::clone(&compiler), class_compiler.clone(), *function)?;
+                Self::call(Rc::clone(&compiler), class_compiler.clone(), arguments)?;
             }
             ExpressionNode::IndexCall { array, index } => {
-                Self::compile_exp(Rc::clone(&compiler), class_compiler.clone(), *array);
-                Self::compile_exp(Rc::clone(&compiler), class_compiler.clone(), *index);
+                Self::compile_exp(Rc::clone(&compiler), class_compiler.clone(), *array)?;
+                Self::compile_exp(Rc::clone(&compiler), class_compiler.clone(), *index)?;
                 Self::emit_byte(Rc::clone(&compiler), OP_INDEX_CALL);
             }
+            ExpressionNode::NamedArgument { name, .. } => {
+                return Err(format!(
+                    "named argument \"{}\" is only allowed in a call.",
+                    name
+                ))
+            }
         }
+        Ok(())
+    }
+
+    fn has_named_argument(arguments: &[ExpressionNode]) -> bool {
+        arguments
+            .iter()
+            .any(|arg| matches!(arg, ExpressionNode::NamedArgument { .. }))
+    }
+
+    // 呼び出される値がスタックに積まれた状態で、引数を積んで呼び出す。
+    // 名前付き引数は位置引数の後ろに積み、OP_CALL_NAMED で名前を渡して VM で並べ替える
+    fn call(
+        compiler: Rc<RefCell<Compiler>>,
+        class_compiler: Rc<RefCell<ClassCompiler>>,
+        arguments: Vec<ExpressionNode>,
+    ) -> Result<(), String> {
+        let len = arguments.len() as u8;
+        let mut names = Vec::new();
+        for arg in arguments {
+            match arg {
+                ExpressionNode::NamedArgument { name, value } => {
+                    let index = compiler
+                        .borrow_mut()
+                        .function
+                        .chunk
+                        .add_constant(Value::String(Rc::new(name)));
+                    names.push(index);
+                    Self::compile_exp(Rc::clone(&compiler), class_compiler.clone(), *value)?;
+                }
+                arg => Self::compile_exp(Rc::clone(&compiler), class_compiler.clone(), arg)?,
+            }
+        }
+        if names.is_empty() {
+            Self::emit_bytes(Rc::clone(&compiler), OP_CALL, len);
+        } else {
+            Self::emit_bytes(Rc::clone(&compiler), OP_CALL_NAMED, len - names.len() as u8);
+            Self::emit_byte(Rc::clone(&compiler), names.len() as u8);
+            for index in names {
+                Self::emit_byte(Rc::clone(&compiler), index);
+            }
+        }
+        Ok(())
     }
 
     fn emit_byte(compiler: Rc<RefCell<Compiler>>, byte: u8) {
-        compiler.borrow_mut().function.chunk.write(byte, 0);
+        let line = compiler.borrow().line;
+        compiler.borrow_mut().function.chunk.write(byte, line);
     }
 
     fn emit_bytes(compiler: Rc<RefCell<Compiler>>, byte1: u8, byte2: u8) {
@@ -832,6 +1285,21 @@ impl Compiler {
         Ok(())
     }
 
+    // defer された文を登録と逆順に出力する。defer 内の return では出力しない
+    fn emit_deferred(
+        compiler: Rc<RefCell<Compiler>>,
+        class_compiler: Rc<RefCell<ClassCompiler>>,
+    ) -> Result<(), String> {
+        let deferred = std::mem::take(&mut compiler.borrow_mut().deferred);
+        let line = compiler.borrow().line;
+        for stmt in deferred.iter().rev() {
+            Self::compile_stmt(Rc::clone(&compiler), class_compiler.clone(), stmt.clone())?;
+        }
+        compiler.borrow_mut().line = line;
+        compiler.borrow_mut().deferred = deferred;
+        Ok(())
+    }
+
     fn begin_scope(compiler: Rc<RefCell<Compiler>>) {
         compiler.borrow_mut().scope_depth += 1;
     }
@@ -845,6 +1313,9 @@ impl Compiler {
             let scope_depth = compiler.borrow().scope_depth;
 
             if local_depth > scope_depth {
+                if compiler.borrow().warn_unused {
+                    Self::warn_unused_local(Rc::clone(&compiler), index);
+                }
                 if is_captured {
                     Self::emit_byte(Rc::clone(&compiler), OP_CLOSE_UPVALUE);
                 } else {
@@ -855,6 +1326,100 @@ impl Compiler {
         }
     }
 
+    // super はスーパークラスを持つクラスの中でだけ使える
+    fn check_super(class_compiler: Rc<RefCell<ClassCompiler>>) -> Result<(), String> {
+        if class_compiler.borrow().enclosing.is_none() {
+            return Err("\"super\" can only be used inside a class.".to_string());
+        }
+        if !class_compiler.borrow().has_super_class {
+            return Err("\"super\" can only be used in a class with a superclass.".to_string());
+        }
+        Ok(())
+    }
+
+    fn begin_loop(compiler: Rc<RefCell<Compiler>>) {
+        let scope_depth = compiler.borrow().scope_depth;
+        compiler.borrow_mut().loops.push(Loop::new(scope_depth));
+    }
+
+    // ループの後ろの位置で break のジャンプを埋める
+    fn end_loop(compiler: Rc<RefCell<Compiler>>) -> Result<(), String> {
+        let lp = match compiler.borrow_mut().loops.pop() {
+            Some(lp) => lp,
+            None => return Err("end of loop without loop.".to_string()),
+        };
+        for offset in lp.breaks {
+            Self::patch_jump(Rc::clone(&compiler), offset)?;
+        }
+        Ok(())
+    }
+
+    // ループの中で宣言されたローカル変数を end_scope と同じように片付けてから、ループの後ろへ飛ぶ。
+    // スコープはまだ続くので local_count は減らさない
+    fn emit_break(compiler: Rc<RefCell<Compiler>>) -> Result<(), String> {
+        let loop_depth = match compiler.borrow().loops.last() {
+            Some(lp) => lp.scope_depth,
+            None => return Err("\"break\" can only be used in a loop.".to_string()),
+        };
+        let local_count = compiler.borrow().local_count;
+        for index in (0..local_count).rev() {
+            let (depth, is_captured) = {
+                let local = &compiler.borrow().locals[index];
+                (local.depth, local.is_captured)
+            };
+            if depth <= loop_depth {
+                break;
+            }
+            if is_captured {
+                Self::emit_byte(Rc::clone(&compiler), OP_CLOSE_UPVALUE);
+            } else {
+                Self::emit_byte(Rc::clone(&compiler), OP_POP);
+            }
+        }
+        let offset = Self::emit_jump(Rc::clone(&compiler), OP_JUMP);
+        if let Some(lp) = compiler.borrow_mut().loops.last_mut() {
+            lp.breaks.push(offset);
+        }
+        Ok(())
+    }
+
+    // 警告はトップレベルのコンパイラの warnings に集める。
+    // import したファイルの行は実行するプログラムのソースと対応しないので、位置を付けない
+    fn warn_unused_local(compiler: Rc<RefCell<Compiler>>, index: usize) {
+        let warning = {
+            let compiler = compiler.borrow();
+            let local = &compiler.locals[index];
+            // コンパイラ内部の変数と "_" で始まる変数は対象外
+            if local.is_used
+                || local.name.is_empty()
+                || local.name.starts_with('@')
+                || local.name.starts_with('_')
+                || local.name == "this"
+                || local.name == "super"
+            {
+                return;
+            }
+            let warning = Diagnostic::new(format!(
+                "warning: unused local variable \"{}\".",
+                local.name
+            ));
+            if compiler.external {
+                warning
+            } else {
+                warning.at_line(local.line as i32)
+            }
+        };
+        let mut root = compiler;
+        loop {
+            let enclosing = root.borrow().enclosing.clone();
+            match enclosing {
+                Some(enclosing) => root = enclosing,
+                None => break,
+            }
+        }
+        root.borrow_mut().warnings.push(warning);
+    }
+
     fn get_scope_depth(compiler: Rc<RefCell<Compiler>>) -> i32 {
         compiler.borrow().scope_depth
     }
@@ -870,49 +1435,77 @@ impl Compiler {
     fn add_local(compiler: Rc<RefCell<Compiler>>, name: impl Into<String>) -> Result<(), String> {
         if compiler.borrow().local_count >= compiler.borrow().locals.len() {
             return Err(format!(
-                "add_local over upper.({} >= {})",
-                compiler.borrow().local_count,
-                compiler.borrow().locals.len()
+                "Too many local variables in function \"{}\".(max {})",
+                compiler.borrow().function.name,
+                LOCAL_MAX
             ));
         }
 
+        // 同じスコープでの再宣言だけをエラーにする。外側のスコープの変数 (引数を含む) は隠してよい
         let name = name.into();
-        for index in (0..compiler.borrow().local_count).rev() {
-            let local = &compiler.borrow().locals[index];
-            if local.depth != -1 && local.depth < compiler.borrow().scope_depth {
-                break;
-            }
-            if local.name == name {
-                return Err(format!("duplecate identifer.({})", name));
+        {
+            let compiler = compiler.borrow();
+            for local in compiler.locals[..compiler.local_count].iter().rev() {
+                if local.depth != -1 && local.depth < compiler.scope_depth {
+                    break;
+                }
+                if local.name == name {
+                    return Err(format!("duplecate identifer.({})", name));
+                }
             }
         }
 
         let local_count = compiler.borrow().local_count;
-        let local = Local::new(name, compiler.borrow().scope_depth);
+        let mut local = Local::new(name, compiler.borrow().scope_depth);
+        local.line = compiler.borrow().line;
         compiler.borrow_mut().locals[local_count] = local;
         compiler.borrow_mut().local_count += 1;
         Ok(())
     }
 
     fn get_local(compiler: Rc<RefCell<Compiler>>, name: impl Into<String>) -> Option<u8> {
+        Self::find_local(compiler, name, true)
+    }
+
+    // read が false なら代入先として探す。代入するだけでは読んだことにしない
+    fn find_local(
+        compiler: Rc<RefCell<Compiler>>,
+        name: impl Into<String>,
+        read: bool,
+    ) -> Option<u8> {
         let name = name.into();
-        for index in (0..compiler.borrow().local_count).rev() {
-            let local = &compiler.borrow().locals[index];
+        let local_count = compiler.borrow().local_count;
+        for index in (0..local_count).rev() {
+            let local = &mut compiler.borrow_mut().locals[index];
             if local.name == name {
+                if read {
+                    local.is_used = true;
+                }
                 return Some(index as u8);
             }
         }
         None
     }
 
-    fn get_upvalue(compiler: Rc<RefCell<Compiler>>, name: impl Into<String>) -> Option<u8> {
+    fn get_upvalue(
+        compiler: Rc<RefCell<Compiler>>,
+        name: impl Into<String>,
+    ) -> Result<Option<u8>, String> {
+        Self::find_upvalue(compiler, name, true)
+    }
+
+    fn find_upvalue(
+        compiler: Rc<RefCell<Compiler>>,
+        name: impl Into<String>,
+        read: bool,
+    ) -> Result<Option<u8>, String> {
         let name = name.into();
         let enclosing = match compiler.borrow().enclosing {
             Some(ref e) => Rc::clone(e),
-            None => return None,
+            None => return Ok(None),
         };
 
-        let local_index = Self::get_local(Rc::clone(&enclosing), &name);
+        let local_index = Self::find_local(Rc::clone(&enclosing), &name, read);
         match local_index {
             Some(local_index) => {
                 enclosing.borrow_mut().locals[local_index as usize].is_captured = true;
@@ -921,29 +1514,40 @@ impl Compiler {
             None => {}
         };
 
-        let upvalue_index = Self::get_upvalue(Rc::clone(&enclosing), &name);
+        let upvalue_index = Self::find_upvalue(Rc::clone(&enclosing), &name, read)?;
         match upvalue_index {
             Some(upvalue_index) => {
                 Self::add_upvalue(Rc::clone(&compiler), upvalue_index as usize, false)
             }
-            None => None,
+            None => Ok(None),
         }
     }
 
-    fn add_upvalue(compiler: Rc<RefCell<Compiler>>, index: usize, is_local: bool) -> Option<u8> {
+    fn add_upvalue(
+        compiler: Rc<RefCell<Compiler>>,
+        index: usize,
+        is_local: bool,
+    ) -> Result<Option<u8>, String> {
         let upvalue_count = compiler.borrow().function.upvalue_count;
         let upvalue_range = 0..upvalue_count;
         for upvalue_index in upvalue_range {
             if compiler.borrow().upvalues[upvalue_index].index == index
                 && compiler.borrow().upvalues[upvalue_index].is_local == is_local
             {
-                return Some(upvalue_index as u8);
+                return Ok(Some(upvalue_index as u8));
             }
         }
 
+        if upvalue_count >= UPVALUE_MAX {
+            return Err(format!(
+                "Too many closure variables in function \"{}\".(max {})",
+                compiler.borrow().function.name,
+                UPVALUE_MAX
+            ));
+        }
         compiler.borrow_mut().upvalues[upvalue_count] = Upvalue::new(index, is_local);
         compiler.borrow_mut().function.upvalue_count += 1;
-        Some(upvalue_count as u8)
+        Ok(Some(upvalue_count as u8))
     }
 
     // fn to_owned_function(self) -> FunctionObject {
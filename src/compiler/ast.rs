@@ -3,43 +3,68 @@ use std::fmt::Display;
 #[derive(Debug, Clone)]
 pub enum StatementNode {
     Class {
+        line: i32,
         name: ExpressionNode,
         body: Box<StatementNode>,
         super_class: Option<ExpressionNode>,
     },
     For {
+        line: i32,
         name: ExpressionNode,
         range: ExpressionNode,
         consequence: Box<StatementNode>,
     },
     Fun {
+        line: i32,
         name: ExpressionNode,
         params: Vec<ExpressionNode>,
         body: Box<StatementNode>,
     },
     If {
+        line: i32,
         condition: ExpressionNode,
         consequence: Box<StatementNode>,
         alternative: Option<Box<StatementNode>>,
     },
     Return {
+        line: i32,
         value: Option<ExpressionNode>,
     },
+    Yield {
+        line: i32,
+        value: Option<ExpressionNode>,
+    },
+    Defer {
+        line: i32,
+        body: Box<StatementNode>,
+    },
+    Break {
+        line: i32,
+    },
+    Import {
+        line: i32,
+        path: String,
+    },
     Var {
+        line: i32,
         name: ExpressionNode,
         value: ExpressionNode,
     },
     While {
+        line: i32,
         condition: ExpressionNode,
         consequence: Box<StatementNode>,
     },
     Block {
+        line: i32,
         stmts: Vec<StatementNode>,
     },
     Print {
-        expression: ExpressionNode,
+        line: i32,
+        expressions: Vec<ExpressionNode>,
     },
     ExpStmt {
+        line: i32,
         expression: ExpressionNode,
     },
 }
@@ -52,6 +77,12 @@ pub enum ExpressionNode {
     IntegerLiteral(i64),
     BooleanLiteral(bool),
     ArrayLiteral(Vec<ExpressionNode>),
+    // [element for name in iterable ... if condition]。loops は外側から順に並ぶ
+    Comprehension {
+        element: Box<ExpressionNode>,
+        loops: Vec<(ExpressionNode, ExpressionNode)>,
+        condition: Option<Box<ExpressionNode>>,
+    },
     RangeLiteral {
         start: Box<ExpressionNode>,
         end: Box<ExpressionNode>,
@@ -106,6 +137,33 @@ pub enum ExpressionNode {
         array: Box<ExpressionNode>,
         index: Box<ExpressionNode>,
     },
+    // 呼び出しの引数リストの中だけに現れる name: value
+    NamedArgument {
+        name: String,
+        value: Box<ExpressionNode>,
+    },
+}
+
+impl StatementNode {
+    // 文が始まるソース上の行
+    pub fn line(&self) -> i32 {
+        match self {
+            StatementNode::Class { line, .. } => *line,
+            StatementNode::For { line, .. } => *line,
+            StatementNode::Fun { line, .. } => *line,
+            StatementNode::If { line, .. } => *line,
+            StatementNode::Return { line, .. } => *line,
+            StatementNode::Yield { line, .. } => *line,
+            StatementNode::Defer { line, .. } => *line,
+            StatementNode::Break { line } => *line,
+            StatementNode::Import { line, .. } => *line,
+            StatementNode::Var { line, .. } => *line,
+            StatementNode::While { line, .. } => *line,
+            StatementNode::Block { line, .. } => *line,
+            StatementNode::Print { line, .. } => *line,
+            StatementNode::ExpStmt { line, .. } => *line,
+        }
+    }
 }
 
 impl Display for StatementNode {
@@ -115,6 +173,7 @@ impl Display for StatementNode {
                 name,
                 body,
                 super_class,
+                ..
             } => match &super_class {
                 Some(sc) => write!(f, "class {} < {} {}", name, sc, body),
                 None => write!(f, "class {} {}", name, body),
@@ -123,8 +182,11 @@ impl Display for StatementNode {
                 name,
                 range,
                 consequence,
+                ..
             } => write!(f, "for({} in {})\r\n{}", name, range, consequence),
-            StatementNode::Fun { name, params, body } => write!(
+            StatementNode::Fun {
+                name, params, body, ..
+            } => write!(
                 f,
                 "func {}({}){}",
                 name,
@@ -139,6 +201,7 @@ impl Display for StatementNode {
                 condition: condtion,
                 consequence,
                 alternative: alternatives,
+                ..
             } => match alternatives {
                 Some(alternatives) => write!(
                     f,
@@ -147,16 +210,24 @@ impl Display for StatementNode {
                 ),
                 None => write!(f, "if({})\r\n{}", condtion, consequence),
             },
-            StatementNode::Return { value } => match value {
+            StatementNode::Return { value, .. } => match value {
                 Some(value) => write!(f, "return {};", value),
                 None => write!(f, "return;"),
             },
-            StatementNode::Var { name, value } => write!(f, "var {} = {};", name, value),
+            StatementNode::Yield { value, .. } => match value {
+                Some(value) => write!(f, "yield {};", value),
+                None => write!(f, "yield;"),
+            },
+            StatementNode::Defer { body, .. } => write!(f, "defer {}", body),
+            StatementNode::Break { .. } => write!(f, "break;"),
+            StatementNode::Import { path, .. } => write!(f, "import \"{}\";", path),
+            StatementNode::Var { name, value, .. } => write!(f, "var {} = {};", name, value),
             StatementNode::While {
                 condition: condtion,
                 consequence,
+                ..
             } => write!(f, "while({})\r\n{}", condtion, consequence),
-            StatementNode::Block { stmts } => write!(
+            StatementNode::Block { stmts, .. } => write!(
                 f,
                 "{{\r\n{}\r\n}}",
                 stmts
@@ -165,8 +236,16 @@ impl Display for StatementNode {
                     .collect::<Vec<_>>()
                     .join("\r\n")
             ),
-            StatementNode::Print { expression } => write!(f, "print {};", expression),
-            StatementNode::ExpStmt { expression } => write!(f, "{};", expression),
+            StatementNode::Print { expressions, .. } => write!(
+                f,
+                "print {};",
+                expressions
+                    .iter()
+                    .map(|exp| format!("{}", exp))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            StatementNode::ExpStmt { expression, .. } => write!(f, "{};", expression),
         }
     }
 }
@@ -188,6 +267,20 @@ impl Display for ExpressionNode {
                     .collect::<Vec<_>>()
                     .join(", ")
             ),
+            ExpressionNode::Comprehension {
+                element,
+                loops,
+                condition,
+            } => {
+                write!(f, "[{}", element)?;
+                for (name, iterable) in loops {
+                    write!(f, " for {} in {}", name, iterable)?;
+                }
+                match condition {
+                    Some(condition) => write!(f, " if {}]", condition),
+                    None => write!(f, "]"),
+                }
+            }
             ExpressionNode::RangeLiteral { start, end } => write!(f, "({}..{})", start, end),
             ExpressionNode::NullLiteral => write!(f, "null"),
             ExpressionNode::Prefix { ope, right } => write!(f, "({} {})", ope, right),
@@ -252,6 +345,7 @@ impl Display for ExpressionNode {
                     .join(", ")
             ),
             ExpressionNode::IndexCall { array, index } => write!(f, "{}[{}]", array, index),
+            ExpressionNode::NamedArgument { name, value } => write!(f, "{}: {}", name, value),
             ExpressionNode::Logical { ope, left, right } => {
                 write!(f, "({} {} {})", left, ope, right)
             }
@@ -0,0 +1,102 @@
+use super::token::Position;
+use std::fmt::Display;
+
+// 字句解析、構文解析、実行時のエラー。表示は message だけで、
+// 位置はエディタなどがソースの該当箇所を示すのに使う
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub message: String,
+    // 1 から数える。分からない場合は None
+    pub line: Option<i32>,
+    // 0 から数える文字単位の位置。実行時のエラーは行しか分からないので None
+    pub column: Option<i32>,
+    // 問題の箇所の文字数
+    pub span: i32,
+}
+
+impl Diagnostic {
+    pub fn new(message: impl Into<String>) -> Self {
+        Self {
+            message: message.into(),
+            line: None,
+            column: None,
+            span: 0,
+        }
+    }
+
+    pub fn at(mut self, position: &Position) -> Self {
+        self.line = Some(position.line);
+        self.column = Some(position.column);
+        self.span = position.length;
+        self
+    }
+
+    pub fn at_line(mut self, line: i32) -> Self {
+        self.line = Some(line);
+        self.column = None;
+        self.span = 0;
+        self
+    }
+
+    // 位置が分からないエラーにだけ位置を付ける。内側で付けた位置の方が正確なので上書きしない
+    pub fn or_at(self, position: Option<&Position>) -> Self {
+        match position {
+            Some(position) if self.line.is_none() => self.at(position),
+            _ => self,
+        }
+    }
+
+    pub fn or_at_line(self, line: Option<i32>) -> Self {
+        match line {
+            Some(line) if self.line.is_none() => self.at_line(line),
+            _ => self,
+        }
+    }
+
+    // メッセージの後ろに、ソースの該当行と問題の箇所の下に ^ を付けた行を並べる。
+    //
+    // expected semicolon. but found ...
+    //   --> 3:9
+    //    |
+    //  3 | print a b;
+    //    |         ^
+    //
+    // 位置がない場合やソースにない行の場合はメッセージだけ。列が分からない場合は ^ の行を省く
+    // 列を持つのはトークンの位置が分かる字句解析と構文解析のエラーだけ。
+    // コンパイルと実行時のエラーは命令や AST が行しか持たないので、"--> line 3" の形で行だけを示す
+    pub fn render(&self, source: &str) -> String {
+        let line = match self.line {
+            Some(line) if line >= 1 => line,
+            _ => return self.message.clone(),
+        };
+        let text = match source.lines().nth(line as usize - 1) {
+            Some(text) => text,
+            None => return self.message.clone(),
+        };
+        let gutter = " ".repeat(line.to_string().len());
+        let mut result = match self.column {
+            Some(column) => format!("{}\n{}--> {}:{}\n", self.message, gutter, line, column + 1),
+            None => format!("{}\n{}--> line {}\n", self.message, gutter, line),
+        };
+        result += &format!("{} |\n{} | {}", gutter, line, text);
+        if let Some(column) = self.column {
+            // タブはそのまま残し、表示したときに ^ の位置がずれないようにする
+            let indent = text
+                .chars()
+                .take(column as usize)
+                .map(|c| if c == '\t' { '\t' } else { ' ' })
+                .collect::<String>();
+            // 文字列のように複数行にまたがる箇所は、その行の終わりまでにする
+            let rest = text.chars().count().saturating_sub(column as usize);
+            let span = (self.span.max(1) as usize).min(rest.max(1));
+            result += &format!("\n{} | {}{}", gutter, indent, "^".repeat(span));
+        }
+        result
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
@@ -1,5 +1,6 @@
 use crate::vm::{chunk::Chunk, value::Value};
-use std::{cell::RefCell, collections::BTreeMap, rc::Rc};
+use rustc_hash::FxHashMap;
+use std::{cell::RefCell, cmp::Ordering, rc::Rc};
 
 // #[derive(Debug, Clone, PartialEq, PartialOrd)]
 // pub enum ObjectType {
@@ -15,6 +16,10 @@ pub struct FunctionObject {
     pub chunk: Chunk,
     pub name: String,
     pub upvalue_count: usize,
+    // yield を含む関数。呼び出すとジェネレータを返す
+    pub is_generator: bool,
+    // 名前付き引数を位置に並べ替えるための仮引数名
+    pub params: Vec<String>,
 }
 
 impl FunctionObject {
@@ -24,6 +29,8 @@ impl FunctionObject {
             chunk: Chunk::new(),
             name: name.into(),
             upvalue_count: 0,
+            is_generator: false,
+            params: Vec::new(),
         }
     }
 }
@@ -69,32 +76,79 @@ impl UpvalueObject {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, PartialOrd)]
+// メソッドやフィールドの表。要素の順序は決まっていないので、順序が必要な場合は名前で並べ替える
+pub type PropertyMap = FxHashMap<Rc<String>, Value>;
+
+// 名前順に並べた要素。PropertyMap 同士の大小比較に使う
+fn sorted_properties(map: &PropertyMap) -> Vec<(&Rc<String>, &Value)> {
+    let mut properties = map.iter().collect::<Vec<_>>();
+    properties.sort_by(|a, b| a.0.cmp(b.0));
+    properties
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct ClassObject {
     pub name: String,
-    pub methods: BTreeMap<Rc<String>, Value>,
+    pub methods: PropertyMap,
+    pub superclass: Option<Rc<RefCell<ClassObject>>>,
+}
+
+impl PartialOrd for ClassObject {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        match self.name.partial_cmp(&other.name) {
+            Some(Ordering::Equal) => {}
+            ordering => return ordering,
+        }
+        match sorted_properties(&self.methods).partial_cmp(&sorted_properties(&other.methods)) {
+            Some(Ordering::Equal) => {}
+            ordering => return ordering,
+        }
+        self.superclass.partial_cmp(&other.superclass)
+    }
 }
 
 impl ClassObject {
     pub fn new(name: impl Into<String>) -> Self {
         Self {
             name: name.into(),
-            methods: BTreeMap::new(),
+            methods: PropertyMap::default(),
+            superclass: None,
+        }
+    }
+
+    // 自身のメソッドになければスーパークラスを順に辿って探す
+    pub fn find_method(&self, name: &Rc<String>) -> Option<Value> {
+        if let Some(method) = self.methods.get(name) {
+            return Some(method.clone());
+        }
+        match &self.superclass {
+            Some(superclass) => superclass.borrow().find_method(name),
+            None => None,
         }
     }
 }
 
-#[derive(Debug, Clone, PartialEq, PartialOrd)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct InstanceObject {
     pub class: Rc<RefCell<ClassObject>>,
-    pub fields: BTreeMap<Rc<String>, Value>,
+    pub fields: PropertyMap,
+}
+
+impl PartialOrd for InstanceObject {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        match self.class.partial_cmp(&other.class) {
+            Some(Ordering::Equal) => {}
+            ordering => return ordering,
+        }
+        sorted_properties(&self.fields).partial_cmp(&sorted_properties(&other.fields))
+    }
 }
 
 impl InstanceObject {
     pub fn new(class: Rc<RefCell<ClassObject>>) -> Self {
         Self {
             class,
-            fields: BTreeMap::new(),
+            fields: PropertyMap::default(),
         }
     }
 }
@@ -110,3 +164,30 @@ impl BoundMethodObject {
         Self { reciever, method }
     }
 }
+
+// yield で中断した関数の状態。再開時に ip とスタックの内容を復元する
+#[derive(Debug, Clone, PartialEq, PartialOrd)]
+pub struct GeneratorObject {
+    pub closure: Rc<ClosureObject>,
+    pub ip: usize,
+    pub slots: Vec<Value>,
+    // 中断中に閉じた上位値と、そのローカル変数の slots での位置。再開時に開き直して、
+    // 中断の前後で同じ変数を共有し続ける
+    pub upvalues: Vec<(usize, Rc<RefCell<UpvalueObject>>)>,
+    // done() のために先読みした値
+    pub peeked: Option<Value>,
+    pub done: bool,
+}
+
+impl GeneratorObject {
+    pub fn new(closure: Rc<ClosureObject>, slots: Vec<Value>) -> Self {
+        Self {
+            closure,
+            ip: 0,
+            slots,
+            upvalues: Vec::new(),
+            peeked: None,
+            done: false,
+        }
+    }
+}
@@ -1,18 +1,54 @@
 use super::{
     ast::{ExpressionNode, Program, StatementNode},
+    diagnostic::Diagnostic,
     scanner::Scanner,
-    token::Token,
+    token::{Position, Token},
 };
+use std::fmt::Display;
 
 #[derive(Debug)]
 pub enum ParseError {
-    Invalid(String),
+    Invalid(Diagnostic),
+    // 文や括弧の途中で入力が終わった。REPL では続きの行を読む
+    UnexpectedEof(Diagnostic),
+}
+
+impl ParseError {
+    fn invalid(message: impl Into<String>) -> Self {
+        ParseError::Invalid(Diagnostic::new(message))
+    }
+
+    fn unexpected_eof(message: impl Into<String>) -> Self {
+        ParseError::UnexpectedEof(Diagnostic::new(message))
+    }
+
+    pub fn diagnostic(&self) -> &Diagnostic {
+        match self {
+            ParseError::Invalid(diagnostic) | ParseError::UnexpectedEof(diagnostic) => diagnostic,
+        }
+    }
+
+    fn or_at(self, position: Option<&Position>) -> Self {
+        match self {
+            ParseError::Invalid(diagnostic) => ParseError::Invalid(diagnostic.or_at(position)),
+            ParseError::UnexpectedEof(diagnostic) => {
+                ParseError::UnexpectedEof(diagnostic.or_at(position))
+            }
+        }
+    }
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.diagnostic())
+    }
 }
 
 type ParseResult<T> = Result<T, ParseError>;
 
 const PRECEDENCE_LOWEST: i32 = 0;
 const PRECEDENCE_ASSIGNMENT: i32 = 5;
+const PRECEDENCE_OR: i32 = 6;
 const PRECEDENCE_AND: i32 = 7;
 const PRECEDENCE_EQUALITY: i32 = 10;
 const PRECEDENCE_COMPARISON: i32 = 20;
@@ -25,25 +61,44 @@ const PRECEDENCE_CALL: i32 = 70;
 pub struct Parser {
     tokens: Vec<Token>,
     cur_index: usize,
+    // 字句解析のエラーは parse で返す
+    scan_error: Option<Diagnostic>,
 }
 
 impl Parser {
     pub fn new(input: &str) -> Self {
         let mut scanner = Scanner::new(input);
-        let tokens = match scanner.tokenize() {
-            Ok(r) => r,
-            Err(e) => panic!("{:?}", e),
+        let (tokens, scan_error) = match scanner.tokenize() {
+            Ok(r) => (r, None),
+            Err(e) => (Vec::new(), Some(e.diagnostic().clone())),
         };
         Parser {
             tokens,
             cur_index: 0,
+            scan_error,
         }
     }
 
     pub fn parse(&mut self) -> ParseResult<Program> {
+        if let Some(diagnostic) = self.scan_error.take() {
+            return Err(ParseError::Invalid(diagnostic));
+        }
         let mut program = Program::new();
         while let Some(_) = self.current_token() {
-            let stmt = self.parse_stmt()?;
+            // 位置のないエラーは、読んでいたトークン (入力の終わりなら最後のトークン) の位置にする
+            let stmt = match self.parse_stmt() {
+                Ok(stmt) => stmt,
+                Err(e) => {
+                    let e = match e {
+                        ParseError::Invalid(diagnostic) if self.current_token().is_none() => {
+                            ParseError::UnexpectedEof(diagnostic)
+                        }
+                        e => e,
+                    };
+                    let token = self.current_token().or(self.tokens.last());
+                    return Err(e.or_at(token.map(|token| token.position())));
+                }
+            };
             program.stmts.push(stmt);
             self.next_token();
         }
@@ -59,51 +114,60 @@ impl Parser {
                 Token::Fun(_) => self.parse_func(),
                 Token::While(_) => self.parse_while(),
                 Token::Return(_) => self.parse_return(),
+                Token::Yield(_) => self.parse_yield(),
+                Token::Defer(_) => self.parse_defer(),
+                Token::Break(_) => self.parse_break(),
+                Token::Import(_) => self.parse_import(),
                 Token::For(_) => self.parse_for(),
                 Token::Class(_) => self.parse_class(),
                 Token::Print(_) => self.parse_print(),
                 _ => self.parse_expression_stmt(),
             }
         } else {
-            Err(ParseError::Invalid("not statement.".to_string()))
+            Err(ParseError::invalid("not statement.".to_string()))
         }
     }
 
     fn parse_print(&mut self) -> ParseResult<StatementNode> {
+        let line = self.current_line();
         if !matches!(self.current_token(), Some(&Token::Print(_))) {
-            return Err(ParseError::Invalid(format!(
-                "expected print. but found {:?}",
-                self.current_token()
+            return Err(ParseError::invalid(format!(
+                "expected print. but found {}",
+                Self::found(self.current_token())
             )));
         }
 
         self.next_token();
-        let expression = self.parse_expression(PRECEDENCE_LOWEST)?;
+        let mut expressions = vec![self.parse_expression(PRECEDENCE_LOWEST)?];
 
+        // カンマ区切りで複数の値を出力できる
         self.next_token();
+        while matches!(self.current_token(), Some(&Token::Comma(_))) {
+            self.next_token();
+            expressions.push(self.parse_expression(PRECEDENCE_LOWEST)?);
+            self.next_token();
+        }
         if matches!(self.current_token(), Some(&Token::Semicolon(_))) {
-            Ok(StatementNode::Print { expression })
+            Ok(StatementNode::Print { line, expressions })
         } else {
-            Err(ParseError::Invalid(format!(
-                "expected semicolon. but found {:?}",
-                self.current_token()
-            )))
+            Err(self.semicolon_error())
         }
     }
 
     fn parse_class(&mut self) -> ParseResult<StatementNode> {
+        let line = self.current_line();
         if let Some(t) = self.current_token() {
             match t {
                 Token::Class(_) => {}
                 _ => {
-                    return Err(ParseError::Invalid(format!(
-                        "expected class. but found {:?}",
-                        self.current_token()
+                    return Err(ParseError::invalid(format!(
+                        "expected class. but found {}",
+                        Self::found(self.current_token())
                     )))
                 }
             }
         } else {
-            return Err(ParseError::Invalid(
+            return Err(ParseError::invalid(
                 "expected class. but not found.".to_string(),
             ));
         }
@@ -112,7 +176,7 @@ impl Parser {
         let token = match self.current_token() {
             Some(t) => t,
             None => {
-                return Err(ParseError::Invalid(
+                return Err(ParseError::invalid(
                     "expected identifer. but not found.".to_string(),
                 ))
             }
@@ -120,8 +184,8 @@ impl Parser {
         let name = match token {
             Token::Identifer { position: _, value } => self.parse_identifer(value.clone())?,
             invalid => {
-                return Err(ParseError::Invalid(format!(
-                    "expected identifer. but found {:?}",
+                return Err(ParseError::invalid(format!(
+                    "expected identifer. but found {}",
                     invalid
                 )))
             }
@@ -131,7 +195,7 @@ impl Parser {
         let token = match self.current_token() {
             Some(t) => t,
             None => {
-                return Err(ParseError::Invalid(
+                return Err(ParseError::invalid(
                     "expected identifer. but not found.".to_string(),
                 ))
             }
@@ -142,7 +206,7 @@ impl Parser {
                 let token = match self.current_token() {
                     Some(t) => t,
                     None => {
-                        return Err(ParseError::Invalid(
+                        return Err(ParseError::invalid(
                             "expected identifer. but not found.".to_string(),
                         ))
                     }
@@ -152,8 +216,8 @@ impl Parser {
                         Some(self.parse_identifer(value.clone())?)
                     }
                     invalid => {
-                        return Err(ParseError::Invalid(format!(
-                            "expected identifer. but found {:?}",
+                        return Err(ParseError::invalid(format!(
+                            "expected identifer. but found {}",
                             invalid
                         )))
                     }
@@ -166,6 +230,7 @@ impl Parser {
 
         let body = self.parse_stmt()?;
         Ok(StatementNode::Class {
+            line,
             name,
             body: Box::new(body),
             super_class,
@@ -173,16 +238,45 @@ impl Parser {
     }
 
     fn parse_return(&mut self) -> ParseResult<StatementNode> {
+        let line = self.current_line();
         if !matches!(self.current_token(), Some(&Token::Return(_))) {
-            return Err(ParseError::Invalid(format!(
-                "expected return. but found {:?}",
-                self.current_token()
+            return Err(ParseError::invalid(format!(
+                "expected return. but found {}",
+                Self::found(self.current_token())
+            )));
+        }
+
+        if matches!(self.peek_token(), Some(&Token::Semicolon(_))) {
+            self.next_token();
+            return Ok(StatementNode::Return { line, value: None });
+        }
+
+        self.next_token();
+        let val = self.parse_expression(PRECEDENCE_LOWEST)?;
+
+        self.next_token();
+        if matches!(self.current_token(), Some(&Token::Semicolon(_))) {
+            Ok(StatementNode::Return {
+                line,
+                value: Some(val),
+            })
+        } else {
+            Err(self.semicolon_error())
+        }
+    }
+
+    fn parse_yield(&mut self) -> ParseResult<StatementNode> {
+        let line = self.current_line();
+        if !matches!(self.current_token(), Some(&Token::Yield(_))) {
+            return Err(ParseError::invalid(format!(
+                "expected yield. but found {}",
+                Self::found(self.current_token())
             )));
         }
 
         if matches!(self.peek_token(), Some(&Token::Semicolon(_))) {
             self.next_token();
-            return Ok(StatementNode::Return { value: None });
+            return Ok(StatementNode::Yield { line, value: None });
         }
 
         self.next_token();
@@ -190,28 +284,99 @@ impl Parser {
 
         self.next_token();
         if matches!(self.current_token(), Some(&Token::Semicolon(_))) {
-            Ok(StatementNode::Return { value: Some(val) })
+            Ok(StatementNode::Yield {
+                line,
+                value: Some(val),
+            })
+        } else {
+            Err(self.semicolon_error())
+        }
+    }
+
+    fn parse_defer(&mut self) -> ParseResult<StatementNode> {
+        let line = self.current_line();
+        if !matches!(self.current_token(), Some(&Token::Defer(_))) {
+            return Err(ParseError::invalid(format!(
+                "expected defer. but found {}",
+                Self::found(self.current_token())
+            )));
+        }
+
+        self.next_token();
+        let body = self.parse_stmt()?;
+        match body {
+            StatementNode::Var { .. } | StatementNode::Fun { .. } | StatementNode::Class { .. } => {
+                Err(ParseError::invalid(format!(
+                    "expected statement after defer. but found declaration {}",
+                    body
+                )))
+            }
+            body => Ok(StatementNode::Defer {
+                line,
+                body: Box::new(body),
+            }),
+        }
+    }
+
+    fn parse_break(&mut self) -> ParseResult<StatementNode> {
+        let line = self.current_line();
+        if !matches!(self.current_token(), Some(&Token::Break(_))) {
+            return Err(ParseError::invalid(format!(
+                "expected break. but found {}",
+                Self::found(self.current_token())
+            )));
+        }
+
+        self.next_token();
+        if matches!(self.current_token(), Some(&Token::Semicolon(_))) {
+            Ok(StatementNode::Break { line })
+        } else {
+            Err(self.semicolon_error())
+        }
+    }
+
+    fn parse_import(&mut self) -> ParseResult<StatementNode> {
+        let line = self.current_line();
+        if !matches!(self.current_token(), Some(&Token::Import(_))) {
+            return Err(ParseError::invalid(format!(
+                "expected import. but found {}",
+                Self::found(self.current_token())
+            )));
+        }
+
+        self.next_token();
+        let path = match self.current_token() {
+            Some(Token::String { value, .. }) => value.clone(),
+            token => {
+                return Err(ParseError::invalid(format!(
+                    "expected file path string after import. but found {}",
+                    Self::found(token)
+                )))
+            }
+        };
+
+        self.next_token();
+        if matches!(self.current_token(), Some(&Token::Semicolon(_))) {
+            Ok(StatementNode::Import { line, path })
         } else {
-            Err(ParseError::Invalid(format!(
-                "expected semicolon. but found {:?}",
-                self.current_token()
-            )))
+            Err(self.semicolon_error())
         }
     }
 
     fn parse_for(&mut self) -> ParseResult<StatementNode> {
+        let line = self.current_line();
         match self.current_token() {
             Some(t) => match t {
                 Token::For(_) => {}
                 _ => {
-                    return Err(ParseError::Invalid(format!(
-                        "expected for. but found {:?}",
-                        self.current_token()
+                    return Err(ParseError::invalid(format!(
+                        "expected for. but found {}",
+                        Self::found(self.current_token())
                     )))
                 }
             },
             None => {
-                return Err(ParseError::Invalid(
+                return Err(ParseError::invalid(
                     "expected for. but not found.".to_string(),
                 ))
             }
@@ -222,14 +387,14 @@ impl Parser {
             Some(t) => match t {
                 Token::LeftParen(_) => {}
                 _ => {
-                    return Err(ParseError::Invalid(format!(
-                        "expected left paren. but found {:?}",
-                        self.current_token()
+                    return Err(ParseError::invalid(format!(
+                        "expected left paren. but found {}",
+                        Self::found(self.current_token())
                     )))
                 }
             },
             None => {
-                return Err(ParseError::Invalid(
+                return Err(ParseError::invalid(
                     "expected left paren. but not found.".to_string(),
                 ))
             }
@@ -239,7 +404,7 @@ impl Parser {
         let token = match self.current_token() {
             Some(t) => t,
             None => {
-                return Err(ParseError::Invalid(
+                return Err(ParseError::invalid(
                     "expected any token. but not found.".to_string(),
                 ))
             }
@@ -247,8 +412,8 @@ impl Parser {
         let name = match token {
             Token::Identifer { position: _, value } => self.parse_identifer(value.clone())?,
             _ => {
-                return Err(ParseError::Invalid(format!(
-                    "expected identifer token. but found {:?}.",
+                return Err(ParseError::invalid(format!(
+                    "expected identifer token. but found {}.",
                     token
                 )))
             }
@@ -259,14 +424,14 @@ impl Parser {
             Some(t) => match t {
                 Token::In(_) => {}
                 _ => {
-                    return Err(ParseError::Invalid(format!(
-                        "expected in keyword. but found {:?}",
-                        self.current_token()
+                    return Err(ParseError::invalid(format!(
+                        "expected in keyword. but found {}",
+                        Self::found(self.current_token())
                     )))
                 }
             },
             None => {
-                return Err(ParseError::Invalid(
+                return Err(ParseError::invalid(
                     "expected in keyword. but not found.".to_string(),
                 ))
             }
@@ -280,14 +445,14 @@ impl Parser {
             Some(t) => match t {
                 Token::RightParen(_) => {}
                 _ => {
-                    return Err(ParseError::Invalid(format!(
-                        "expected right paren. but found {:?}",
-                        self.current_token()
+                    return Err(ParseError::invalid(format!(
+                        "expected right paren. but found {}",
+                        Self::found(self.current_token())
                     )))
                 }
             },
             None => {
-                return Err(ParseError::Invalid(
+                return Err(ParseError::invalid(
                     "expected right paren. but not found.".to_string(),
                 ))
             }
@@ -296,6 +461,7 @@ impl Parser {
         self.next_token();
         let body = self.parse_stmt()?;
         Ok(StatementNode::For {
+            line,
             name,
             range,
             consequence: Box::new(body),
@@ -303,18 +469,19 @@ impl Parser {
     }
 
     fn parse_while(&mut self) -> Result<StatementNode, ParseError> {
+        let line = self.current_line();
         match self.current_token() {
             Some(t) => match t {
                 Token::While(_) => {}
                 _ => {
-                    return Err(ParseError::Invalid(format!(
-                        "expected while. but found {:?}",
-                        self.current_token()
+                    return Err(ParseError::invalid(format!(
+                        "expected while. but found {}",
+                        Self::found(self.current_token())
                     )))
                 }
             },
             None => {
-                return Err(ParseError::Invalid(
+                return Err(ParseError::invalid(
                     "expected while. but not found.".to_string(),
                 ))
             }
@@ -325,14 +492,14 @@ impl Parser {
             Some(t) => match t {
                 Token::LeftParen(_) => {}
                 _ => {
-                    return Err(ParseError::Invalid(format!(
-                        "expected left paren. but found {:?}",
-                        self.current_token()
+                    return Err(ParseError::invalid(format!(
+                        "expected left paren. but found {}",
+                        Self::found(self.current_token())
                     )))
                 }
             },
             None => {
-                return Err(ParseError::Invalid(
+                return Err(ParseError::invalid(
                     "expected left paren. but not found.".to_string(),
                 ))
             }
@@ -346,14 +513,14 @@ impl Parser {
             Some(t) => match t {
                 Token::RightParen(_) => {}
                 _ => {
-                    return Err(ParseError::Invalid(format!(
-                        "expected right paren. but found {:?}",
-                        self.current_token()
+                    return Err(ParseError::invalid(format!(
+                        "expected right paren. but found {}",
+                        Self::found(self.current_token())
                     )))
                 }
             },
             None => {
-                return Err(ParseError::Invalid(
+                return Err(ParseError::invalid(
                     "expected right paren. but not found.".to_string(),
                 ))
             }
@@ -362,24 +529,26 @@ impl Parser {
         self.next_token();
         let body = self.parse_stmt()?;
         Ok(StatementNode::While {
+            line,
             condition,
             consequence: Box::new(body),
         })
     }
 
     fn parse_func(&mut self) -> Result<StatementNode, ParseError> {
+        let line = self.current_line();
         match self.current_token() {
             Some(t) => match t {
                 Token::Fun(_) => {}
                 _ => {
-                    return Err(ParseError::Invalid(format!(
-                        "expected fun. but found {:?}",
-                        self.current_token()
+                    return Err(ParseError::invalid(format!(
+                        "expected fun. but found {}",
+                        Self::found(self.current_token())
                     )))
                 }
             },
             None => {
-                return Err(ParseError::Invalid(
+                return Err(ParseError::invalid(
                     "expected fun. but not found.".to_string(),
                 ))
             }
@@ -389,7 +558,7 @@ impl Parser {
         let token = match self.current_token() {
             Some(t) => t,
             None => {
-                return Err(ParseError::Invalid(
+                return Err(ParseError::invalid(
                     "expected any token. but not found.".to_string(),
                 ))
             }
@@ -397,8 +566,8 @@ impl Parser {
         let name = match token {
             Token::Identifer { position: _, value } => self.parse_identifer(value.clone())?,
             _ => {
-                return Err(ParseError::Invalid(format!(
-                    "expected identifer token. but found {:?}.",
+                return Err(ParseError::invalid(format!(
+                    "expected identifer token. but found {}.",
                     token
                 )))
             }
@@ -409,14 +578,14 @@ impl Parser {
             Some(t) => match t {
                 Token::LeftParen(_) => {}
                 _ => {
-                    return Err(ParseError::Invalid(format!(
-                        "expected left paren. but found {:?}",
-                        self.current_token()
+                    return Err(ParseError::invalid(format!(
+                        "expected left paren. but found {}",
+                        Self::found(self.current_token())
                     )))
                 }
             },
             None => {
-                return Err(ParseError::Invalid(
+                return Err(ParseError::invalid(
                     "expected left paren. but not found.".to_string(),
                 ))
             }
@@ -429,30 +598,41 @@ impl Parser {
                 Token::Identifer { position: _, value } => {
                     arguments.push(self.parse_identifer(value.clone())?)
                 }
-                Token::Comma(_) => {}
                 Token::RightParen(_) => break,
                 _ => {
-                    return Err(ParseError::Invalid(format!(
-                        "expected identifer or right paren or comma. but found {:?}.",
+                    return Err(ParseError::invalid(format!(
+                        "expected identifer or right paren. but found {}.",
                         token
                     )))
                 }
             }
+            // 末尾のカンマは許可する
             self.next_token();
+            match self.current_token() {
+                Some(Token::Comma(_)) => self.next_token(),
+                Some(Token::RightParen(_)) => {}
+                Some(token) => {
+                    return Err(ParseError::invalid(format!(
+                        "expected comma or right paren. but found {}.",
+                        token
+                    )))
+                }
+                None => {}
+            }
         }
 
         match self.current_token() {
             Some(t) => match t {
                 Token::RightParen(_) => {}
                 _ => {
-                    return Err(ParseError::Invalid(format!(
-                        "expected right paren. but found {:?}",
-                        self.current_token()
+                    return Err(ParseError::invalid(format!(
+                        "expected right paren. but found {}",
+                        Self::found(self.current_token())
                     )))
                 }
             },
             None => {
-                return Err(ParseError::Invalid(
+                return Err(ParseError::invalid(
                     "expected right paren. but not found.".to_string(),
                 ))
             }
@@ -461,6 +641,7 @@ impl Parser {
         self.next_token();
         let body = self.parse_stmt()?;
         Ok(StatementNode::Fun {
+            line,
             name,
             params: arguments,
             body: Box::new(body),
@@ -468,12 +649,16 @@ impl Parser {
     }
 
     fn parse_block(&mut self) -> ParseResult<StatementNode> {
-        if !matches!(self.current_token(), Some(&Token::LeftBrace(_))) {
-            return Err(ParseError::Invalid(format!(
-                "expected left brace. but found {:?}",
-                self.current_token()
-            )));
-        }
+        let line = self.current_line();
+        let open = match self.current_token() {
+            Some(Token::LeftBrace(position)) => position.clone(),
+            _ => {
+                return Err(ParseError::invalid(format!(
+                    "expected left brace. but found {}",
+                    Self::found(self.current_token())
+                )))
+            }
+        };
 
         self.next_token();
         let mut statements: Vec<StatementNode> = Vec::new();
@@ -486,21 +671,25 @@ impl Parser {
             self.next_token();
         }
 
-        if matches!(self.current_token(), Some(&Token::RightBrace(_))) {
-            Ok(StatementNode::Block { stmts: statements })
-        } else {
-            Err(ParseError::Invalid(format!(
-                "expected right brace. but found {:?}",
-                self.current_token()
-            )))
+        match self.current_token() {
+            Some(Token::RightBrace(_)) => Ok(StatementNode::Block {
+                line,
+                stmts: statements,
+            }),
+            Some(token) => Err(ParseError::invalid(format!(
+                "expected right brace. but found {}",
+                token
+            ))),
+            None => Err(Self::unterminated_error("right brace", Some(open))),
         }
     }
 
     fn parse_var(&mut self) -> ParseResult<StatementNode> {
+        let line = self.current_line();
         if !matches!(self.current_token(), Some(&Token::Var(_))) {
-            return Err(ParseError::Invalid(format!(
-                "expected var. but found {:?}",
-                self.current_token()
+            return Err(ParseError::invalid(format!(
+                "expected var. but found {}",
+                Self::found(self.current_token())
             )));
         }
 
@@ -508,7 +697,7 @@ impl Parser {
         let token = match self.current_token() {
             Some(t) => t,
             None => {
-                return Err(ParseError::Invalid(
+                return Err(ParseError::invalid(
                     "expected any token. but not found.".to_string(),
                 ))
             }
@@ -516,8 +705,8 @@ impl Parser {
         let name = match token {
             Token::Identifer { position: _, value } => self.parse_identifer(value.clone())?,
             _ => {
-                return Err(ParseError::Invalid(format!(
-                    "expected identifer token. but found {:?}.",
+                return Err(ParseError::invalid(format!(
+                    "expected identifer token. but found {}.",
                     token
                 )))
             }
@@ -526,6 +715,7 @@ impl Parser {
         if matches!(self.peek_token(), Some(&Token::Semicolon(_))) {
             self.next_token();
             return Ok(StatementNode::Var {
+                line,
                 name,
                 value: ExpressionNode::NullLiteral,
             });
@@ -533,9 +723,9 @@ impl Parser {
 
         self.next_token();
         if !matches!(self.current_token(), Some(&Token::Equal(_))) {
-            return Err(ParseError::Invalid(format!(
-                "expected equal. but found {:?}",
-                self.current_token()
+            return Err(ParseError::invalid(format!(
+                "expected equal. but found {}",
+                Self::found(self.current_token())
             )));
         }
 
@@ -544,28 +734,26 @@ impl Parser {
 
         self.next_token();
         if matches!(self.current_token(), Some(&Token::Semicolon(_))) {
-            Ok(StatementNode::Var { name, value })
+            Ok(StatementNode::Var { line, name, value })
         } else {
-            Err(ParseError::Invalid(format!(
-                "expected semicolon. but found {:?}",
-                self.current_token()
-            )))
+            Err(self.semicolon_error())
         }
     }
 
     fn parse_if(&mut self) -> ParseResult<StatementNode> {
+        let line = self.current_line();
         match self.current_token() {
             Some(t) => match t {
                 Token::If(_) => {}
                 _ => {
-                    return Err(ParseError::Invalid(format!(
-                        "expected if. but found {:?}",
-                        self.current_token()
+                    return Err(ParseError::invalid(format!(
+                        "expected if. but found {}",
+                        Self::found(self.current_token())
                     )))
                 }
             },
             None => {
-                return Err(ParseError::Invalid(
+                return Err(ParseError::invalid(
                     "expected if. but not found.".to_string(),
                 ))
             }
@@ -576,14 +764,14 @@ impl Parser {
             Some(t) => match t {
                 Token::LeftParen(_) => {}
                 _ => {
-                    return Err(ParseError::Invalid(format!(
-                        "expected left paren. but found {:?}",
-                        self.current_token()
+                    return Err(ParseError::invalid(format!(
+                        "expected left paren. but found {}",
+                        Self::found(self.current_token())
                     )))
                 }
             },
             None => {
-                return Err(ParseError::Invalid(
+                return Err(ParseError::invalid(
                     "expected left paren. but not found.".to_string(),
                 ))
             }
@@ -597,14 +785,14 @@ impl Parser {
             Some(t) => match t {
                 Token::RightParen(_) => {}
                 _ => {
-                    return Err(ParseError::Invalid(format!(
-                        "expected right paren. but found {:?}",
-                        self.current_token()
+                    return Err(ParseError::invalid(format!(
+                        "expected right paren. but found {}",
+                        Self::found(self.current_token())
                     )))
                 }
             },
             None => {
-                return Err(ParseError::Invalid(
+                return Err(ParseError::invalid(
                     "expected right paren. but not found.".to_string(),
                 ))
             }
@@ -620,6 +808,7 @@ impl Parser {
                     self.next_token();
                     let alternative = self.parse_stmt()?;
                     return Ok(StatementNode::If {
+                        line,
                         condition,
                         consequence: Box::new(consequence),
                         alternative: Some(Box::new(alternative)),
@@ -629,6 +818,7 @@ impl Parser {
             }
         }
         Ok(StatementNode::If {
+            line,
             condition,
             consequence: Box::new(consequence),
             alternative: None,
@@ -636,16 +826,14 @@ impl Parser {
     }
 
     fn parse_expression_stmt(&mut self) -> ParseResult<StatementNode> {
+        let line = self.current_line();
         let expression = self.parse_expression(PRECEDENCE_LOWEST)?;
 
         self.next_token();
         if matches!(self.current_token(), Some(&Token::Semicolon(_))) {
-            Ok(StatementNode::ExpStmt { expression })
+            Ok(StatementNode::ExpStmt { line, expression })
         } else {
-            Err(ParseError::Invalid(format!(
-                "expected semicolon. but found {:?}",
-                self.current_token()
-            )))
+            Err(self.semicolon_error())
         }
     }
 
@@ -653,7 +841,7 @@ impl Parser {
         let token = match self.current_token() {
             Some(t) => t,
             None => {
-                return Err(ParseError::Invalid(
+                return Err(ParseError::invalid(
                     "expected any token. but not found.".to_string(),
                 ))
             }
@@ -673,8 +861,8 @@ impl Parser {
             Token::Bang(_) => self.parse_prefix("!".to_string())?,
             Token::LeftParen(_) => self.parse_grouped()?,
             _ => {
-                return Err(ParseError::Invalid(format!(
-                    "expected prefix token. but found {:?}.",
+                return Err(ParseError::invalid(format!(
+                    "expected prefix token. but found {}.",
                     token
                 )))
             }
@@ -710,17 +898,21 @@ impl Parser {
                     self.next_token();
                     left = self.parse_infix_right("%", left)?;
                 }
-                Token::Equal(_) => {
+                Token::Equal(position) => {
+                    // 代入できるのは変数、添字、プロパティだけ。コンパイラはこれ以外を受け取らない
                     match &left {
                         ExpressionNode::Identifer(_) => {}
-                        ExpressionNode::Assign {
-                            ope: _,
-                            left: _,
-                            right: _,
-                        } => {}
                         ExpressionNode::IndexCall { array: _, index: _ } => {}
                         ExpressionNode::SetProperty { left: _, right: _ } => {}
-                        _ => break,
+                        invalid => {
+                            return Err(ParseError::Invalid(
+                                Diagnostic::new(format!(
+                                    "invalid assignment target \"{}\".",
+                                    invalid
+                                ))
+                                .at(position),
+                            ))
+                        }
                     }
                     self.next_token();
                     left = self.parse_assign("=", left)?;
@@ -749,6 +941,11 @@ impl Parser {
                     self.next_token();
                     left = self.parse_infix(">=", left)?;
                 }
+                // for 文の in は変数名の直後で読むので、ここに来るのは式の中の in だけ
+                Token::In(_) => {
+                    self.next_token();
+                    left = self.parse_infix("in", left)?;
+                }
                 Token::And(_) => {
                     self.next_token();
                     left = self.parse_logical("and", left)?;
@@ -780,39 +977,231 @@ impl Parser {
     }
 
     fn parse_grouped(&mut self) -> ParseResult<ExpressionNode> {
+        let open = self.current_position();
         self.next_token();
         let result = self.parse_expression(PRECEDENCE_LOWEST);
         if let Some(token) = self.peek_token() {
             match token {
                 Token::RightParen(_) => self.next_token(),
                 _ => {
-                    return Err(ParseError::Invalid(format!(
-                        "expected right paren. but found {:?}.",
+                    return Err(ParseError::invalid(format!(
+                        "expected right paren. but found {}.",
                         token
                     )))
                 }
             }
         } else {
-            return Err(ParseError::Invalid(
-                "expected right paren. but not found.".to_string(),
-            ));
+            return Err(Self::unterminated_error("right paren", open));
         }
         result
     }
 
     fn parse_array(&mut self) -> ParseResult<ExpressionNode> {
+        let open = self.current_position();
         self.next_token();
-        // let precedence = self.current_precedence();
-        let mut values: Vec<ExpressionNode> = Vec::new();
+        let is_end: fn(&Token) -> bool = |token| matches!(token, Token::RightBracket(_));
+        let mut values = Vec::new();
+        // 先頭の式の後ろに for が続けば内包表記。そうでなければ残りを要素として読む
+        if !matches!(
+            self.current_token(),
+            Some(Token::RightBracket(_)) | Some(Token::Comma(_)) | None
+        ) {
+            let element = self.parse_expression(PRECEDENCE_LOWEST)?;
+            if matches!(self.peek_token(), Some(Token::For(_))) {
+                return self.parse_comprehension(element, open);
+            }
+            values.push(element);
+            self.skip_list_separator(is_end, "right bracket")?;
+        }
+        let values = self.parse_expression_list(values, is_end, "right bracket", open)?;
+        Ok(ExpressionNode::ArrayLiteral(values))
+    }
+
+    // 呼び出し時は要素の式の最後のトークンが current_token になっている
+    fn parse_comprehension(
+        &mut self,
+        element: ExpressionNode,
+        open: Option<Position>,
+    ) -> ParseResult<ExpressionNode> {
+        let mut loops = Vec::new();
+        let mut condition = None;
+        self.next_token();
+        while let Some(Token::For(_)) = self.current_token() {
+            self.next_token();
+            let name = match self.current_token() {
+                Some(Token::Identifer { value, .. }) => ExpressionNode::Identifer(value.clone()),
+                _ => {
+                    return Err(ParseError::invalid(format!(
+                        "expected identifer. but found {}",
+                        Self::found(self.current_token())
+                    )))
+                }
+            };
+            self.next_token();
+            if !matches!(self.current_token(), Some(Token::In(_))) {
+                return Err(ParseError::invalid(format!(
+                    "expected in. but found {}",
+                    Self::found(self.current_token())
+                )));
+            }
+            self.next_token();
+            let iterable = self.parse_expression(PRECEDENCE_LOWEST)?;
+            loops.push((name, iterable));
+            self.next_token();
+        }
+        if let Some(Token::If(_)) = self.current_token() {
+            self.next_token();
+            condition = Some(Box::new(self.parse_expression(PRECEDENCE_LOWEST)?));
+            self.next_token();
+        }
+        match self.current_token() {
+            Some(Token::RightBracket(_)) => Ok(ExpressionNode::Comprehension {
+                element: Box::new(element),
+                loops,
+                condition,
+            }),
+            Some(token) => Err(ParseError::invalid(format!(
+                "expected right bracket. but found {}",
+                token
+            ))),
+            None => Err(Self::unterminated_error("right bracket", open)),
+        }
+    }
+
+    // 終端トークンまでカンマ区切りの式を読む。末尾のカンマは許可する。
+    // 呼び出し後は終端トークンが current_token になる。
+    // open は開き括弧の位置で、終端が見つからなかった場合のエラーに含める。
+    // values には読み終えた先頭の要素を渡せる。
+    fn parse_expression_list(
+        &mut self,
+        mut values: Vec<ExpressionNode>,
+        is_end: fn(&Token) -> bool,
+        end_name: &str,
+        open: Option<Position>,
+    ) -> ParseResult<Vec<ExpressionNode>> {
         while let Some(token) = self.current_token() {
-            match token {
-                Token::RightBracket(_) => break,
-                Token::Comma(_) => {}
-                _ => values.push(self.parse_expression(PRECEDENCE_LOWEST)?),
+            if is_end(token) {
+                return Ok(values);
+            }
+            if let Token::Comma(_) = token {
+                return Err(ParseError::invalid(format!(
+                    "expected expression or {}. but found {}.",
+                    end_name, token
+                )));
+            }
+            values.push(self.parse_expression(PRECEDENCE_LOWEST)?);
+            self.skip_list_separator(is_end, end_name)?;
+        }
+        Err(Self::unterminated_error(end_name, open))
+    }
+
+    // 要素の式の直後に呼ぶ。カンマなら読み飛ばし、終端ならそのまま残す
+    fn skip_list_separator(
+        &mut self,
+        is_end: fn(&Token) -> bool,
+        end_name: &str,
+    ) -> ParseResult<()> {
+        self.next_token();
+        match self.current_token() {
+            Some(Token::Comma(_)) => self.next_token(),
+            Some(token) if is_end(token) => {}
+            Some(token) => {
+                return Err(ParseError::invalid(format!(
+                    "expected comma or {}. but found {}.",
+                    end_name, token
+                )))
+            }
+            None => {}
+        }
+        Ok(())
+    }
+
+    // 呼び出しの引数リスト。name: value の形の引数は位置引数の後ろにだけ書ける
+    fn parse_arguments(&mut self, open: Option<Position>) -> ParseResult<Vec<ExpressionNode>> {
+        let mut arguments: Vec<ExpressionNode> = Vec::new();
+        while let Some(token) = self.current_token() {
+            if let Token::RightParen(_) = token {
+                return Ok(arguments);
+            }
+            if let Token::Comma(_) = token {
+                return Err(ParseError::invalid(format!(
+                    "expected expression or right paren. but found {}.",
+                    token
+                )));
+            }
+            let name = match (token, self.peek_token()) {
+                (Token::Identifer { value, .. }, Some(Token::Colon(_))) => Some(value.clone()),
+                _ => None,
+            };
+            match name {
+                Some(name) => {
+                    let duplicated = arguments.iter().any(|arg| {
+                        matches!(arg, ExpressionNode::NamedArgument { name: n, .. } if *n == name)
+                    });
+                    if duplicated {
+                        return Err(ParseError::invalid(format!(
+                            "named argument \"{}\" is given more than once.",
+                            name
+                        )));
+                    }
+                    self.next_token();
+                    self.next_token();
+                    let value = self.parse_expression(PRECEDENCE_LOWEST)?;
+                    arguments.push(ExpressionNode::NamedArgument {
+                        name,
+                        value: Box::new(value),
+                    });
+                }
+                None => {
+                    if let Some(ExpressionNode::NamedArgument { .. }) = arguments.last() {
+                        return Err(ParseError::invalid(
+                            "positional argument follows named argument.".to_string(),
+                        ));
+                    }
+                    arguments.push(self.parse_expression(PRECEDENCE_LOWEST)?);
+                }
             }
+
             self.next_token();
+            match self.current_token() {
+                Some(Token::Comma(_)) => self.next_token(),
+                Some(Token::RightParen(_)) => {}
+                Some(token) => {
+                    return Err(ParseError::invalid(format!(
+                        "expected comma or right paren. but found {}.",
+                        token
+                    )))
+                }
+                None => {}
+            }
+        }
+        Err(Self::unterminated_error("right paren", open))
+    }
+
+    // 文末のセミコロンがない場合のエラー。入力が終わっていれば最後のトークンの位置を示す
+    fn semicolon_error(&self) -> ParseError {
+        if self.current_token().is_some() {
+            return ParseError::invalid(format!(
+                "expected semicolon. but found {}",
+                Self::found(self.current_token())
+            ));
+        }
+        match self.tokens.last() {
+            Some(last) => ParseError::UnexpectedEof(
+                Diagnostic::new("expected ';' at end of input.").at(last.position()),
+            ),
+            None => ParseError::unexpected_eof("expected ';' at end of input.".to_string()),
+        }
+    }
+
+    // 閉じ括弧が見つからないまま入力が終わった場合のエラー
+    fn unterminated_error(end_name: &str, open: Option<Position>) -> ParseError {
+        match open {
+            Some(open) => ParseError::UnexpectedEof(
+                Diagnostic::new(format!("expected {}. but not found.", end_name)).at(&open),
+            ),
+            None => ParseError::unexpected_eof(format!("expected {}. but not found.", end_name)),
         }
-        Ok(ExpressionNode::ArrayLiteral(values))
     }
 
     fn parse_float(&mut self, value: f64) -> ParseResult<ExpressionNode> {
@@ -848,14 +1237,14 @@ impl Parser {
             match token {
                 Token::RightBracket(_) => self.next_token(),
                 _ => {
-                    return Err(ParseError::Invalid(format!(
-                        "expected right bracket. but found {:?}.",
+                    return Err(ParseError::invalid(format!(
+                        "expected right bracket. but found {}.",
                         token
                     )))
                 }
             }
         } else {
-            return Err(ParseError::Invalid(
+            return Err(ParseError::invalid(
                 "expected right bracket. but not found.".to_string(),
             ));
         }
@@ -866,17 +1255,9 @@ impl Parser {
     }
 
     fn parse_funcall(&mut self, left: ExpressionNode) -> ParseResult<ExpressionNode> {
+        let open = self.current_position();
         self.next_token();
-        // let precedence = self.current_precedence();
-        let mut parameter: Vec<ExpressionNode> = Vec::new();
-        while let Some(token) = self.current_token() {
-            match token {
-                Token::RightParen(_) => break,
-                Token::Comma(_) => {}
-                _ => parameter.push(self.parse_expression(PRECEDENCE_LOWEST)?),
-            }
-            self.next_token();
-        }
+        let parameter = self.parse_arguments(open)?;
         Ok(ExpressionNode::FunCall {
             function: Box::new(left),
             arguments: parameter,
@@ -898,10 +1279,7 @@ impl Parser {
         })
     }
 
-    fn parse_range(
-        &mut self,
-        left: ExpressionNode,
-    ) -> ParseResult<ExpressionNode> {
+    fn parse_range(&mut self, left: ExpressionNode) -> ParseResult<ExpressionNode> {
         let precedence = self.current_precedence();
         self.next_token();
         let right = self.parse_expression(precedence)?;
@@ -935,16 +1313,9 @@ impl Parser {
             }
             if let Token::LeftParen(_) = token {
                 self.next_token();
+                let open = self.current_position();
                 self.next_token();
-                let mut parameter: Vec<ExpressionNode> = Vec::new();
-                while let Some(token) = self.current_token() {
-                    match token {
-                        Token::RightParen(_) => break,
-                        Token::Comma(_) => {}
-                        _ => parameter.push(self.parse_expression(PRECEDENCE_LOWEST)?),
-                    }
-                    self.next_token();
-                }
+                let parameter = self.parse_arguments(open)?;
 
                 if is_super {
                     return Ok(ExpressionNode::InvokeSuperMethod {
@@ -1019,6 +1390,14 @@ impl Parser {
         })
     }
 
+    // エラーメッセージで見つかったトークンを表す。トークンがなければ入力の終わり
+    fn found(token: Option<&Token>) -> String {
+        match token {
+            Some(token) => token.to_string(),
+            None => "end of input".to_string(),
+        }
+    }
+
     fn current_token(&self) -> Option<&Token> {
         self.tokens.get(self.cur_index)
     }
@@ -1027,6 +1406,17 @@ impl Parser {
         self.tokens.get(self.cur_index + 1)
     }
 
+    fn current_line(&self) -> i32 {
+        match self.current_token() {
+            Some(token) => token.position().line,
+            None => 0,
+        }
+    }
+
+    fn current_position(&self) -> Option<Position> {
+        self.current_token().map(|token| token.position().clone())
+    }
+
     fn current_precedence(&self) -> i32 {
         self.get_precedence(self.current_token())
     }
@@ -1040,13 +1430,14 @@ impl Parser {
             Some(token) => match token {
                 Token::Equal(_) => PRECEDENCE_ASSIGNMENT,
                 Token::And(_) => PRECEDENCE_AND,
-                Token::Or(_) => PRECEDENCE_AND,
+                Token::Or(_) => PRECEDENCE_OR,
                 Token::EqualEqual(_) => PRECEDENCE_EQUALITY,
                 Token::BangEqual(_) => PRECEDENCE_EQUALITY,
                 Token::Less(_) => PRECEDENCE_COMPARISON,
                 Token::LessEqual(_) => PRECEDENCE_COMPARISON,
                 Token::Greater(_) => PRECEDENCE_COMPARISON,
                 Token::GreaterEqual(_) => PRECEDENCE_COMPARISON,
+                Token::In(_) => PRECEDENCE_COMPARISON,
                 Token::Plus(_) => PRECEDENCE_TERM,
                 Token::Minus(_) => PRECEDENCE_TERM,
                 Token::Star(_) => PRECEDENCE_FACTOR,
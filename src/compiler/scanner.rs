@@ -1,34 +1,110 @@
-use super::token::{Position, Token};
+use super::{
+    diagnostic::Diagnostic,
+    token::{Position, Token},
+};
+use std::fmt::Display;
 use std::iter::Peekable;
 use std::str::Chars;
 
 #[derive(Debug)]
 pub enum ScannerError {
-    Invalid(String),
+    Invalid(Diagnostic),
+}
+
+impl ScannerError {
+    fn invalid(message: impl Into<String>) -> Self {
+        ScannerError::Invalid(Diagnostic::new(message))
+    }
+
+    pub fn diagnostic(&self) -> &Diagnostic {
+        match self {
+            ScannerError::Invalid(diagnostic) => diagnostic,
+        }
+    }
+}
+
+impl Display for ScannerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.diagnostic())
+    }
 }
 
 type ScannerResult<T> = Result<T, ScannerError>;
 
+// 先頭の文字で候補を絞ってから比べる。キーワードでなければ None
+fn keyword(ident: &[u8]) -> Option<fn(Position) -> Token> {
+    let (keyword, token): (&[u8], fn(Position) -> Token) = match ident.first()? {
+        b'a' => (b"and", Token::And),
+        b'b' => (b"break", Token::Break),
+        b'c' => (b"class", Token::Class),
+        b'd' => (b"defer", Token::Defer),
+        b'e' => (b"else", Token::Else),
+        b'f' => match ident.get(1)? {
+            b'a' => (b"false", Token::False),
+            b'o' => (b"for", Token::For),
+            b'u' => (b"fun", Token::Fun),
+            _ => return None,
+        },
+        b'i' => match ident.get(1)? {
+            b'f' => (b"if", Token::If),
+            b'm' => (b"import", Token::Import),
+            b'n' => (b"in", Token::In),
+            _ => return None,
+        },
+        b'n' => (b"null", Token::Null),
+        b'o' => (b"or", Token::Or),
+        b'p' => (b"print", Token::Print),
+        b'r' => (b"return", Token::Return),
+        b's' => (b"super", Token::Super),
+        b't' => match ident.get(1)? {
+            b'h' => (b"this", Token::This),
+            b'o' => (b"to", Token::To),
+            b'r' => (b"true", Token::True),
+            _ => return None,
+        },
+        b'v' => (b"var", Token::Var),
+        b'w' => (b"while", Token::While),
+        b'y' => (b"yield", Token::Yield),
+        _ => return None,
+    };
+    if ident == keyword {
+        Some(token)
+    } else {
+        None
+    }
+}
+
 pub struct Scanner<'a> {
     current_line: i32,
     current_column: i32,
     current_length: i32,
     chars: Peekable<Chars<'a>>,
+    source: &'a str,
+    // chars が次に返す文字のバイト位置
+    offset: usize,
 }
 
 impl<'a> Scanner<'a> {
     pub fn new(contents: &'a str) -> Self {
         Self {
-            current_line: 0,
+            current_line: 1,
             current_column: 0,
             current_length: 0,
             chars: contents.chars().peekable(),
+            source: contents,
+            offset: 0,
         }
     }
 
+    fn advance(&mut self) -> Option<char> {
+        let c = self.chars.next()?;
+        self.offset += c.len_utf8();
+        Some(c)
+    }
+
     pub fn tokenize(&mut self) -> ScannerResult<Vec<Token>> {
         let mut tokens = vec![];
-        while let Some(token) = self.next_token()? {
+        while let Some(token) = self.next_token().map_err(|e| self.locate(e))? {
             match token {
                 Token::WhiteSpace(_) => {}
                 Token::LineFeed(_) => {}
@@ -40,23 +116,37 @@ impl<'a> Scanner<'a> {
         Ok(tokens)
     }
 
+    // 読んでいる途中のトークンの位置をエラーに付ける
+    fn locate(&self, error: ScannerError) -> ScannerError {
+        let position = Position::new(
+            self.current_line,
+            self.current_column,
+            self.current_length.max(1),
+        );
+        match error {
+            ScannerError::Invalid(diagnostic) => {
+                ScannerError::Invalid(diagnostic.or_at(Some(&position)))
+            }
+        }
+    }
+
     fn next_token(&mut self) -> ScannerResult<Option<Token>> {
         match self.chars.peek() {
             Some(c) => match c {
                 c if *c == ' ' || *c == '\t' || *c == '\r' => self.skip_whitespace(),
                 c if *c == '\n' => self.skip_linefeed(),
-                '{' | '}' | '[' | ']' | '(' | ')' | ',' | '+' | '-' | '*' | '/' | '^' | '%'
-                | '.' | ';' | '!' | '=' | '<' | '>' => self.parse_symbol(),
+                '{' | '}' | '[' | ']' | '(' | ')' | ',' | ':' | '+' | '-' | '*' | '/' | '^'
+                | '%' | '.' | ';' | '!' | '=' | '<' | '>' => self.parse_symbol(),
                 '"' => {
-                    self.chars.next();
+                    self.advance();
                     self.current_length += 1;
                     self.parse_string_token()
                 }
                 c if c.is_numeric() => self.parse_number_token(),
-                c if c.is_ascii_alphabetic() => self.parse_identifer_token(),
-                _ => Err(ScannerError::Invalid(format!(
-                    "error: an unexpected char {}, {}, {}",
-                    c, self.current_line, self.current_column
+                c if c.is_ascii_alphabetic() || *c == '_' => self.parse_identifer_token(),
+                _ => Err(ScannerError::invalid(format!(
+                    "error: an unexpected char {}",
+                    c
                 ))),
             },
             None => Ok(None),
@@ -67,7 +157,7 @@ impl<'a> Scanner<'a> {
         while let Some(c) = self.chars.peek() {
             match c {
                 c if *c == ' ' || *c == '\t' || *c == '\r' => {
-                    self.chars.next();
+                    self.advance();
                     self.current_length += 1;
                 }
                 _ => break,
@@ -89,13 +179,12 @@ impl<'a> Scanner<'a> {
         self.current_line += 1;
         self.current_column = 0;
         self.current_length = 0;
-        self.chars.next();
+        self.advance();
         result
     }
 
     fn parse_symbol(&mut self) -> ScannerResult<Option<Token>> {
-        let mut length = 1;
-        let mut position = Position::new(self.current_line, self.current_column, 1);
+        let position = Position::new(self.current_line, self.current_column, 1);
         let result = match self.chars.peek() {
             Some(c) => match c {
                 '{' => Ok(Some(Token::LeftBrace(position))),
@@ -105,6 +194,7 @@ impl<'a> Scanner<'a> {
                 '(' => Ok(Some(Token::LeftParen(position))),
                 ')' => Ok(Some(Token::RightParen(position))),
                 ',' => Ok(Some(Token::Comma(position))),
+                ':' => Ok(Some(Token::Colon(position))),
                 '+' => Ok(Some(Token::Plus(position))),
                 '-' => Ok(Some(Token::Minus(position))),
                 '*' => Ok(Some(Token::Star(position))),
@@ -113,194 +203,68 @@ impl<'a> Scanner<'a> {
                 '%' => Ok(Some(Token::Percent(position))),
                 '.' => Ok(Some(Token::Dot(position))),
                 ';' => Ok(Some(Token::Semicolon(position))),
-                '!' => {
-                    self.chars.next();
-                    match self.chars.peek() {
-                        Some(c) => match c {
-                            '=' => {
-                                length = 2;
-                                position.length = 2;
-                                Ok(Some(Token::BangEqual(position)))
-                            }
-                            _ => {
-                                let result = Ok(Some(Token::Bang(position)));
-                                self.current_column += 1;
-                                self.current_length = 0;
-                                return result;
-                            }
-                        },
-                        None => {
-                            let result = Ok(Some(Token::Bang(position)));
-                            self.current_column += 1;
-                            self.current_length = 0;
-                            return result;
-                        }
-                    }
-                }
-                '=' => {
-                    self.chars.next();
-                    match self.chars.peek() {
-                        Some(c) => match c {
-                            '=' => {
-                                length = 2;
-                                position.length = 2;
-                                Ok(Some(Token::EqualEqual(position)))
-                            }
-                            _ => {
-                                let result = Ok(Some(Token::Equal(position)));
-                                self.current_column += 1;
-                                self.current_length = 0;
-                                return result;
-                            }
-                        },
-                        None => {
-                            let result = Ok(Some(Token::Equal(position)));
-                            self.current_column += 1;
-                            self.current_length = 0;
-                            return result;
-                        }
-                    }
-                }
-                '<' => {
-                    self.chars.next();
-                    match self.chars.peek() {
-                        Some(c) => match c {
-                            '=' => {
-                                length = 2;
-                                position.length = 2;
-                                Ok(Some(Token::LessEqual(position)))
-                            }
-                            _ => {
-                                let result = Ok(Some(Token::Less(position)));
-                                self.current_column += 1;
-                                self.current_length = 0;
-                                return result;
-                            }
-                        },
-                        None => {
-                            let result = Ok(Some(Token::Less(position)));
-                            self.current_column += 1;
-                            self.current_length = 0;
-                            return result;
-                        }
-                    }
-                }
-                '>' => {
-                    self.chars.next();
-                    match self.chars.peek() {
-                        Some(c) => match c {
-                            '=' => {
-                                length = 2;
-                                position.length = 2;
-                                Ok(Some(Token::GreaterEqual(position)))
-                            }
-                            _ => {
-                                let result = Ok(Some(Token::Greater(position)));
-                                self.current_column += 1;
-                                self.current_length = 0;
-                                return result;
-                            }
-                        },
-                        None => {
-                            let result = Ok(Some(Token::Greater(position)));
-                            self.current_column += 1;
-                            self.current_length = 0;
-                            return result;
-                        }
-                    }
-                }
-                _ => Err(ScannerError::Invalid(format!(
+                '!' => return self.parse_equal_suffix(Token::Bang, Token::BangEqual),
+                '=' => return self.parse_equal_suffix(Token::Equal, Token::EqualEqual),
+                '<' => return self.parse_equal_suffix(Token::Less, Token::LessEqual),
+                '>' => return self.parse_equal_suffix(Token::Greater, Token::GreaterEqual),
+                _ => Err(ScannerError::invalid(format!(
                     "error: an unexpected char {}",
                     c
                 ))),
             },
             None => Ok(None),
         };
-        self.current_column += length;
+        self.current_column += 1;
         self.current_length = 0;
-        self.chars.next();
+        self.advance();
         result
     }
 
+    // '!' '=' '<' '>' の後ろに '=' が続けば2文字のトークン、そうでなければ1文字のトークンにする
+    fn parse_equal_suffix(
+        &mut self,
+        single: fn(Position) -> Token,
+        double: fn(Position) -> Token,
+    ) -> ScannerResult<Option<Token>> {
+        let mut position = Position::new(self.current_line, self.current_column, 1);
+        self.advance();
+        let token = match self.chars.peek() {
+            Some('=') => {
+                self.advance();
+                position.length = 2;
+                double(position)
+            }
+            _ => single(position),
+        };
+        self.current_column += token.position().length;
+        self.current_length = 0;
+        Ok(Some(token))
+    }
+
     fn parse_identifer_token(&mut self) -> ScannerResult<Option<Token>> {
-        let mut ident_str = String::new();
+        let start = self.offset;
         while let Some(&c) = self.chars.peek() {
             if c.is_ascii_alphanumeric() | matches!(c, '_') {
-                self.chars.next();
+                self.advance();
                 self.current_length += 1;
-                ident_str.push(c);
             } else {
                 break;
             }
         }
 
-        let result: ScannerResult<Option<Token>>;
+        // キーワードはソースの切り出しのまま判定し、識別子のときだけ String を作る
+        let ident = &self.source[start..self.offset];
         let position = Position::new(self.current_line, self.current_column, self.current_length);
-        match &*ident_str {
-            "and" => {
-                result = Ok(Some(Token::And(position)));
-            }
-            "class" => {
-                result = Ok(Some(Token::Class(position)));
-            }
-            "else" => {
-                result = Ok(Some(Token::Else(position)));
-            }
-            "false" => {
-                result = Ok(Some(Token::False(position)));
-            }
-            "for" => {
-                result = Ok(Some(Token::For(position)));
-            }
-            "fun" => {
-                result = Ok(Some(Token::Fun(position)));
-            }
-            "if" => {
-                result = Ok(Some(Token::If(position)));
-            }
-            "null" => {
-                result = Ok(Some(Token::Null(position)));
-            }
-            "or" => {
-                result = Ok(Some(Token::Or(position)));
-            }
-            "return" => {
-                result = Ok(Some(Token::Return(position)));
-            }
-            "true" => {
-                result = Ok(Some(Token::True(position)));
-            }
-            "var" => {
-                result = Ok(Some(Token::Var(position)));
-            }
-            "while" => {
-                result = Ok(Some(Token::While(position)));
-            }
-            "in" => {
-                result = Ok(Some(Token::In(position)));
-            }
-            "print" => {
-                result = Ok(Some(Token::Print(position)));
-            }
-            "this" => {
-                result = Ok(Some(Token::This(position)));
-            }
-            "super" => {
-                result = Ok(Some(Token::Super(position)));
-            }
-            "to" => {
-                result = Ok(Some(Token::To(position)));
-            }
-            _ => {
-                result = Ok(Some(Token::Identifer {
-                    position,
-                    value: ident_str,
-                }));
-            }
-        }
+        let token = match keyword(ident.as_bytes()) {
+            Some(keyword) => keyword(position),
+            None => Token::Identifer {
+                position,
+                value: ident.to_string(),
+            },
+        };
         self.current_column += self.current_length;
         self.current_length = 0;
-        result
+        Ok(Some(token))
     }
 
     fn parse_number_token(&mut self) -> ScannerResult<Option<Token>> {
@@ -308,17 +272,17 @@ impl<'a> Scanner<'a> {
         let mut is_float = false;
         while let Some(&c) = self.chars.peek() {
             if c.is_numeric() {
-                self.chars.next();
+                self.advance();
                 self.current_length += 1;
                 number_str.push(c);
             } else if !is_float && matches!(c, '.') {
                 is_float = true;
-                self.chars.next();
+                self.advance();
                 self.current_length += 1;
                 number_str.push(c);
                 if let Some(&c) = self.chars.peek() {
                     if !c.is_numeric() {
-                        return Err(ScannerError::Invalid(format!(
+                        return Err(ScannerError::invalid(format!(
                             "error: expected numeric but found '{}'.",
                             c
                         )));
@@ -337,7 +301,7 @@ impl<'a> Scanner<'a> {
                     position,
                     value: number,
                 })),
-                Err(e) => Err(ScannerError::Invalid(format!("error: {}", e.to_string()))),
+                Err(e) => Err(Self::number_error(e.to_string(), &position)),
             };
         } else {
             result = match number_str.parse::<i64>() {
@@ -345,7 +309,7 @@ impl<'a> Scanner<'a> {
                     position,
                     value: number,
                 })),
-                Err(e) => Err(ScannerError::Invalid(format!("error: {}", e.to_string()))),
+                Err(e) => Err(Self::number_error(e.to_string(), &position)),
             };
         }
         self.current_column += self.current_length;
@@ -353,16 +317,24 @@ impl<'a> Scanner<'a> {
         result
     }
 
+    // 数値に変換できないリテラルのエラーは、読み終えた後の位置ではなくリテラルの位置を示す
+    fn number_error(message: String, position: &Position) -> ScannerError {
+        ScannerError::Invalid(Diagnostic::new(format!("error: {}", message)).at(position))
+    }
+
     fn parse_string_token(&mut self) -> ScannerResult<Option<Token>> {
         let mut utf16 = vec![];
         let mut buffer = String::new();
+        // 文字列の中で改行した場合も、トークンの位置は開始位置にする
+        let line = self.current_line;
+        let mut line_start: Option<i32> = None;
 
-        while let Some(c1) = self.chars.next() {
+        while let Some(c1) = self.advance() {
             self.current_length += 1;
             match c1 {
                 '\\' => {
-                    let c2 = self.chars.next().ok_or_else(|| {
-                        ScannerError::Invalid("error: a next char is expected".to_string())
+                    let c2 = self.advance().ok_or_else(|| {
+                        ScannerError::invalid("error: a next char is expected".to_string())
                     })?;
                     self.current_length += 1;
 
@@ -380,7 +352,7 @@ impl<'a> Scanner<'a> {
                     } else if c2 == 'u' {
                         let hexs = (0..4)
                             .filter_map(|_| {
-                                let c = self.chars.next()?;
+                                let c = self.advance()?;
                                 self.current_length += 1;
                                 if c.is_ascii_hexdigit() {
                                     Some(c)
@@ -392,14 +364,14 @@ impl<'a> Scanner<'a> {
                         match u16::from_str_radix(&hexs.iter().collect::<String>(), 16) {
                             Ok(code_point) => utf16.push(code_point),
                             Err(e) => {
-                                return Err(ScannerError::Invalid(format!(
+                                return Err(ScannerError::invalid(format!(
                                     "error: a unicode character is expected {}",
                                     e.to_string()
                                 )))
                             }
                         };
                     } else {
-                        return Err(ScannerError::Invalid(format!(
+                        return Err(ScannerError::invalid(format!(
                             "error: an unexpected escaped char {}",
                             c2
                         )));
@@ -407,16 +379,24 @@ impl<'a> Scanner<'a> {
                 }
                 '"' => {
                     Self::push_utf16(&mut buffer, &mut utf16)?;
-                    let position =
-                        Position::new(self.current_line, self.current_column, self.current_length);
+                    let position = Position::new(line, self.current_column, self.current_length);
                     let result = Ok(Some(Token::String {
                         position,
                         value: buffer,
                     }));
-                    self.current_column += self.current_length;
+                    self.current_column = match line_start {
+                        Some(line_start) => self.current_length - line_start,
+                        None => self.current_column + self.current_length,
+                    };
                     self.current_length = 0;
                     return result;
                 }
+                '\n' => {
+                    Self::push_utf16(&mut buffer, &mut utf16)?;
+                    buffer.push(c1);
+                    self.current_line += 1;
+                    line_start = Some(self.current_length);
+                }
                 _ => {
                     Self::push_utf16(&mut buffer, &mut utf16)?;
                     buffer.push(c1);
@@ -436,7 +416,7 @@ impl<'a> Scanner<'a> {
                 utf16.clear();
             }
             Err(e) => {
-                return Err(ScannerError::Invalid(format!("error: {}", e.to_string())));
+                return Err(ScannerError::invalid(format!("error: {}", e.to_string())));
             }
         };
         Ok(())
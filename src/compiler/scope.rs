@@ -3,6 +3,9 @@ pub struct Local {
     pub name: String,
     pub depth: i32,
     pub is_captured: bool,
+    // 宣言された行と、値が読まれたかどうか。未使用の警告に使う
+    pub line: u32,
+    pub is_used: bool,
 }
 
 impl Local {
@@ -11,6 +14,8 @@ impl Local {
             name: name.into(),
             depth,
             is_captured: false,
+            line: 0,
+            is_used: false,
         }
     }
 }
@@ -26,3 +31,20 @@ impl Upvalue {
         Self { index, is_local }
     }
 }
+
+// break を含むループ。scope_depth はループの外側のスコープの深さで、
+// break はそれより深いローカル変数を片付けてから breaks の位置からループの後ろへ飛ぶ
+#[derive(Debug, Clone, PartialEq, PartialOrd)]
+pub struct Loop {
+    pub scope_depth: i32,
+    pub breaks: Vec<usize>,
+}
+
+impl Loop {
+    pub fn new(scope_depth: i32) -> Self {
+        Self {
+            scope_depth,
+            breaks: Vec::new(),
+        }
+    }
+}
@@ -1,4 +1,6 @@
-#[derive(Debug)]
+use std::fmt::Display;
+
+#[derive(Debug, Clone)]
 pub struct Position {
     pub line: i32,
     pub column: i32,
@@ -15,6 +17,12 @@ impl Position {
     }
 }
 
+impl Display for Position {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}, column {}", self.line, self.column)
+    }
+}
+
 #[derive(Debug)]
 pub enum Token {
     WhiteSpace(Position),
@@ -26,6 +34,7 @@ pub enum Token {
     LeftParen(Position),
     RightParen(Position),
     Comma(Position),
+    Colon(Position),
     Dot(Position),
     Minus(Position),
     Plus(Position),
@@ -65,4 +74,128 @@ pub enum Token {
     This(Position),
     Super(Position),
     To(Position),
+    Yield(Position),
+    Defer(Position),
+    Import(Position),
+    Break(Position),
+}
+
+impl Token {
+    pub fn position(&self) -> &Position {
+        match self {
+            Token::WhiteSpace(position) => position,
+            Token::LineFeed(position) => position,
+            Token::LeftBrace(position) => position,
+            Token::RightBrace(position) => position,
+            Token::LeftBracket(position) => position,
+            Token::RightBracket(position) => position,
+            Token::LeftParen(position) => position,
+            Token::RightParen(position) => position,
+            Token::Comma(position) => position,
+            Token::Colon(position) => position,
+            Token::Dot(position) => position,
+            Token::Minus(position) => position,
+            Token::Plus(position) => position,
+            Token::Semicolon(position) => position,
+            Token::Slash(position) => position,
+            Token::Star(position) => position,
+            Token::Pow(position) => position,
+            Token::Percent(position) => position,
+            Token::Bang(position) => position,
+            Token::BangEqual(position) => position,
+            Token::Equal(position) => position,
+            Token::EqualEqual(position) => position,
+            Token::Greater(position) => position,
+            Token::GreaterEqual(position) => position,
+            Token::Less(position) => position,
+            Token::LessEqual(position) => position,
+            Token::Identifer { position, .. } => position,
+            Token::String { position, .. } => position,
+            Token::Float { position, .. } => position,
+            Token::Integer { position, .. } => position,
+            Token::And(position) => position,
+            Token::Class(position) => position,
+            Token::Else(position) => position,
+            Token::False(position) => position,
+            Token::For(position) => position,
+            Token::Fun(position) => position,
+            Token::If(position) => position,
+            Token::Null(position) => position,
+            Token::Or(position) => position,
+            Token::Return(position) => position,
+            Token::True(position) => position,
+            Token::Var(position) => position,
+            Token::While(position) => position,
+            Token::In(position) => position,
+            Token::Print(position) => position,
+            Token::This(position) => position,
+            Token::Super(position) => position,
+            Token::To(position) => position,
+            Token::Yield(position) => position,
+            Token::Defer(position) => position,
+            Token::Import(position) => position,
+            Token::Break(position) => position,
+        }
+    }
+}
+
+// エラーメッセージ用。記号とキーワードはソース上の綴り、リテラルは種類と値で表す
+impl Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let lexeme = match self {
+            Token::WhiteSpace(_) => return write!(f, "whitespace"),
+            Token::LineFeed(_) => return write!(f, "line feed"),
+            Token::Identifer { value, .. } => return write!(f, "identifer \"{}\"", value),
+            Token::String { value, .. } => return write!(f, "string {:?}", value),
+            Token::Float { value, .. } => return write!(f, "float {}", value),
+            Token::Integer { value, .. } => return write!(f, "integer {}", value),
+            Token::LeftBrace(_) => "{",
+            Token::RightBrace(_) => "}",
+            Token::LeftBracket(_) => "[",
+            Token::RightBracket(_) => "]",
+            Token::LeftParen(_) => "(",
+            Token::RightParen(_) => ")",
+            Token::Comma(_) => ",",
+            Token::Colon(_) => ":",
+            Token::Dot(_) => ".",
+            Token::Minus(_) => "-",
+            Token::Plus(_) => "+",
+            Token::Semicolon(_) => ";",
+            Token::Slash(_) => "/",
+            Token::Star(_) => "*",
+            Token::Pow(_) => "^",
+            Token::Percent(_) => "%",
+            Token::Bang(_) => "!",
+            Token::BangEqual(_) => "!=",
+            Token::Equal(_) => "=",
+            Token::EqualEqual(_) => "==",
+            Token::Greater(_) => ">",
+            Token::GreaterEqual(_) => ">=",
+            Token::Less(_) => "<",
+            Token::LessEqual(_) => "<=",
+            Token::And(_) => "and",
+            Token::Class(_) => "class",
+            Token::Else(_) => "else",
+            Token::False(_) => "false",
+            Token::For(_) => "for",
+            Token::Fun(_) => "fun",
+            Token::If(_) => "if",
+            Token::Null(_) => "null",
+            Token::Or(_) => "or",
+            Token::Return(_) => "return",
+            Token::True(_) => "true",
+            Token::Var(_) => "var",
+            Token::While(_) => "while",
+            Token::In(_) => "in",
+            Token::Print(_) => "print",
+            Token::This(_) => "this",
+            Token::Super(_) => "super",
+            Token::To(_) => "to",
+            Token::Yield(_) => "yield",
+            Token::Defer(_) => "defer",
+            Token::Import(_) => "import",
+            Token::Break(_) => "break",
+        };
+        write!(f, "\"{}\"", lexeme)
+    }
 }
@@ -1,14 +1,19 @@
-use crate::compiler::object::FunctionType;
-use crate::compiler::parser::Parser as AstParser;
+use crate::compiler::ast::{Program, StatementNode};
+use crate::compiler::diagnostic::Diagnostic;
+use crate::compiler::object::{FunctionObject, FunctionType};
+use crate::compiler::parser::{ParseError, Parser as AstParser};
+use crate::compiler::scanner::Scanner;
 use crate::compiler::Compiler;
 use crate::vm::frame::CallFrame;
 use clap::Parser;
 use compiler::object::ClosureObject;
 use compiler::ClassCompiler;
-use std::fs::File;
-use std::io::Read;
+use std::fs::{self, File};
+use std::io::{self, BufRead, Read, Write};
 use std::process::ExitCode;
 use std::{cell::RefCell, rc::Rc};
+use vm::builder::VMBuilder;
+use vm::value::Value;
 use vm::VM;
 
 mod compiler;
@@ -19,18 +24,59 @@ mod vm;
 struct Args {
     #[arg(short, long)]
     input: Option<String>,
+    #[arg(long)]
+    instruction_limit: Option<usize>,
+    #[arg(long)]
+    frame_max: Option<usize>,
+    #[arg(long)]
+    stack_max: Option<usize>,
+    #[arg(long)]
+    profile: bool,
+    #[arg(long)]
+    disassemble: bool,
+    #[arg(long)]
+    dump_tokens: bool,
+    #[arg(long)]
+    warn_unused: bool,
+    #[arg(long)]
+    no_prelude: bool,
 }
 
 fn main() -> ExitCode {
     let args = Args::parse();
     if let Some(input_path) = args.input {
-        let mut file = File::open(input_path).expect("file not found");
+        let mut file = File::open(&input_path).expect("file not found");
         let mut data: String = String::new();
         file.read_to_string(&mut data)
             .expect("something went wrong reading the file");
 
+        if args.dump_tokens {
+            let tokens = match Scanner::new(&data).tokenize() {
+                Ok(tokens) => tokens,
+                Err(e) => {
+                    println!("{}", e.diagnostic().render(&data));
+                    return ExitCode::from(8);
+                }
+            };
+            for token in tokens {
+                let position = token.position();
+                println!(
+                    "{:>4}:{:<4} len={:<3} {:?}",
+                    position.line, position.column, position.length, token
+                );
+            }
+            return ExitCode::from(0);
+        }
+
         let mut parser = AstParser::new(&data);
-        let program = parser.parse().unwrap();
+        // エラーはソースの該当行と一緒に表示する
+        let program = match parser.parse() {
+            Ok(program) => program,
+            Err(e) => {
+                println!("{}", e.diagnostic().render(&data));
+                return ExitCode::from(8);
+            }
+        };
 
         let compiler = Rc::new(RefCell::new(Compiler::new(
             "__main__",
@@ -38,9 +84,34 @@ fn main() -> ExitCode {
             0,
             None,
         )));
+        compiler.borrow_mut().warn_unused = args.warn_unused;
+        if let Ok(path) = fs::canonicalize(&input_path) {
+            compiler.borrow_mut().importing.push(path);
+        }
         let class_compiler = Rc::new(RefCell::new(ClassCompiler::new()));
+        let mut result = Ok(());
         for stmt in program.stmts {
-            Compiler::compile_stmt(compiler.clone(), class_compiler.clone(), stmt);
+            result = Compiler::compile_stmt(compiler.clone(), class_compiler.clone(), stmt);
+            if result.is_err() {
+                break;
+            }
+        }
+        for warning in compiler.borrow().warnings.iter() {
+            eprintln!("{}", warning.render(&data));
+        }
+        if let Err(msg) = result {
+            let line = compiler.borrow().line;
+            println!(
+                "{}",
+                Diagnostic::new(msg).at_line(line as i32).render(&data)
+            );
+            return ExitCode::from(8);
+        }
+        if args.disassemble {
+            match Compiler::compile_to_disassembly(&data) {
+                Ok(disassembly) => print!("{}", disassembly),
+                Err(msg) => eprintln!("{}", msg),
+            }
         }
         let frame = CallFrame::new(
             Rc::new(ClosureObject::new(Rc::new(
@@ -49,24 +120,129 @@ fn main() -> ExitCode {
             0,
             0,
         );
-        let mut vm = VM::new(frame);
+        let mut builder = VMBuilder::new();
+        if let Some(limit) = args.instruction_limit {
+            builder = builder.instruction_limit(limit);
+        }
+        if let Some(frame_max) = args.frame_max {
+            builder = builder.frame_max(frame_max);
+        }
+        if let Some(stack_max) = args.stack_max {
+            builder = builder.stack_max(stack_max);
+        }
+        let mut vm = builder.build(frame);
+        if !args.no_prelude {
+            if let Err(msg) = vm.load_prelude() {
+                println!("{}", msg);
+                return ExitCode::from(101);
+            }
+        }
+        // プレリュードの命令は数えない
+        vm.set_profile(args.profile);
         match vm.interpret() {
             vm::InterpretResult::Ok => {
-                return ExitCode::from(0);
+                return exit_code(vm.exit_value());
             }
             vm::InterpretResult::CompileError => {
                 return ExitCode::from(8);
             }
-            vm::InterpretResult::RuntimeError(msg) => {
-                println!("{}", msg);
+            vm::InterpretResult::RuntimeError(diagnostic) => {
+                println!("{}", diagnostic.render(&data));
                 return ExitCode::from(101);
             }
             vm::InterpretResult::End => {
                 return ExitCode::from(0);
             }
+            vm::InterpretResult::Continue => {
+                return ExitCode::from(0);
+            }
         }
     } else {
-        println!("repl");
-        ExitCode::from(0)
+        repl(!args.no_prelude)
+    }
+}
+
+// トップレベルの return でプログラムを終える。0 から 255 の整数を返した場合はそれを終了ステータスにし、
+// それ以外の値や値なしの return は 0 で終わる
+fn exit_code(value: &Value) -> ExitCode {
+    match value {
+        Value::Integer(i) if (0..=255).contains(i) => ExitCode::from(*i as u8),
+        _ => ExitCode::from(0),
+    }
+}
+
+fn repl(prelude: bool) -> ExitCode {
+    let frame = CallFrame::new(
+        Rc::new(ClosureObject::new(Rc::new(FunctionObject::new(
+            "__main__", 0,
+        )))),
+        0,
+        0,
+    );
+    let mut vm = VM::new(frame);
+    if prelude {
+        if let Err(msg) = vm.load_prelude() {
+            println!("{}", msg);
+            return ExitCode::from(101);
+        }
+    }
+
+    let stdin = io::stdin();
+    // 入力が文の途中で終わっている間は、続きの行をためておく
+    let mut input = String::new();
+    loop {
+        print!("{}", if input.is_empty() { "> " } else { "... " });
+        io::stdout().flush().unwrap();
+        let mut line = String::new();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) => return ExitCode::from(0),
+            Ok(_) => {}
+            Err(e) => {
+                println!("{}", e);
+                return ExitCode::from(74);
+            }
+        }
+        if input.is_empty() && line.trim().is_empty() {
+            continue;
+        }
+        input.push_str(&line);
+
+        let program = match parse_repl_input(&input) {
+            Ok(program) => program,
+            Err(ParseError::UnexpectedEof(_)) => continue,
+            Err(e) => {
+                println!("{}", e.diagnostic().render(&input));
+                input.clear();
+                continue;
+            }
+        };
+        let result = Compiler::compile_program("__repl__", program)
+            .and_then(|function| vm.run_script(function));
+        if let Err(diagnostic) = result {
+            println!("{}", diagnostic.render(&input));
+        }
+        input.clear();
+    }
+}
+
+// セミコロンのない式で終わる入力は、その式の値を表示する
+fn parse_repl_input(input: &str) -> Result<Program, ParseError> {
+    let error = match AstParser::new(input).parse() {
+        Ok(program) => return Ok(program),
+        Err(e) => e,
+    };
+    let mut program = match AstParser::new(&format!("{};", input)).parse() {
+        Ok(program) => program,
+        Err(_) => return Err(error),
+    };
+    match program.stmts.pop() {
+        Some(StatementNode::ExpStmt { line, expression }) => {
+            program.stmts.push(StatementNode::Print {
+                line,
+                expressions: vec![expression],
+            });
+            Ok(program)
+        }
+        _ => Err(error),
     }
 }
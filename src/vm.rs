@@ -1,49 +1,98 @@
 use self::{
+    builder::VMBuilder,
+    cache::InlineCache,
     chunk::{
-        OP_ADD, OP_ARRAY, OP_CALL, OP_CLASS, OP_CLOSE_UPVALUE, OP_CLOSURE, OP_CONSTANT,
-        OP_CONSTANT0, OP_COUNTUP, OP_DEFINE_GLOBAL, OP_DIVIDE, OP_EQUAL, OP_FALSE, OP_GET_GLOBAL,
+        opcode_name, OP_ADD, OP_ARRAY, OP_ARRAY_PUSH, OP_CALL, OP_CALL_NAMED, OP_CLASS,
+        OP_CLOSE_UPVALUE, OP_CLOSURE, OP_CONSTANT, OP_CONSTANT0, OP_CONSTANT_SMALL, OP_CONTAINS,
+        OP_COUNTUP, OP_DEFINE_GLOBAL, OP_DIVIDE, OP_EQUAL, OP_FALSE, OP_GET_GLOBAL, OP_GET_ITER,
         OP_GET_LOCAL, OP_GET_PROP, OP_GET_SUPER, OP_GET_UPVALUE, OP_GREATER, OP_INDEX_CALL,
         OP_INDEX_SET, OP_INHERIT, OP_INVOKE, OP_JUMP, OP_JUMP_IF_FALSE, OP_JUMP_IF_RANGE_END,
         OP_LESS, OP_LOOP, OP_METHOD, OP_MULTIPLY, OP_NEGATIVE, OP_NOT, OP_NULL, OP_POP, OP_POW,
         OP_PRINT, OP_RANGE, OP_REM, OP_RETURN, OP_SET_GLOBAL, OP_SET_LOCAL, OP_SET_PROP,
-        OP_SET_UPVALUE, OP_SUBTRACT, OP_SUPER_INVOKE, OP_TRUE,
+        OP_SET_UPVALUE, OP_SUBTRACT, OP_SUPER_INVOKE, OP_TRUE, OP_YIELD,
     },
     frame::CallFrame,
+    key::Key,
+    map::OrderedMap,
+    output::OutputBuffer,
     table::Table,
-    value::{StackArray, Value},
+    value::{slot, slot_mut, CalcError, StackArray, Value},
 };
-use crate::compiler::object::{
-    BoundMethodObject, ClassObject, ClosureObject, FunctionObject, InstanceObject, UpvalueObject,
+use crate::compiler::{
+    diagnostic::Diagnostic,
+    object::{
+        BoundMethodObject, ClassObject, ClosureObject, FunctionObject, GeneratorObject,
+        InstanceObject, UpvalueObject,
+    },
+    Compiler,
 };
 use chrono::Local as LocalTime;
-use std::{cell::RefCell, rc::Rc};
+use std::{
+    cell::RefCell,
+    cmp::Ordering,
+    collections::{BTreeSet, HashMap},
+    io::{self, Write},
+    rc::Rc,
+};
 
+pub mod builder;
+pub mod cache;
 pub mod chunk;
 pub mod frame;
+pub mod key;
+pub mod map;
+pub mod output;
 pub mod table;
 pub mod value;
 
 pub enum InterpretResult {
     Ok,
     CompileError,
-    RuntimeError(String),
+    RuntimeError(Diagnostic),
     End,
+    // step で1命令実行し、まだ続きがある状態
+    Continue,
+}
+
+impl InterpretResult {
+    // 行は run で実行中の命令から付ける
+    fn runtime_error(message: impl Into<String>) -> Self {
+        InterpretResult::RuntimeError(Diagnostic::new(message))
+    }
 }
 
+// 呼び出しの深さと値スタックの大きさのデフォルト。VMBuilder で変えられる
 const FRAME_MAX: usize = 256;
+const STACK_MAX: usize = 256;
+// 言語自身で書いた標準関数。ユーザーのプログラムより先に読み込む
+const PRELUDE: &str = include_str!("vm/prelude.rox");
 
 pub struct VM {
     stack: StackArray,
     globals: Table,
-    frames: [CallFrame; FRAME_MAX],
+    frames: Box<[CallFrame]>,
     frame_count: usize,
     open_upvalue: Option<Rc<RefCell<UpvalueObject>>>,
+    instruction_limit: Option<usize>,
+    profile: bool,
+    opcode_counts: [u64; 256],
+    output: Box<dyn Write>,
+    // 実行中のジェネレータ。OP_YIELD はこの末尾のジェネレータに状態を保存する
+    generators: Vec<Rc<RefCell<GeneratorObject>>>,
+    // OP_INVOKE と OP_GET_PROP で見つけたメソッド
+    inline_cache: InlineCache,
+    // __main__ の return で返した値。return しないで終わった場合は null
+    exit_value: Value,
 }
 
 impl VM {
     pub fn new(frame: CallFrame) -> Self {
+        VMBuilder::new().build(frame)
+    }
+
+    fn with_limits(frame: CallFrame, frame_max: usize, stack_max: usize) -> Self {
         let mut vm = VM {
-            stack: StackArray::new(),
+            stack: StackArray::new(stack_max),
             globals: Table::new(),
             frames: vec![
                 CallFrame::new(
@@ -51,23 +100,162 @@ impl VM {
                     0,
                     0
                 );
-                FRAME_MAX
+                frame_max
             ]
-            .try_into()
-            .unwrap(),
+            .into_boxed_slice(),
             frame_count: 0,
             open_upvalue: None,
+            instruction_limit: None,
+            profile: false,
+            opcode_counts: [0; 256],
+            output: Box::new(io::stdout()),
+            generators: Vec::new(),
+            inline_cache: InlineCache::new(),
+            exit_value: Value::Null,
         };
-        vm.stack.push(Value::Closure(frame.closure.clone()));
-        Self::frame_push(&mut vm, frame);
+        vm.register_native();
+        vm.load(frame);
         vm
     }
 
-    pub fn interpret(&mut self) -> InterpretResult {
+    // 実行するプログラムのフレームを積む。reset の後に別のプログラムを実行するときにも使う
+    pub fn load(&mut self, frame: CallFrame) {
+        self.stack.push(Value::Closure(frame.closure.clone()));
+        self.frame_push(frame);
+    }
+
+    // スタック、フレーム、上位値、ジェネレータを空にして、同じ VM で別のプログラムを実行できるようにする。
+    // グローバル変数はそのまま残る。実行するには load でプログラムを積み直す
+    pub fn reset(&mut self) {
+        self.stack.set_index(0);
+        self.frame_count = 0;
+        self.open_upvalue = None;
+        self.generators.clear();
+        self.exit_value = Value::Null;
+        self.opcode_counts = [0; 256];
+        self.inline_cache.clear();
+    }
+
+    // グローバル変数を全て消す。ネイティブ関数と定数は登録し直すが、プレリュードは読み込み直さない
+    pub fn clear_globals(&mut self) {
+        self.globals = Table::new();
         self.register_native();
+    }
+
+    pub fn interpret(&mut self) -> InterpretResult {
         return self.run();
     }
 
+    // トップレベルの return で返した値。main ではこれを終了ステータスにする
+    pub fn exit_value(&self) -> &Value {
+        &self.exit_value
+    }
+
+    // 実行できる命令数の上限。信頼できないスクリプトの無限ループ対策。
+    pub fn set_instruction_limit(&mut self, limit: usize) {
+        self.instruction_limit = Some(limit);
+    }
+
+    // print の出力先。デフォルトは標準出力
+    pub fn set_output(&mut self, output: Box<dyn Write>) {
+        self.output = output;
+    }
+
+    // print の出力をメモリ上のバッファに切り替え、そのバッファを返す
+    pub fn capture_output(&mut self) -> OutputBuffer {
+        let buffer = OutputBuffer::new();
+        self.output = Box::new(buffer.clone());
+        buffer
+    }
+
+    // ホスト側からグローバル変数を設定する。名前は識別子として正しくなくてもよいが、
+    // その場合はスクリプトから名前で参照できない (ホスト側の get_global でだけ読める)
+    pub fn set_global(&mut self, name: &str, value: Value) {
+        self.globals.insert(Rc::new(name.to_string()), value);
+    }
+
+    pub fn get_global(&self, name: &str) -> Option<Value> {
+        self.globals.find(&name.to_string()).cloned()
+    }
+
+    // 登録されている全てのグローバル変数 (ネイティブ関数を含む)。順序は不定
+    pub fn globals_iter(&self) -> impl Iterator<Item = (&str, &Value)> {
+        self.globals
+            .iter()
+            .map(|(name, value)| (name.as_str(), value))
+    }
+
+    // 標準関数をグローバル変数に登録する。ユーザーのプログラムの実行前に呼ぶ
+    // プレリュードの実行は命令数の制限に数えない
+    pub fn load_prelude(&mut self) -> Result<(), String> {
+        let function = Compiler::compile_script("__prelude__", PRELUDE)?;
+        let limit = self.instruction_limit.take();
+        let result = self.run_script(function).map_err(|e| e.message);
+        self.instruction_limit = limit;
+        result
+    }
+
+    // スクリプトを呼び出し、戻るまで実行する。トップレベルの定義はグローバル変数になる。
+    // エラーの場合はスタックとフレームを呼び出し前に戻すので、同じ VM で続けて実行できる。
+    // 実行時エラーにはエラーになった命令の行を付ける
+    pub fn run_script(&mut self, function: FunctionObject) -> Result<(), Diagnostic> {
+        let name = function.name.clone();
+        let closure = Rc::new(ClosureObject::new(Rc::new(function)));
+        let frame_count = self.frame_count;
+        let generator_count = self.generators.len();
+        let index = self.stack.len();
+        self.stack.push(Value::Closure(Rc::clone(&closure)));
+        if let Err(e) = self.call_closure(closure, index) {
+            self.stack.set_index(index);
+            return Err(Diagnostic::new(format!("{}: {}", name, e)));
+        }
+        while self.frame_count > frame_count {
+            let error = match self.step() {
+                InterpretResult::Continue => continue,
+                InterpretResult::RuntimeError(e) => Diagnostic {
+                    message: format!("{}: {}", name, e.message),
+                    ..e
+                }
+                .or_at_line(self.current_line()),
+                _ => Diagnostic::new(format!("script \"{}\" ended unexpectedly.", name)),
+            };
+            // 中断したフレームの分だけ片付け、呼び出し元の上位値と実行中のジェネレータは残す
+            self.close_upvalues(index);
+            self.generators.truncate(generator_count);
+            self.frame_count = frame_count;
+            self.stack.set_index(index);
+            return Err(error);
+        }
+        self.stack.pop();
+        Ok(())
+    }
+
+    // 命令ごとの実行回数を数え、run の終了時に出力する
+    pub fn set_profile(&mut self, profile: bool) {
+        self.profile = profile;
+    }
+
+    fn print_profile(&self) {
+        let mut counts = self
+            .opcode_counts
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .collect::<Vec<_>>();
+        counts.sort_by(|a, b| b.1.cmp(a.1));
+
+        println!("== profile ==");
+        for (opcode, count) in counts {
+            let name = opcode_name(opcode as u8).unwrap_or("UNKNOWN");
+            println!("{:<24}{:>12}", name, count);
+        }
+        println!(
+            "{:<24}{:>12}",
+            "total",
+            self.opcode_counts.iter().sum::<u64>()
+        );
+    }
+
     fn register_native(&mut self) {
         self.globals
             .insert(Rc::new("len".to_string()), Value::Native { function: len });
@@ -97,679 +285,1462 @@ impl VM {
         );
         self.globals
             .insert(Rc::new("get".to_string()), Value::Native { function: get });
+        self.globals
+            .insert(Rc::new("ord".to_string()), Value::Native { function: ord });
+        self.globals
+            .insert(Rc::new("chr".to_string()), Value::Native { function: chr });
+        self.globals.insert(
+            Rc::new("trim".to_string()),
+            Value::Native { function: trim },
+        );
+        self.globals.insert(
+            Rc::new("starts_with".to_string()),
+            Value::Native {
+                function: starts_with,
+            },
+        );
+        self.globals.insert(
+            Rc::new("ends_with".to_string()),
+            Value::Native {
+                function: ends_with,
+            },
+        );
+        self.globals.insert(
+            Rc::new("replace".to_string()),
+            Value::Native { function: replace },
+        );
+        self.globals.insert(
+            Rc::new("repeat".to_string()),
+            Value::Native { function: repeat },
+        );
+        self.globals
+            .insert(Rc::new("set".to_string()), Value::Native { function: set });
+        self.globals.insert(
+            Rc::new("set_add".to_string()),
+            Value::Native { function: set_add },
+        );
+        self.globals.insert(
+            Rc::new("set_has".to_string()),
+            Value::Native { function: set_has },
+        );
+        self.globals.insert(
+            Rc::new("set_remove".to_string()),
+            Value::Native {
+                function: set_remove,
+            },
+        );
+        self.globals.insert(
+            Rc::new("contains".to_string()),
+            Value::Native { function: contains },
+        );
+        self.globals.insert(
+            Rc::new("map_of".to_string()),
+            Value::Native { function: map_of },
+        );
+        self.globals.insert(
+            Rc::new("map_get".to_string()),
+            Value::Native { function: map_get },
+        );
+        self.globals.insert(
+            Rc::new("map_set".to_string()),
+            Value::Native { function: map_set },
+        );
+        self.globals.insert(
+            Rc::new("map_has".to_string()),
+            Value::Native { function: map_has },
+        );
+        self.globals.insert(
+            Rc::new("map_remove".to_string()),
+            Value::Native {
+                function: map_remove,
+            },
+        );
+        self.globals.insert(
+            Rc::new("keys".to_string()),
+            Value::Native { function: keys },
+        );
+        self.globals.insert(
+            Rc::new("values".to_string()),
+            Value::Native { function: values },
+        );
+        self.globals.insert(
+            Rc::new("copy".to_string()),
+            Value::Native { function: copy },
+        );
+        self.globals.insert(
+            Rc::new("deep_copy".to_string()),
+            Value::Native {
+                function: deep_copy,
+            },
+        );
+        self.globals.insert(
+            Rc::new("enumerate".to_string()),
+            Value::Native {
+                function: enumerate,
+            },
+        );
+        self.globals
+            .insert(Rc::new("zip".to_string()), Value::Native { function: zip });
+        self.globals.insert(
+            Rc::new("fill".to_string()),
+            Value::Native { function: fill },
+        );
+        self.globals.insert(
+            Rc::new("slice".to_string()),
+            Value::Native { function: slice },
+        );
+
+        self.globals
+            .insert(Rc::new("abs".to_string()), Value::Native { function: abs });
+        self.globals.insert(
+            Rc::new("sign".to_string()),
+            Value::Native { function: sign },
+        );
+
+        self.globals
+            .insert(Rc::new("sin".to_string()), Value::Native { function: sin });
+        self.globals
+            .insert(Rc::new("cos".to_string()), Value::Native { function: cos });
+        self.globals
+            .insert(Rc::new("tan".to_string()), Value::Native { function: tan });
+        self.globals.insert(
+            Rc::new("atan2".to_string()),
+            Value::Native { function: atan2 },
+        );
+
+        self.globals
+            .insert(Rc::new("exp".to_string()), Value::Native { function: exp });
+        self.globals
+            .insert(Rc::new("log".to_string()), Value::Native { function: log });
+        self.globals.insert(
+            Rc::new("log2".to_string()),
+            Value::Native { function: log2 },
+        );
+        self.globals.insert(
+            Rc::new("log10".to_string()),
+            Value::Native { function: log10 },
+        );
+
+        self.globals.insert(
+            Rc::new("to_hex".to_string()),
+            Value::Native { function: to_hex },
+        );
+        self.globals.insert(
+            Rc::new("to_binary".to_string()),
+            Value::Native {
+                function: to_binary,
+            },
+        );
+
+        self.globals.insert(
+            Rc::new("to_bytes".to_string()),
+            Value::Native { function: to_bytes },
+        );
+
+        self.globals.insert(
+            Rc::new("from_bytes".to_string()),
+            Value::Native {
+                function: from_bytes,
+            },
+        );
+
+        self.globals.insert(
+            Rc::new("base64_encode".to_string()),
+            Value::Native {
+                function: base64_encode,
+            },
+        );
+
+        self.globals.insert(
+            Rc::new("base64_decode".to_string()),
+            Value::Native {
+                function: base64_decode,
+            },
+        );
+
+        #[cfg(feature = "hash")]
+        {
+            self.globals.insert(
+                Rc::new("sha256".to_string()),
+                Value::Native { function: sha256 },
+            );
+
+            self.globals
+                .insert(Rc::new("md5".to_string()), Value::Native { function: md5 });
+        }
+
+        #[cfg(feature = "regex")]
+        {
+            self.globals.insert(
+                Rc::new("match".to_string()),
+                Value::Native {
+                    function: regex_match,
+                },
+            );
+
+            self.globals.insert(
+                Rc::new("regex_replace".to_string()),
+                Value::Native {
+                    function: regex_replace,
+                },
+            );
+        }
+
+        self.globals.insert(
+            Rc::new("pad_left".to_string()),
+            Value::Native { function: pad_left },
+        );
+
+        self.globals.insert(
+            Rc::new("pad_right".to_string()),
+            Value::Native {
+                function: pad_right,
+            },
+        );
+
+        self.globals.insert(
+            Rc::new("char_at".to_string()),
+            Value::Native { function: char_at },
+        );
+
+        self.globals.insert(
+            Rc::new("chars".to_string()),
+            Value::Native { function: chars },
+        );
+
+        self.globals.insert(
+            Rc::new("repr".to_string()),
+            Value::Native { function: repr },
+        );
+
+        self.globals
+            .insert(Rc::new("min".to_string()), Value::Native { function: min });
+
+        self.globals
+            .insert(Rc::new("max".to_string()), Value::Native { function: max });
+
+        self.globals.insert(
+            Rc::new("popcount".to_string()),
+            Value::Native { function: popcount },
+        );
+
+        self.globals.insert(
+            Rc::new("leading_zeros".to_string()),
+            Value::Native {
+                function: leading_zeros,
+            },
+        );
+
+        self.globals.insert(
+            Rc::new("is_power_of_two".to_string()),
+            Value::Native {
+                function: is_power_of_two,
+            },
+        );
+
+        self.globals.insert(
+            Rc::new("fixed".to_string()),
+            Value::Native { function: fixed },
+        );
+
+        self.globals.insert(
+            Rc::new("round_to".to_string()),
+            Value::Native { function: round_to },
+        );
+
+        self.globals.insert(
+            Rc::new("parse_int".to_string()),
+            Value::Native {
+                function: parse_int,
+            },
+        );
+
+        self.globals.insert(
+            Rc::new("bool".to_string()),
+            Value::Native { function: bool },
+        );
+
+        self.globals.insert(
+            Rc::new("include".to_string()),
+            Value::VmNative { function: include },
+        );
+        self.globals.insert(
+            Rc::new("assert_eq".to_string()),
+            Value::VmNative {
+                function: assert_eq,
+            },
+        );
+
+        // 数学定数はただのグローバル変数として登録する。
+        // スクリプトの実行前に登録するので、同じ名前をスクリプトで定義すればそちらが優先される
+        self.globals.insert(
+            Rc::new("PI".to_string()),
+            Value::Float(std::f64::consts::PI),
+        );
+        self.globals
+            .insert(Rc::new("E".to_string()), Value::Float(std::f64::consts::E));
     }
 
     fn run(&mut self) -> InterpretResult {
         loop {
-            let instruction = match self
-                .frame_last()
-                .closure
-                .function
-                .chunk
-                .get_instruction(self.get_current_ip())
-            {
-                Some(i) => *i,
-                None => {
-                    return InterpretResult::End;
+            // 命令数の制限やプロファイルでは1命令ずつ数えるので、step だけで実行する
+            if self.instruction_limit.is_none() && !self.profile {
+                self.run_simple_instructions();
+            }
+            match self.step() {
+                InterpretResult::Continue => {}
+                result => {
+                    if self.profile {
+                        self.print_profile();
+                    }
+                    if let InterpretResult::RuntimeError(diagnostic) = result {
+                        return InterpretResult::RuntimeError(
+                            diagnostic.or_at_line(self.current_line()),
+                        );
+                    }
+                    return result;
                 }
-            };
-            // self.frame_last().closure.function.chunk.disassemble_instruction(self.get_current_ip());
-            *self.get_current_ip_mut() += 1;
+            }
+        }
+    }
 
-            match instruction {
-                OP_RETURN => {
-                    let result = self.stack.pop();
-                    self.close_upvalues();
+    // 実行中の命令の行。エラーになった命令の ip は1つ以上進んでいるので、1つ前の位置を見る
+    fn current_line(&self) -> Option<i32> {
+        if self.frame_count == 0 {
+            return None;
+        }
+        let frame = self.frame_last();
+        frame
+            .closure
+            .function
+            .chunk
+            .get_source_line(frame.ip.saturating_sub(1))
+            .map(|line| line as i32)
+    }
 
-                    let frame = self.frame_pop();
-                    if frame.closure.function.name == "__main__" {
-                        self.stack.pop_index();
-                        return InterpretResult::Ok;
-                    }
-                    let index = frame.sp;
-                    self.stack.set_index(index);
-                    self.stack.push(result);
-                }
+    // 関数呼び出しやオブジェクトを扱わない単純な命令を、step を経由せずにまとめて実行する。
+    // ip をローカル変数に持ち、命令ごとにフレームを引き直さない。
+    // 整数同士でない演算や桁あふれなど、単純に扱えない場合はその命令の手前で止めて step に任せるので、
+    // 動作は step で1命令ずつ実行した場合と同じになる
+    fn run_simple_instructions(&mut self) {
+        let frame = slot_mut(
+            &mut self.frames,
+            self.frame_count - 1,
+            self.frame_count,
+            "frame",
+        );
+        let chunk = &frame.closure.function.chunk;
+        let sp = frame.sp;
+        let mut ip = frame.ip;
+        loop {
+            // スタックがいっぱいなら、step に任せて実行時エラーにする
+            if self.stack.len() >= self.stack.max() {
+                break;
+            }
+            let instruction = match chunk.get_instruction(ip) {
+                Some(instruction) => *instruction,
+                None => break,
+            };
+            match instruction {
                 OP_CONSTANT => {
-                    let value = Self::read_constant(&mut self.frame_last_mut());
-                    self.stack.push(value);
+                    let index = match chunk.get_instruction(ip + 1) {
+                        Some(index) => *index as usize,
+                        None => break,
+                    };
+                    self.stack.push(chunk.get_constant(index));
+                    ip += 2;
                 }
-                OP_NEGATIVE => {
-                    let a = self.stack.pop();
-                    match -a {
-                        Ok(value) => self.stack.push(value),
-                        Err(error) => {
-                            return InterpretResult::RuntimeError(format!(
-                                "Instruction is \"OP_NEGATIVE\". [Not Support Operation]{}",
-                                error
-                            ))
-                        }
-                    }
+                OP_CONSTANT0 => {
+                    self.stack.push(Value::Integer(0));
+                    ip += 1;
                 }
-                OP_ADD => {
-                    let b = self.stack.pop();
-                    let a = self.stack.pop();
-                    match a + b {
-                        Ok(value) => self.stack.push(value),
-                        Err(error) => {
-                            return InterpretResult::RuntimeError(format!(
-                                "Instruction is \"OP_ADD\". [Not Support Operation]{}",
-                                error
-                            ))
-                        }
-                    }
+                OP_CONSTANT_SMALL => {
+                    let value = match chunk.get_instruction(ip + 1) {
+                        Some(value) => *value as i64,
+                        None => break,
+                    };
+                    self.stack.push(Value::Integer(value));
+                    ip += 2;
                 }
-                OP_SUBTRACT => {
-                    let b = self.stack.pop();
-                    let a = self.stack.pop();
-                    match a - b {
-                        Ok(value) => self.stack.push(value),
-                        Err(error) => {
-                            return InterpretResult::RuntimeError(format!(
-                                "Instruction is \"OP_SUBTRACT\". [Not Support Operation]{}",
-                                error
-                            ))
-                        }
-                    }
+                OP_GET_LOCAL => {
+                    let index = match chunk.get_instruction(ip + 1) {
+                        Some(index) => *index as usize,
+                        None => break,
+                    };
+                    let value = self.stack.get(sp + index).clone();
+                    self.stack.push(value);
+                    ip += 2;
                 }
-                OP_MULTIPLY => {
-                    let b = self.stack.pop();
-                    let a = self.stack.pop();
-                    match a * b {
-                        Ok(value) => self.stack.push(value),
-                        Err(error) => {
-                            return InterpretResult::RuntimeError(format!(
-                                "Instruction is \"OP_MULTIPLY\". [Not Support Operation]{}",
-                                error
-                            ))
-                        }
-                    }
+                OP_SET_LOCAL => {
+                    let index = match chunk.get_instruction(ip + 1) {
+                        Some(index) => *index as usize,
+                        None => break,
+                    };
+                    let value = self.stack.last().clone();
+                    *self.stack.get_mut(sp + index) = value;
+                    ip += 2;
                 }
-                OP_DIVIDE => {
-                    let b = self.stack.pop();
-                    let a = self.stack.pop();
-                    match a / b {
-                        Ok(value) => self.stack.push(value),
-                        Err(error) => {
-                            return InterpretResult::RuntimeError(format!(
-                                "Instruction is \"OP_DIVIDE\". [Not Support Operation]{}",
-                                error
-                            ))
-                        }
-                    }
+                OP_POP => {
+                    self.stack.pop_index();
+                    ip += 1;
                 }
-                OP_REM => {
-                    let b = self.stack.pop();
-                    let a = self.stack.pop();
-                    match a % b {
-                        Ok(value) => self.stack.push(value),
-                        Err(error) => {
-                            return InterpretResult::RuntimeError(format!(
-                                "Instruction is \"OP_DIVIDE\". [Not Support Operation]{}",
-                                error
-                            ))
-                        }
+                OP_JUMP => {
+                    let offset = match chunk.read_u16(ip + 1) {
+                        Some(offset) => offset as usize,
+                        None => break,
+                    };
+                    ip += 3 + offset;
+                }
+                OP_JUMP_IF_FALSE => {
+                    let offset = match chunk.read_u16(ip + 1) {
+                        Some(offset) => offset as usize,
+                        None => break,
+                    };
+                    ip += 3;
+                    if self.stack.last().is_falsy() {
+                        ip += offset;
                     }
                 }
-                OP_POW => {
-                    let b = self.stack.pop();
-                    let a = self.stack.pop();
-                    let value = match a {
-                        Value::Float(a) => match b {
-                            Value::Float(b) => Value::Float(a.powf(b)),
-                            Value::Integer(b) => Value::Float(a.powi(b as i32)),
-                            _ => {
-                                return InterpretResult::RuntimeError(format!(
-                                    "Instruction is \"OP_POW\". [Not Support Operation]{} ^ {}",
-                                    a, b
-                                ))
-                            }
+                OP_LOOP => {
+                    let offset = match chunk.read_u16(ip + 1) {
+                        Some(offset) => offset as usize,
+                        None => break,
+                    };
+                    ip = ip + 3 - offset;
+                }
+                OP_ADD | OP_SUBTRACT | OP_LESS | OP_GREATER => {
+                    let len = self.stack.len();
+                    let (a, b) = match (self.stack.get(len - 2), self.stack.get(len - 1)) {
+                        (Value::Integer(a), Value::Integer(b)) => (*a, *b),
+                        _ => break,
+                    };
+                    let value = match instruction {
+                        OP_ADD => match a.checked_add(b) {
+                            Some(value) => Value::Integer(value),
+                            None => break,
                         },
-                        Value::Integer(a) => match b {
-                            Value::Float(b) => Value::Float((a as f64).powf(b)),
-                            Value::Integer(b) => Value::Float((a as f64).powi(b as i32)),
-                            _ => {
-                                return InterpretResult::RuntimeError(format!(
-                                    "Instruction is \"OP_POW\". [Not Support Operation]{} ^ {}",
-                                    a, b
-                                ))
-                            }
+                        OP_SUBTRACT => match a.checked_sub(b) {
+                            Some(value) => Value::Integer(value),
+                            None => break,
                         },
-                        _ => {
-                            return InterpretResult::RuntimeError(format!(
-                                "Instruction is \"OP_POW\". [Not Support Operation]{} ^ {}",
-                                a, b
-                            ))
-                        }
+                        OP_LESS => Value::Boolean(a < b),
+                        _ => Value::Boolean(a > b),
                     };
-                    self.stack.push(value);
+                    self.stack.pop_index();
+                    *self.stack.get_mut(len - 2) = value;
+                    ip += 1;
                 }
-                OP_TRUE => {
-                    self.stack.push(Value::Boolean(true));
+                _ => break,
+            }
+        }
+        frame.ip = ip;
+    }
+
+    // 1命令だけ実行して制御を戻す。続きがある場合は InterpretResult::Continue を返す。
+    // 命令数の制限はここで数えるので、メソッドやジェネレータを最後まで実行する内側のループにもかかる
+    pub fn step(&mut self) -> InterpretResult {
+        if let Some(limit) = self.instruction_limit.as_mut() {
+            if *limit == 0 {
+                return InterpretResult::runtime_error("instruction limit exceeded".to_string());
+            }
+            *limit -= 1;
+        }
+        if let Err(e) = self.check_stack_limit(1) {
+            return InterpretResult::runtime_error(e);
+        }
+        // 命令の読み出しと ip の更新は、フレームを一度だけ取り出して行う
+        let frame = self.frame_last_mut();
+        let instruction = match frame.closure.function.chunk.get_instruction(frame.ip) {
+            Some(i) => *i,
+            None => {
+                return InterpretResult::End;
+            }
+        };
+        // frame.closure.function.chunk.disassemble_instruction(frame.ip);
+        frame.ip += 1;
+        if self.profile {
+            self.opcode_counts[instruction as usize] += 1;
+        }
+
+        match instruction {
+            OP_RETURN => {
+                let result = self.stack.pop();
+                self.close_upvalues(self.frame_last().sp);
+
+                let frame = self.frame_pop();
+                if frame.closure.function.name == "__main__" {
+                    self.stack.pop_index();
+                    self.exit_value = result;
+                    return InterpretResult::Ok;
                 }
-                OP_FALSE => {
-                    self.stack.push(Value::Boolean(false));
+                let index = frame.sp;
+                self.stack.set_index(index);
+                self.stack.push(result);
+            }
+            OP_CONSTANT => {
+                let value = Self::read_constant(&mut self.frame_last_mut());
+                self.stack.push(value);
+            }
+            OP_NEGATIVE => {
+                match self.invoke_operator("__neg__", 0) {
+                    Some(Ok(_)) => return InterpretResult::Continue,
+                    Some(Err(e)) => return InterpretResult::runtime_error(e),
+                    None => {}
                 }
-                OP_NULL => {
-                    self.stack.push(Value::Null);
-                }
-                OP_NOT => {
-                    let a = self.stack.pop();
-                    match !a {
-                        Ok(value) => self.stack.push(value),
-                        Err(error) => {
-                            return InterpretResult::RuntimeError(format!(
-                                "Instruction is \"OP_NOT\". [Not Support Operation]{}",
-                                error
-                            ))
-                        }
+                let a = self.stack.pop();
+                match -a {
+                    Ok(value) => self.stack.push(value),
+                    Err(error) => {
+                        return InterpretResult::runtime_error(format!(
+                            "Instruction is \"OP_NEGATIVE\". [Not Support Operation]{}",
+                            error
+                        ))
                     }
                 }
-                OP_GREATER => {
-                    let b = self.stack.pop();
-                    let a = self.stack.pop();
-                    if a > b {
-                        self.stack.push(Value::Boolean(true));
-                    } else {
-                        self.stack.push(Value::Boolean(false));
+            }
+            OP_ADD => {
+                match self.invoke_operator("__add__", 1) {
+                    Some(Ok(_)) => return InterpretResult::Continue,
+                    Some(Err(e)) => return InterpretResult::runtime_error(e),
+                    None => {}
+                }
+                let b = self.stack.pop();
+                let a = self.stack.pop();
+                match a + b {
+                    Ok(value) => self.stack.push(value),
+                    Err(error) => {
+                        return InterpretResult::runtime_error(format!(
+                            "Instruction is \"OP_ADD\". [Not Support Operation]{}",
+                            error
+                        ))
                     }
                 }
-                OP_LESS => {
-                    let b = self.stack.pop();
-                    let a = self.stack.pop();
-                    if a < b {
-                        self.stack.push(Value::Boolean(true));
-                    } else {
-                        self.stack.push(Value::Boolean(false));
+            }
+            OP_SUBTRACT => {
+                match self.invoke_operator("__sub__", 1) {
+                    Some(Ok(_)) => return InterpretResult::Continue,
+                    Some(Err(e)) => return InterpretResult::runtime_error(e),
+                    None => {}
+                }
+                let b = self.stack.pop();
+                let a = self.stack.pop();
+                match a - b {
+                    Ok(value) => self.stack.push(value),
+                    Err(error) => {
+                        return InterpretResult::runtime_error(format!(
+                            "Instruction is \"OP_SUBTRACT\". [Not Support Operation]{}",
+                            error
+                        ))
                     }
                 }
-                OP_EQUAL => {
-                    let b = self.stack.pop();
-                    let a = self.stack.pop();
-                    if a == b {
-                        self.stack.push(Value::Boolean(true));
-                    } else {
-                        self.stack.push(Value::Boolean(false));
+            }
+            OP_MULTIPLY => {
+                match self.invoke_operator("__mul__", 1) {
+                    Some(Ok(_)) => return InterpretResult::Continue,
+                    Some(Err(e)) => return InterpretResult::runtime_error(e),
+                    None => {}
+                }
+                let b = self.stack.pop();
+                let a = self.stack.pop();
+                match a * b {
+                    Ok(value) => self.stack.push(value),
+                    Err(error) => {
+                        return InterpretResult::runtime_error(format!(
+                            "Instruction is \"OP_MULTIPLY\". [Not Support Operation]{}",
+                            error
+                        ))
                     }
                 }
-                OP_PRINT => {
-                    let a = self.stack.pop();
-                    println!("{}", a);
+            }
+            OP_DIVIDE => {
+                match self.invoke_operator("__div__", 1) {
+                    Some(Ok(_)) => return InterpretResult::Continue,
+                    Some(Err(e)) => return InterpretResult::runtime_error(e),
+                    None => {}
                 }
-                OP_POP => {
-                    self.stack.pop_index();
+                let b = self.stack.pop();
+                let a = self.stack.pop();
+                match a / b {
+                    Ok(value) => self.stack.push(value),
+                    Err(error) => {
+                        return InterpretResult::runtime_error(format!(
+                            "Instruction is \"OP_DIVIDE\". [Not Support Operation]{}",
+                            error
+                        ))
+                    }
                 }
-                OP_DEFINE_GLOBAL => {
-                    let key = Self::read_string(&mut self.frame_last_mut());
-                    let value = self.stack.pop();
-                    self.globals.insert(key, value);
+            }
+            OP_REM => {
+                match self.invoke_operator("__mod__", 1) {
+                    Some(Ok(_)) => return InterpretResult::Continue,
+                    Some(Err(e)) => return InterpretResult::runtime_error(e),
+                    None => {}
                 }
-                OP_GET_GLOBAL => {
-                    let key = Self::read_string(&mut self.frame_last_mut());
-                    match self.globals.find(&key) {
-                        Some(value) => self.stack.push(value.clone()),
-                        None => {
-                            return InterpretResult::RuntimeError(format!(
-                                "Instruction is \"OP_GET_GLOBAL\". not found identifer name.({})",
-                                key
+                let b = self.stack.pop();
+                let a = self.stack.pop();
+                match a % b {
+                    Ok(value) => self.stack.push(value),
+                    Err(error) => {
+                        return InterpretResult::runtime_error(format!(
+                            "Instruction is \"OP_REM\". [Not Support Operation]{}",
+                            error
+                        ))
+                    }
+                }
+            }
+            OP_POW => {
+                match self.invoke_operator("__pow__", 1) {
+                    Some(Ok(_)) => return InterpretResult::Continue,
+                    Some(Err(e)) => return InterpretResult::runtime_error(e),
+                    None => {}
+                }
+                let b = self.stack.pop();
+                let a = self.stack.pop();
+                let value = match a {
+                    Value::Float(a) => match b {
+                        Value::Float(b) => Value::Float(a.powf(b)),
+                        Value::Integer(b) => Value::Float(a.powi(b as i32)),
+                        _ => {
+                            return InterpretResult::runtime_error(format!(
+                                "Instruction is \"OP_POW\". [Not Support Operation]{}",
+                                CalcError::unsupported("exponentiate", "^", &Value::Float(a), &b)
                             ))
                         }
-                    };
+                    },
+                    Value::Integer(a) => match b {
+                        Value::Float(b) => Value::Float((a as f64).powf(b)),
+                        Value::Integer(b) => Value::Float((a as f64).powi(b as i32)),
+                        _ => {
+                            return InterpretResult::runtime_error(format!(
+                                "Instruction is \"OP_POW\". [Not Support Operation]{}",
+                                CalcError::unsupported("exponentiate", "^", &Value::Integer(a), &b)
+                            ))
+                        }
+                    },
+                    _ => {
+                        return InterpretResult::runtime_error(format!(
+                            "Instruction is \"OP_POW\". [Not Support Operation]{}",
+                            CalcError::unsupported("exponentiate", "^", &a, &b)
+                        ))
+                    }
+                };
+                self.stack.push(value);
+            }
+            OP_TRUE => {
+                self.stack.push(Value::Boolean(true));
+            }
+            OP_FALSE => {
+                self.stack.push(Value::Boolean(false));
+            }
+            OP_NULL => {
+                self.stack.push(Value::Null);
+            }
+            OP_NOT => {
+                let a = self.stack.pop();
+                match !a {
+                    Ok(value) => self.stack.push(value),
+                    Err(error) => {
+                        return InterpretResult::runtime_error(format!(
+                            "Instruction is \"OP_NOT\". [Not Support Operation]{}",
+                            error
+                        ))
+                    }
                 }
-                OP_SET_GLOBAL => {
-                    let key = Self::read_string(&mut self.frame_last_mut());
-                    let value = self.stack.last().clone();
-                    self.globals.insert(key, value);
+            }
+            OP_GREATER => {
+                match self.invoke_operator("__gt__", 1) {
+                    Some(Ok(_)) => return InterpretResult::Continue,
+                    Some(Err(e)) => return InterpretResult::runtime_error(e),
+                    None => {}
                 }
-                OP_GET_LOCAL => {
-                    let index = match Self::read_local_index(&mut self.frame_last_mut()) {
-                        Some(index) => index,
-                        None => {
-                            return InterpretResult::RuntimeError(
-                                "Instruction is \"OP_GET_LOCAL\". but no value.".to_string(),
-                            )
-                        }
-                    };
-                    let sp = self.frame_last().sp;
-                    let value = self.stack.get(sp + index).clone();
-                    self.stack.push(value);
+                let b = self.stack.pop();
+                let a = self.stack.pop();
+                if a > b {
+                    self.stack.push(Value::Boolean(true));
+                } else {
+                    self.stack.push(Value::Boolean(false));
                 }
-                OP_SET_LOCAL => {
-                    let index = match Self::read_local_index(&mut self.frame_last_mut()) {
-                        Some(index) => index,
-                        None => {
-                            return InterpretResult::RuntimeError(
-                                "Instruction is \"OP_SET_LOCAL\". but no value.".to_string(),
-                            )
-                        }
-                    };
-                    let value = self.stack.last().clone();
-                    let sp = self.frame_last().sp;
-                    *self.stack.get_mut(sp + index) = value;
+            }
+            OP_LESS => {
+                match self.invoke_operator("__lt__", 1) {
+                    Some(Ok(_)) => return InterpretResult::Continue,
+                    Some(Err(e)) => return InterpretResult::runtime_error(e),
+                    None => {}
                 }
-                OP_JUMP_IF_FALSE => {
-                    let offset = match Self::read_jump(&mut self.frame_last_mut()) {
-                        Some(offset) => offset,
-                        None => return InterpretResult::RuntimeError(
-                            "Instruction is \"OP_JUMP_IF_FALSE\". but no offset on instruction."
+                let b = self.stack.pop();
+                let a = self.stack.pop();
+                if a < b {
+                    self.stack.push(Value::Boolean(true));
+                } else {
+                    self.stack.push(Value::Boolean(false));
+                }
+            }
+            OP_CONTAINS => {
+                let container = self.stack.pop();
+                let item = self.stack.pop();
+                match container.contains(&item) {
+                    Ok(b) => self.stack.push(Value::Boolean(b)),
+                    Err(error) => {
+                        return InterpretResult::runtime_error(format!(
+                            "Instruction is \"OP_CONTAINS\". [Not Support Operation]{}",
+                            error
+                        ))
+                    }
+                }
+            }
+            OP_EQUAL => {
+                match self.invoke_operator("__eq__", 1) {
+                    Some(Ok(_)) => return InterpretResult::Continue,
+                    Some(Err(e)) => return InterpretResult::runtime_error(e),
+                    None => {}
+                }
+                let b = self.stack.pop();
+                let a = self.stack.pop();
+                if a == b {
+                    self.stack.push(Value::Boolean(true));
+                } else {
+                    self.stack.push(Value::Boolean(false));
+                }
+            }
+            OP_PRINT => {
+                let count = match Self::read_byte(&mut self.frame_last_mut()) {
+                    Some(count) => count as usize,
+                    None => {
+                        return InterpretResult::runtime_error(
+                            "Instruction is \"OP_PRINT\". but no offset on instruction."
                                 .to_string(),
-                        ),
-                    };
-                    if self.stack.last().is_falsy() {
-                        *self.get_current_ip_mut() += offset;
+                        )
                     }
+                };
+                // 複数の値は空白で区切り、最後に1度だけ改行する
+                let line = self
+                    .stack
+                    .get_slice(self.stack.len() - count)
+                    .iter()
+                    .map(|v| format!("{}", v))
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                self.stack.set_index(self.stack.len() - count);
+                if let Err(e) = writeln!(self.output, "{}", line) {
+                    return InterpretResult::runtime_error(format!(
+                        "Instruction is \"OP_PRINT\". {}",
+                        e
+                    ));
                 }
-                OP_JUMP => {
-                    let offset = match Self::read_jump(&mut self.frame_last_mut()) {
+            }
+            OP_POP => {
+                self.stack.pop_index();
+            }
+            OP_DEFINE_GLOBAL => {
+                let key = Self::read_string(&mut self.frame_last_mut());
+                let value = self.stack.pop();
+                self.globals.insert(key, value);
+            }
+            OP_GET_GLOBAL => {
+                // 名前の定数は複製せずに参照したまま引く
+                let key =
+                    Self::read_string_ref(Self::current_frame(&mut self.frames, self.frame_count));
+                match self.globals.find(key) {
+                    Some(value) => self.stack.push(value.clone()),
+                    None => {
+                        let key = Rc::clone(key);
+                        return InterpretResult::runtime_error(match self.suggest_global(&key) {
+                            Some(suggestion) => format!(
+                                "undefined variable '{}'; did you mean '{}'?",
+                                key, suggestion
+                            ),
+                            None => format!("undefined variable '{}'", key),
+                        });
+                    }
+                };
+            }
+            OP_SET_GLOBAL => {
+                let key =
+                    Self::read_string_ref(Self::current_frame(&mut self.frames, self.frame_count));
+                let value = self.stack.last().clone();
+                match self.globals.get_mut(key) {
+                    Some(global) => *global = value,
+                    None => {
+                        self.globals.insert(Rc::clone(key), value);
+                    }
+                }
+            }
+            OP_GET_LOCAL => {
+                let frame = self.frame_last_mut();
+                let index = match Self::read_local_index(frame) {
+                    Some(index) => index,
+                    None => {
+                        return InterpretResult::runtime_error(
+                            "Instruction is \"OP_GET_LOCAL\". but no value.".to_string(),
+                        )
+                    }
+                };
+                let sp = frame.sp;
+                let value = self.stack.get(sp + index).clone();
+                self.stack.push(value);
+            }
+            OP_SET_LOCAL => {
+                let frame = self.frame_last_mut();
+                let index = match Self::read_local_index(frame) {
+                    Some(index) => index,
+                    None => {
+                        return InterpretResult::runtime_error(
+                            "Instruction is \"OP_SET_LOCAL\". but no value.".to_string(),
+                        )
+                    }
+                };
+                let sp = frame.sp;
+                let value = self.stack.last().clone();
+                *self.stack.get_mut(sp + index) = value;
+            }
+            OP_JUMP_IF_FALSE => {
+                let offset =
+                    match Self::read_jump(&mut self.frame_last_mut()) {
                         Some(offset) => offset,
-                        None => {
-                            return InterpretResult::RuntimeError(
-                                "Instruction is \"OP_JUMP\". but no offset on instruction."
-                                    .to_string(),
-                            )
-                        }
+                        None => return InterpretResult::runtime_error(
+                            "Instruction is \"OP_JUMP_IF_FALSE\". but no offset on instruction."
+                                .to_string(),
+                        ),
                     };
+                if self.stack.last().is_falsy() {
                     *self.get_current_ip_mut() += offset;
                 }
-                OP_LOOP => {
-                    let offset = match Self::read_jump(&mut self.frame_last_mut()) {
-                        Some(offset) => offset,
-                        None => {
-                            return InterpretResult::RuntimeError(
-                                "Instruction is \"OP_LOOP\". but no offset on instruction."
-                                    .to_string(),
-                            )
-                        }
-                    };
-                    *self.get_current_ip_mut() -= offset;
+            }
+            OP_JUMP => {
+                let offset = match Self::read_jump(&mut self.frame_last_mut()) {
+                    Some(offset) => offset,
+                    None => {
+                        return InterpretResult::runtime_error(
+                            "Instruction is \"OP_JUMP\". but no offset on instruction.".to_string(),
+                        )
+                    }
+                };
+                *self.get_current_ip_mut() += offset;
+            }
+            OP_LOOP => {
+                let offset = match Self::read_jump(&mut self.frame_last_mut()) {
+                    Some(offset) => offset,
+                    None => {
+                        return InterpretResult::runtime_error(
+                            "Instruction is \"OP_LOOP\". but no offset on instruction.".to_string(),
+                        )
+                    }
+                };
+                *self.get_current_ip_mut() -= offset;
+            }
+            OP_CALL => {
+                let arg_count = match Self::read_byte(&mut self.frame_last_mut()) {
+                    Some(arg_count) => arg_count as usize,
+                    None => {
+                        return InterpretResult::runtime_error(
+                            "Instruction is \"OP_CALL\". but no offset on instruction.".to_string(),
+                        )
+                    }
+                };
+
+                let index = self.stack.len() - (arg_count + 1) as usize;
+                let callee = self.stack.get(index).clone();
+                match self.call_value(callee, arg_count) {
+                    Ok(_) => {}
+                    Err(e) => return InterpretResult::runtime_error(e),
                 }
-                OP_CALL => {
-                    let arg_count = match Self::read_byte(&mut self.frame_last_mut()) {
-                        Some(arg_count) => arg_count as usize,
-                        None => {
-                            return InterpretResult::RuntimeError(
-                                "Instruction is \"OP_CALL\". but no offset on instruction."
-                                    .to_string(),
-                            )
-                        }
-                    };
+            }
+            OP_CALL_NAMED => {
+                let counts = (
+                    Self::read_byte(&mut self.frame_last_mut()),
+                    Self::read_byte(&mut self.frame_last_mut()),
+                );
+                let (positional, named) = match counts {
+                    (Some(positional), Some(named)) => (positional as usize, named as usize),
+                    _ => {
+                        return InterpretResult::runtime_error(
+                            "Instruction is \"OP_CALL_NAMED\". but no offset on instruction."
+                                .to_string(),
+                        )
+                    }
+                };
+                let names = (0..named)
+                    .map(|_| Self::read_string(&mut self.frame_last_mut()))
+                    .collect::<Vec<_>>();
+                let arg_count = match self.reorder_arguments(positional, names) {
+                    Ok(arg_count) => arg_count,
+                    Err(e) => return InterpretResult::runtime_error(e),
+                };
 
-                    let index = self.stack.len() - (arg_count + 1) as usize;
-                    let callee = self.stack.get(index).clone();
-                    match self.call_value(callee, arg_count) {
-                        Ok(_) => {}
-                        Err(e) => return InterpretResult::RuntimeError(e),
+                let index = self.stack.len() - (arg_count + 1);
+                let callee = self.stack.get(index).clone();
+                match self.call_value(callee, arg_count) {
+                    Ok(_) => {}
+                    Err(e) => return InterpretResult::runtime_error(e),
+                }
+            }
+            OP_ARRAY => {
+                let length = match Self::read_byte(&mut self.frame_last_mut()) {
+                    Some(length) => length as usize,
+                    None => {
+                        return InterpretResult::runtime_error(
+                            "Instruction is \"OP_ARRAY\". but no offset on instruction."
+                                .to_string(),
+                        )
                     }
+                };
+                let values = self
+                    .stack
+                    .get_slice(self.stack.len() - length)
+                    .iter()
+                    .map(|v| v.clone())
+                    .collect::<Vec<Value>>();
+                self.stack.set_index(self.stack.len() - length);
+                self.stack.push(Value::Array(Rc::new(RefCell::new(values))));
+            }
+            OP_INDEX_CALL => {
+                match self.invoke_operator("__index__", 1) {
+                    Some(Ok(_)) => return InterpretResult::Continue,
+                    Some(Err(e)) => return InterpretResult::runtime_error(e),
+                    None => {}
                 }
-                OP_ARRAY => {
-                    let length = match Self::read_byte(&mut self.frame_last_mut()) {
-                        Some(length) => length as usize,
-                        None => {
-                            return InterpretResult::RuntimeError(
-                                "Instruction is \"OP_ARRAY\". but no offset on instruction."
-                                    .to_string(),
-                            )
-                        }
-                    };
-                    let values = self
-                        .stack
-                        .get_slice(self.stack.len() - length)
-                        .iter()
-                        .map(|v| v.clone())
-                        .collect::<Vec<Value>>();
-                    self.stack.set_index(self.stack.len() - length);
-                    self.stack.push(Value::Array(Rc::new(RefCell::new(values))));
-                }
-                OP_INDEX_CALL => {
-                    let b = match self.stack.pop() {
-                        Value::Integer(v) => v as usize,
-                        _ => {
-                            return InterpretResult::RuntimeError(
-                                "Instruction is \"OP_INDEX_CALL\". but no value.".to_string(),
-                            )
-                        }
-                    };
-                    let a = match self.stack.pop() {
-                        Value::Array(v) => v,
-                        _ => {
-                            return InterpretResult::RuntimeError(
-                                "Instruction is \"OP_INDEX_CALL\". but no value.".to_string(),
-                            )
-                        }
+                let index = self.stack.pop();
+                let container = self.stack.pop();
+                // マップはキーで引く。ないキーは null
+                if let Value::Map(map) = &container {
+                    let value = match Key::from_value(&index) {
+                        Some(key) => map.borrow().get(&key).cloned(),
+                        None => None,
                     };
-                    match a.borrow().get(b) {
-                        Some(v) => self.stack.push(v.clone()),
+                    self.stack.push(value.unwrap_or(Value::Null));
+                    return InterpretResult::Continue;
+                }
+                let b = match index {
+                    Value::Integer(v) => v as usize,
+                    _ => {
+                        return InterpretResult::runtime_error(
+                            "Instruction is \"OP_INDEX_CALL\". but no value.".to_string(),
+                        )
+                    }
+                };
+                if let Value::Bytes(bytes) = &container {
+                    match bytes.borrow().get(b) {
+                        Some(v) => self.stack.push(Value::Integer(*v as i64)),
                         None => self.stack.push(Value::Null),
                     };
+                    return InterpretResult::Continue;
                 }
-                OP_CLOSURE => {
-                    let value = Self::read_constant(&mut self.frame_last_mut());
-                    match value {
-                        Value::Function(func) => {
-                            let range = 0..func.upvalue_count;
-                            let mut closure_object = ClosureObject::new(func);
-                            for _ in range {
-                                let is_local = match Self::read_byte(&mut self.frame_last_mut()) {
-                                    Some(length) => length,
-                                    None => {
-                                        return InterpretResult::RuntimeError(
-                                            "Instruction is \"OP_CLOSURE\". but no offset on instruction."
-                                                .to_string(),
-                                        )
-                                    }
-                                };
-                                let upvalue_index = match Self::read_byte(&mut self.frame_last_mut()) {
-                                    Some(length) => length as usize,
-                                    None => {
-                                        return InterpretResult::RuntimeError(
-                                            "Instruction is \"OP_CLOSURE\". but no offset on instruction."
-                                                .to_string(),
-                                        )
-                                    }
-                                };
-                                if is_local == 1 {
-                                    closure_object.upvalues.push(
-                                        self.capture_upvalue(self.frame_last().sp + upvalue_index),
-                                    )
-                                } else {
-                                    closure_object.upvalues.push(
-                                        self.frame_last().closure.upvalues[upvalue_index].clone(),
-                                    )
-                                }
+                let a = match container.as_array() {
+                    Some(v) => v,
+                    None => {
+                        return InterpretResult::runtime_error(
+                            "Instruction is \"OP_INDEX_CALL\". but no value.".to_string(),
+                        )
+                    }
+                };
+                match a.borrow().get(b) {
+                    Some(v) => self.stack.push(v.clone()),
+                    None => self.stack.push(Value::Null),
+                };
+            }
+            OP_CLOSURE => {
+                let value = Self::read_constant(&mut self.frame_last_mut());
+                match value {
+                    Value::Function(func) => {
+                        let range = 0..func.upvalue_count;
+                        let mut closure_object = ClosureObject::new(func);
+                        for _ in range {
+                            let is_local = match Self::read_byte(&mut self.frame_last_mut()) {
+                                Some(length) => length,
+                                None => return InterpretResult::runtime_error(
+                                    "Instruction is \"OP_CLOSURE\". but no offset on instruction."
+                                        .to_string(),
+                                ),
+                            };
+                            let upvalue_index = match Self::read_byte(&mut self.frame_last_mut()) {
+                                Some(length) => length as usize,
+                                None => return InterpretResult::runtime_error(
+                                    "Instruction is \"OP_CLOSURE\". but no offset on instruction."
+                                        .to_string(),
+                                ),
+                            };
+                            if is_local == 1 {
+                                closure_object.upvalues.push(
+                                    self.capture_upvalue(self.frame_last().sp + upvalue_index),
+                                )
+                            } else {
+                                closure_object
+                                    .upvalues
+                                    .push(self.frame_last().closure.upvalues[upvalue_index].clone())
                             }
-                            self.stack.push(Value::Closure(Rc::new(closure_object)));
                         }
-                        _ => todo!(),
+                        self.stack.push(Value::Closure(Rc::new(closure_object)));
                     }
+                    _ => todo!(),
                 }
-                OP_CLOSE_UPVALUE => {
-                    self.close_upvalues();
-                    self.stack.pop();
-                }
-                OP_GET_UPVALUE => {
-                    let upvalue_index = match Self::read_byte(&mut self.frame_last_mut()) {
-                        Some(length) => length as usize,
-                        None => {
-                            return InterpretResult::RuntimeError(
-                                "Instruction is \"OP_CLOSURE\". but no offset on instruction."
-                                    .to_string(),
-                            )
-                        }
-                    };
-                    let closed_value = match &self.frame_last().closure.upvalues[upvalue_index]
-                        .borrow()
-                        .closed
-                    {
-                        Some(val) => val.clone(),
-                        None => {
-                            match &self.frame_last().closure.upvalues[upvalue_index]
-                                .borrow()
-                                .location
-                            {
-                                Some(loc) => self.stack.get(*loc).clone(),
-                                None => panic!(""),
-                            }
-                        }
-                    };
-                    self.stack.push(closed_value);
+            }
+            OP_CLOSE_UPVALUE => {
+                self.close_upvalues(self.stack.len() - 1);
+                self.stack.pop();
+            }
+            OP_GET_UPVALUE => {
+                let upvalue_index = match Self::read_byte(&mut self.frame_last_mut()) {
+                    Some(length) => length as usize,
+                    None => {
+                        return InterpretResult::runtime_error(
+                            "Instruction is \"OP_CLOSURE\". but no offset on instruction."
+                                .to_string(),
+                        )
+                    }
+                };
+                let upvalue = self.frame_last().closure.upvalues[upvalue_index].borrow();
+                let closed_value = match (&upvalue.closed, upvalue.location) {
+                    (Some(val), _) => val.clone(),
+                    (None, Some(loc)) => self.stack.get(loc).clone(),
+                    (None, None) => panic!(""),
+                };
+                drop(upvalue);
+                self.stack.push(closed_value);
+            }
+            OP_SET_UPVALUE => {
+                let upvalue_index = match Self::read_byte(&mut self.frame_last_mut()) {
+                    Some(length) => length as usize,
+                    None => {
+                        return InterpretResult::runtime_error(
+                            "Instruction is \"OP_CLOSURE\". but no offset on instruction."
+                                .to_string(),
+                        )
+                    }
+                };
+                // まだ閉じていない上位値は、捕捉元のスタックの値を書き換える
+                let value = self.stack.last().clone();
+                let upvalue = &Self::current_frame(&mut self.frames, self.frame_count)
+                    .closure
+                    .upvalues[upvalue_index];
+                let location = upvalue.borrow().location;
+                match location {
+                    Some(loc) => *self.stack.get_mut(loc) = value,
+                    None => upvalue.borrow_mut().closed = Some(value),
                 }
-                OP_SET_UPVALUE => {
-                    let upvalue_index = match Self::read_byte(&mut self.frame_last_mut()) {
-                        Some(length) => length as usize,
-                        None => {
-                            return InterpretResult::RuntimeError(
-                                "Instruction is \"OP_CLOSURE\". but no offset on instruction."
-                                    .to_string(),
-                            )
-                        }
-                    };
-                    self.frame_last().closure.upvalues[upvalue_index]
-                        .borrow_mut()
-                        .closed = Some(self.stack.last().clone());
-                }
-                OP_CLASS => {
-                    let name = Self::read_string(&mut self.frame_last_mut());
-                    let class = Value::Class(Rc::new(RefCell::new(ClassObject::new(&*name))));
-                    self.stack.push(class);
-                }
-                OP_GET_PROP => {
-                    let instance = self.stack.last().clone();
-                    let name = Self::read_string(&mut self.frame_last_mut());
-                    match instance {
-                        Value::Instance(instance) => {
-                            if let Some(value) = instance.borrow().fields.get(&name) {
-                                self.stack.pop_index();
-                                self.stack.push(value.clone());
-                                continue;
-                            }
-                            match self.bind_method(instance.borrow().class.clone(), name.clone()) {
-                                Some(_) => continue,
-                                None => {}
-                            }
+            }
+            OP_CLASS => {
+                let name = Self::read_string(&mut self.frame_last_mut());
+                let class = Value::Class(Rc::new(RefCell::new(ClassObject::new(&*name))));
+                self.stack.push(class);
+            }
+            OP_GET_PROP => {
+                let offset = self.frame_last().ip - 1;
+                let instance = self.stack.last().clone();
+                let name = Self::read_string(&mut self.frame_last_mut());
+                match instance {
+                    Value::Instance(instance) => {
+                        if let Some(value) = instance.borrow().fields.get(&name) {
                             self.stack.pop_index();
-                            self.stack.push(Value::Null);
-                        }
-                        invalid => {
-                            self.stack.print();
-                            panic!("[OP_GET_PROP]{:?}", invalid);
+                            self.stack.push(value.clone());
+                            return InterpretResult::Continue;
                         }
-                    };
-                }
-                OP_SET_PROP => {
-                    let instance = self.stack.get(self.stack.len() - 2).clone();
-                    let name = Self::read_string(&mut self.frame_last_mut());
-                    let value = self.stack.last().clone();
+                        let class = instance.borrow().class.clone();
+                        let value = match self.find_method_cached(offset, &class, &name) {
+                            Some(method) => Value::BoundMethod(Rc::new(RefCell::new(
+                                BoundMethodObject::new(Value::Instance(instance), method),
+                            ))),
+                            None => Value::Null,
+                        };
+                        self.stack.pop_index();
+                        self.stack.push(value);
+                    }
+                    invalid => {
+                        self.stack.print();
+                        panic!("[OP_GET_PROP]{:?}", invalid);
+                    }
+                };
+            }
+            OP_SET_PROP => {
+                let instance = self.stack.get(self.stack.len() - 2).clone();
+                let name = Self::read_string(&mut self.frame_last_mut());
+                let value = self.stack.last().clone();
 
-                    match instance {
-                        Value::Instance(instance) => {
-                            instance.borrow_mut().fields.insert(name, value.clone());
-                        }
-                        invalid => {
-                            self.stack.print();
-                            panic!("[OP_SET_PROP] invalid. {}", invalid);
-                        }
-                    };
-                    self.stack.pop_index();
-                    self.stack.pop_index();
-                    self.stack.push(value);
-                }
-                OP_METHOD => {
-                    let name = Self::read_string(&mut self.frame_last_mut());
-                    let method = self.stack.last().clone();
-                    let class = match self.stack.get(self.stack.len() - 2) {
-                        Value::Class(cls) => cls.clone(),
-                        _ => todo!(),
-                    };
-                    class.borrow_mut().methods.insert(name, method);
-                    self.stack.pop_index();
-                }
-                OP_INVOKE => {
-                    let name = Self::read_string(&mut self.frame_last_mut());
-                    let arg_count = match Self::read_byte(&mut self.frame_last_mut()) {
-                        Some(arg_count) => arg_count as usize,
-                        None => {
-                            return InterpretResult::RuntimeError(
-                                "Instruction is \"OP_INVOKE\". but no offset on instruction."
-                                    .to_string(),
-                            )
-                        }
-                    };
-                    match self.invoke(name, arg_count) {
-                        Ok(_) => {}
-                        Err(e) => return InterpretResult::RuntimeError(e),
-                    };
-                }
-                OP_INHERIT => {
-                    let index = self.stack.len() - 2;
-                    let super_class = match self.stack.get(index).clone() {
-                        Value::Class(cls) => cls,
-                        invalid => return InterpretResult::RuntimeError(format!(
-                            "Instruction is \"OP_INHERIT\". Super class must be class. But found {}",
-                            invalid
-                        )),
-                    };
-                    let sub_class = match self.stack.last().clone() {
-                        Value::Class(cls) => cls,
-                        invalid => {
-                            return InterpretResult::RuntimeError(format!(
+                match instance {
+                    Value::Instance(instance) => {
+                        instance.borrow_mut().fields.insert(name, value.clone());
+                    }
+                    invalid => {
+                        self.stack.print();
+                        panic!("[OP_SET_PROP] invalid. {}", invalid);
+                    }
+                };
+                self.stack.pop_index();
+                self.stack.pop_index();
+                self.stack.push(value);
+            }
+            OP_METHOD => {
+                let name = Self::read_string(&mut self.frame_last_mut());
+                let method = self.stack.last().clone();
+                let class = match self.stack.get(self.stack.len() - 2) {
+                    Value::Class(cls) => cls.clone(),
+                    _ => todo!(),
+                };
+                class.borrow_mut().methods.insert(name, method);
+                self.stack.pop_index();
+            }
+            OP_INVOKE => {
+                let offset = self.frame_last().ip - 1;
+                let name = Self::read_string(&mut self.frame_last_mut());
+                let arg_count = match Self::read_byte(&mut self.frame_last_mut()) {
+                    Some(arg_count) => arg_count as usize,
+                    None => {
+                        return InterpretResult::runtime_error(
+                            "Instruction is \"OP_INVOKE\". but no offset on instruction."
+                                .to_string(),
+                        )
+                    }
+                };
+                match self.invoke_at(offset, name, arg_count) {
+                    Ok(_) => {}
+                    Err(e) => return InterpretResult::runtime_error(e),
+                };
+            }
+            OP_INHERIT => {
+                let index = self.stack.len() - 2;
+                let super_class = match self.stack.get(index).clone() {
+                    Value::Class(cls) => cls,
+                    invalid => {
+                        return InterpretResult::runtime_error(format!(
+                        "Instruction is \"OP_INHERIT\". Super class must be class. But found {}",
+                        invalid
+                    ))
+                    }
+                };
+                let sub_class = match self.stack.last().clone() {
+                    Value::Class(cls) => cls,
+                    invalid => {
+                        return InterpretResult::runtime_error(format!(
                             "Instruction is \"OP_INHERIT\". Sub class must be class. But found {}",
                             invalid
                         ))
+                    }
+                };
+                sub_class.borrow_mut().superclass = Some(super_class);
+                self.stack.pop_index();
+            }
+            OP_SUPER_INVOKE => {
+                let name = Self::read_string(&mut self.frame_last_mut());
+                let arg_count = match Self::read_byte(&mut self.frame_last_mut()) {
+                    Some(arg_count) => arg_count as usize,
+                    None => {
+                        return InterpretResult::runtime_error(
+                            "Instruction is \"OP_SUPER_INVOKE\". but no offset on instruction."
+                                .to_string(),
+                        )
+                    }
+                };
+                match self.stack.pop().clone() {
+                    Value::Class(cls) => {
+                        match self.invoke_from_class(cls, name, arg_count) {
+                            Ok(_) => {}
+                            Err(e) => return InterpretResult::runtime_error(e),
+                        };
+                    }
+                    invalid => {
+                        return InterpretResult::runtime_error(format!("invalid: {}", invalid))
+                    }
+                };
+            }
+            OP_GET_SUPER => {
+                let super_class = self.stack.pop().clone();
+                let name = Self::read_string(&mut self.frame_last_mut());
+                match super_class {
+                    Value::Class(super_class) => {
+                        match self.bind_method(super_class.clone(), name.clone()) {
+                            Some(_) => return InterpretResult::Continue,
+                            None => {}
                         }
-                    };
-                    for (k, v) in super_class.borrow().methods.iter() {
-                        sub_class.borrow_mut().methods.insert(k.clone(), v.clone());
+                        self.stack.pop_index();
+                        self.stack.push(Value::Null);
                     }
-                    self.stack.pop_index();
+                    invalid => {
+                        self.stack.print();
+                        panic!("[OP_SUPER_GET_PROP]{:?}", invalid);
+                    }
+                };
+            }
+            OP_INDEX_SET => {
+                // __index_set__ の戻り値ではなく、配列と同じく代入した値を式の値にする
+                let value = self.stack.last().clone();
+                let frame_count = self.frame_count;
+                match self.invoke_operator("__index_set__", 2) {
+                    Some(Ok(_)) => {
+                        if let Err(e) = self.finish_call(frame_count, "__index_set__") {
+                            return InterpretResult::runtime_error(e);
+                        }
+                        self.stack.pop();
+                        self.stack.push(value);
+                        return InterpretResult::Continue;
+                    }
+                    Some(Err(e)) => return InterpretResult::runtime_error(e),
+                    None => {}
                 }
-                OP_SUPER_INVOKE => {
-                    let name = Self::read_string(&mut self.frame_last_mut());
-                    let arg_count = match Self::read_byte(&mut self.frame_last_mut()) {
-                        Some(arg_count) => arg_count as usize,
+                let value = self.stack.pop();
+                let index = self.stack.pop();
+                let container = self.stack.pop();
+                // マップに新しいキーを代入すると末尾に追加する
+                if let Value::Map(map) = &container {
+                    let key = match Key::from_value(&index) {
+                        Some(key) => key,
                         None => {
-                            return InterpretResult::RuntimeError(
-                                "Instruction is \"OP_INVOKE\". but no offset on instruction."
-                                    .to_string(),
-                            )
-                        }
-                    };
-                    match self.stack.pop().clone() {
-                        Value::Class(cls) => {
-                            match self.invoke_from_class(cls, name, arg_count) {
-                                Ok(_) => {}
-                                Err(e) => return InterpretResult::RuntimeError(e),
-                            };
-                        }
-                        invalid => {
-                            return InterpretResult::RuntimeError(format!("invalid: {}", invalid))
+                            return InterpretResult::runtime_error(format!(
+                                "Map key must be null, boolean, number or string. but found {}.",
+                                index.type_name()
+                            ))
                         }
                     };
+                    map.borrow_mut().insert(key, value.clone());
+                    self.stack.push(value);
+                    return InterpretResult::Continue;
                 }
-                OP_GET_SUPER => {
-                    let super_class = self.stack.pop().clone();
-                    let name = Self::read_string(&mut self.frame_last_mut());
-                    match super_class {
-                        Value::Class(super_class) => {
-                            match self.bind_method(super_class.clone(), name.clone()) {
-                                Some(_) => continue,
-                                None => {}
-                            }
-                            self.stack.pop_index();
-                            self.stack.push(Value::Null);
-                        }
+                let index = match index {
+                    Value::Integer(v) => v as usize,
+                    _ => {
+                        return InterpretResult::runtime_error(
+                            "Instruction is \"OP_INDEX_SET\". but no value.".to_string(),
+                        )
+                    }
+                };
+                if let Value::Bytes(bytes) = &container {
+                    let byte = match value {
+                        Value::Integer(v) if (0..=255).contains(&v) => v as u8,
                         invalid => {
-                            self.stack.print();
-                            panic!("[OP_SUPER_GET_PROP]{:?}", invalid);
-                        }
-                    };
-                }
-                OP_INDEX_SET => {
-                    let value = self.stack.pop();
-                    let index = match self.stack.pop() {
-                        Value::Integer(v) => v as usize,
-                        _ => {
-                            return InterpretResult::RuntimeError(
-                                "Instruction is \"OP_INDEX_SET\". but no value.".to_string(),
-                            )
-                        }
-                    };
-                    let array = match self.stack.pop() {
-                        Value::Array(v) => v,
-                        _ => {
-                            return InterpretResult::RuntimeError(
-                                "Instruction is \"OP_INDEX_SET\". but no value.".to_string(),
-                            )
+                            return InterpretResult::runtime_error(format!(
+                                "Bytes element must be an integer between 0 and 255. but found {}.",
+                                invalid
+                            ))
                         }
                     };
-                    match array.borrow_mut().get_mut(index) {
-                        Some(get_val) => *get_val = value,
+                    match bytes.borrow_mut().get_mut(index) {
+                        Some(b) => *b = byte,
                         None => {
-                            return InterpretResult::RuntimeError(
+                            return InterpretResult::runtime_error(
                                 "Instruction is \"OP_INDEX_SET\". but no value.".to_string(),
                             )
                         }
                     };
+                    self.stack.push(value);
+                    return InterpretResult::Continue;
                 }
-                OP_CONSTANT0 => {
-                    self.stack.push(Value::Integer(0));
-                }
-                OP_JUMP_IF_RANGE_END => {
-                    let range = self.stack.pop();
-                    let index = match self.stack.pop() {
-                        Value::Integer(i) => i as usize,
-                        invalid => panic!("Range index expected integer. But found {}", invalid),
-                    };
-                    let offset = match Self::read_jump(&mut self.frame_last_mut()) {
+                let array = match container.as_array() {
+                    Some(v) => v,
+                    None => {
+                        return InterpretResult::runtime_error(
+                            "Instruction is \"OP_INDEX_SET\". but no value.".to_string(),
+                        )
+                    }
+                };
+                match array.borrow_mut().get_mut(index) {
+                    Some(get_val) => *get_val = value.clone(),
+                    None => {
+                        return InterpretResult::runtime_error(
+                            "Instruction is \"OP_INDEX_SET\". but no value.".to_string(),
+                        )
+                    }
+                };
+                // 代入式の値として代入した値を残す
+                self.stack.push(value);
+            }
+            OP_CONSTANT0 => {
+                self.stack.push(Value::Integer(0));
+            }
+            OP_CONSTANT_SMALL => {
+                let value = match Self::read_byte(&mut self.frame_last_mut()) {
+                    Some(value) => value,
+                    None => {
+                        return InterpretResult::runtime_error(
+                            "Instruction is \"OP_CONSTANT_SMALL\". but no value.".to_string(),
+                        )
+                    }
+                };
+                self.stack.push(Value::Integer(value as i64));
+            }
+            OP_JUMP_IF_RANGE_END => {
+                let range = self.stack.pop();
+                let index = match self.stack.pop() {
+                    Value::Integer(i) => i as usize,
+                    invalid => panic!("Range index expected integer. But found {}", invalid),
+                };
+                let offset =
+                    match Self::read_jump(&mut self.frame_last_mut()) {
                         Some(offset) => offset,
-                        None => return InterpretResult::RuntimeError(
+                        None => return InterpretResult::runtime_error(
                             "Instruction is \"OP_JUMP_IF_FALSE\". but no offset on instruction."
                                 .to_string(),
                         ),
                     };
-                    match range {
-                        Value::Array(array) => match array.borrow().get(index) {
-                            Some(v) => {
-                                self.stack.push(v.clone());
-                            }
-                            None => {
-                                self.stack.push(Value::Null);
-                                *self.get_current_ip_mut() += offset;
-                            }
-                        },
-                        invalid => panic!("Range expected array. But found {}", invalid),
-                    };
-                }
-                OP_COUNTUP => {
-                    let index = match Self::read_local_index(&mut self.frame_last_mut()) {
-                        Some(index) => index,
+                match range {
+                    Value::Array(array) => match array.borrow().get(index) {
+                        Some(v) => {
+                            self.stack.push(v.clone());
+                        }
                         None => {
-                            return InterpretResult::RuntimeError(
-                                "Instruction is \"OP_SET_LOCAL\". but no value.".to_string(),
-                            )
+                            self.stack.push(Value::Null);
+                            *self.get_current_ip_mut() += offset;
                         }
-                    };
-                    let sp = self.frame_last().sp;
-                    let value = self.stack.get(sp + index).clone();
-                    *self.stack.get_mut(sp + index) = match value {
-                        Value::Integer(i) => Value::Integer(i + 1),
-                        invalid => panic!("invalid: {}", invalid),
-                    };
+                    },
+                    Value::Generator(generator) => match self.generator_next(generator) {
+                        Ok(Some(v)) => self.stack.push(v),
+                        Ok(None) => {
+                            self.stack.push(Value::Null);
+                            *self.get_current_ip_mut() += offset;
+                        }
+                        Err(e) => return InterpretResult::runtime_error(e),
+                    },
+                    // マップはキーを追加した順に繰り返す
+                    Value::Map(map) => match map.borrow().key_at(index) {
+                        Some(key) => {
+                            self.stack.push(key.to_value());
+                        }
+                        None => {
+                            self.stack.push(Value::Null);
+                            *self.get_current_ip_mut() += offset;
+                        }
+                    },
+                    // インスタンスは done() が真になるまで next() の値を取り出す
+                    Value::Instance(ref instance) => {
+                        let missing = ["next", "done"].into_iter().find(|name| {
+                            let name = Rc::new(name.to_string());
+                            let instance = instance.borrow();
+                            !instance.fields.contains_key(&name)
+                                && instance.class.borrow().find_method(&name).is_none()
+                        });
+                        if let Some(name) = missing {
+                            return InterpretResult::runtime_error(format!(
+                                "instance of {} is not iterable. (missing {})",
+                                instance.borrow().class.borrow().name,
+                                name
+                            ));
+                        }
+                        let done = match self.call_method(range.clone(), "done") {
+                            Ok(done) => done,
+                            Err(e) => return InterpretResult::runtime_error(e),
+                        };
+                        if done.is_falsy() {
+                            match self.call_method(range, "next") {
+                                Ok(v) => self.stack.push(v),
+                                Err(e) => return InterpretResult::runtime_error(e),
+                            }
+                        } else {
+                            self.stack.push(Value::Null);
+                            *self.get_current_ip_mut() += offset;
+                        }
+                    }
+                    invalid => {
+                        return InterpretResult::runtime_error(format!(
+                            "{} ({}) is not iterable.",
+                            invalid,
+                            invalid.type_name()
+                        ))
+                    }
+                };
+            }
+            OP_ARRAY_PUSH => {
+                let value = self.stack.pop();
+                match self.stack.pop() {
+                    Value::Array(array) => array.borrow_mut().push(value),
+                    invalid => {
+                        return InterpretResult::runtime_error(format!(
+                            "Instruction is \"OP_ARRAY_PUSH\". expected array. but found {}",
+                            invalid
+                        ))
+                    }
                 }
-                OP_RANGE => {
-                    let end = match self.stack.pop() {
-                        Value::Integer(i) => i,
-                        invalid => panic!("invalid: {}", invalid),
-                    };
-                    let start = match self.stack.pop() {
-                        Value::Integer(i) => i,
-                        invalid => panic!("invalid: {}", invalid),
-                    };
-                    let mut values: Vec<Value> = Vec::new();
-                    for i in start..=end {
-                        values.push(Value::Integer(i));
+            }
+            OP_YIELD => {
+                let value = self.stack.pop();
+                let generator = match self.generators.last() {
+                    Some(generator) => Rc::clone(generator),
+                    None => {
+                        return InterpretResult::runtime_error(
+                            "Instruction is \"OP_YIELD\". but no running generator.".to_string(),
+                        )
                     }
-                    self.stack.push(Value::Array(Rc::new(RefCell::new(values))));
+                };
+                let sp = self.frame_last().sp;
+                let upvalues = self.open_upvalues_from(sp);
+                self.close_upvalues(sp);
+                let frame = self.frame_pop().clone();
+                {
+                    let mut generator = generator.borrow_mut();
+                    generator.ip = frame.ip;
+                    generator.slots = self.stack.get_slice(frame.sp).to_vec();
+                    generator.upvalues = upvalues;
+                    generator.done = false;
                 }
-                _ => {
-                    return InterpretResult::CompileError;
+                self.stack.set_index(frame.sp);
+                self.stack.push(value);
+            }
+            OP_GET_ITER => {
+                // 集合は開始時のキーを配列に写して繰り返す。位置から毎回たどり直さずに済み、
+                // 繰り返しの途中で集合を変えても影響しない
+                if let Value::Set(set) = self.stack.last() {
+                    let keys = set.borrow().iter().map(|key| key.to_value()).collect();
+                    let index = self.stack.len() - 1;
+                    *self.stack.get_mut(index) = Value::Array(Rc::new(RefCell::new(keys)));
+                }
+                // iter() を持つインスタンスはその戻り値 (配列またはイテレータ) を繰り返し対象にする
+                if let Value::Instance(instance) = self.stack.last() {
+                    let name = Rc::new("iter".to_string());
+                    let class = instance.borrow().class.clone();
+                    if class.borrow().find_method(&name).is_some() {
+                        if let Err(e) = self.invoke_from_class(class, name, 0) {
+                            return InterpretResult::runtime_error(e);
+                        }
+                    }
+                }
+            }
+            OP_COUNTUP => {
+                let index = match Self::read_local_index(&mut self.frame_last_mut()) {
+                    Some(index) => index,
+                    None => {
+                        return InterpretResult::runtime_error(
+                            "Instruction is \"OP_SET_LOCAL\". but no value.".to_string(),
+                        )
+                    }
+                };
+                let sp = self.frame_last().sp;
+                let value = self.stack.get(sp + index).clone();
+                *self.stack.get_mut(sp + index) = match value {
+                    Value::Integer(i) => Value::Integer(i + 1),
+                    invalid => panic!("invalid: {}", invalid),
+                };
+            }
+            OP_RANGE => {
+                let end = match self.stack.pop() {
+                    Value::Integer(i) => i,
+                    invalid => panic!("invalid: {}", invalid),
+                };
+                let start = match self.stack.pop() {
+                    Value::Integer(i) => i,
+                    invalid => panic!("invalid: {}", invalid),
+                };
+                let mut values: Vec<Value> = Vec::new();
+                for i in start..=end {
+                    values.push(Value::Integer(i));
                 }
+                self.stack.push(Value::Array(Rc::new(RefCell::new(values))));
+            }
+            _ => {
+                return InterpretResult::CompileError;
             }
         }
+        InterpretResult::Continue
     }
 
     fn call_value(&mut self, callee: Value, arg_count: usize) -> Result<(), String> {
         let index = self.stack.len() - (arg_count + 1) as usize;
-        match &callee {
+        // 呼び出す値は受け取ったものをそのまま使い、参照カウントを増やさない
+        match callee {
             Value::Closure(closure) => {
                 if arg_count != closure.function.arity as usize {
                     return Err(format!(
-                        "expected arg_count eq {} but found {}.",
-                        closure.function.arity, arg_count
+                        "{}: expected arg_count eq {} but found {}.",
+                        closure.function.name, closure.function.arity, arg_count
                     ));
                 }
 
-                let frame = CallFrame::new(Rc::clone(closure), 0, index);
-                self.frame_push(frame);
+                self.call_closure(closure, index)?;
             }
             Value::Native { function } => {
                 let args = self.stack.get_slice(self.stack.len() - arg_count as usize);
@@ -780,13 +1751,16 @@ impl VM {
                 self.stack.pop_index();
                 self.stack.push(value);
             }
+            Value::VmNative { function } => {
+                let args = self.stack.get_slice(self.stack.len() - arg_count).to_vec();
+                let value = function(self, &args)?;
+                self.stack.set_index(index);
+                self.stack.push(value);
+            }
             Value::Class(class) => {
                 let value =
                     Value::Instance(Rc::new(RefCell::new(InstanceObject::new(class.clone()))));
-                let init = match class.borrow().methods.get(&Rc::new("init".to_string())) {
-                    Some(v) => Some(v.clone()),
-                    None => None,
-                };
+                let init = class.borrow().find_method(&Rc::new("init".to_string()));
 
                 let tmp = self.stack.get_mut(index);
                 *tmp = value;
@@ -796,19 +1770,24 @@ impl VM {
                         Value::Closure(closure) => {
                             if arg_count != closure.function.arity as usize {
                                 return Err(format!(
-                                    "expected arg_count eq {} but found {}.",
-                                    closure.function.arity, arg_count
+                                    "{}.init: expected arg_count eq {} but found {}.",
+                                    class.borrow().name,
+                                    closure.function.arity,
+                                    arg_count
                                 ));
                             }
 
-                            let frame = CallFrame::new(closure, 0, index);
-                            self.frame_push(frame);
+                            self.call_closure(closure, index)?;
                         }
                         invalid => panic!("expected closure but found {}.", invalid),
                     },
                     None => {
                         if arg_count != 0 {
-                            panic!("expected arg_count eq 0 but found {}.", arg_count);
+                            return Err(format!(
+                                "{}.init: expected arg_count eq 0 but found {}.",
+                                class.borrow().name,
+                                arg_count
+                            ));
                         }
                     }
                 }
@@ -817,27 +1796,88 @@ impl VM {
                 let closure = bound.borrow().method.clone();
                 if arg_count != closure.function.arity as usize {
                     return Err(format!(
-                        "expected arg_count eq {} but found {}.",
-                        closure.function.arity, arg_count
+                        "{}: expected arg_count eq {} but found {}.",
+                        closure.function.name, closure.function.arity, arg_count
                     ));
                 }
 
                 let reciever = bound.borrow().reciever.clone();
                 let tmp = self.stack.get_mut(index);
                 *tmp = reciever;
-                let frame = CallFrame::new(closure, 0, index);
-                self.frame_push(frame);
+                self.call_closure(closure, index)?;
+            }
+            Value::Instance(instance) => {
+                let class = instance.borrow().class.clone();
+                let name = Rc::new("__call__".to_string());
+                if class.borrow().find_method(&name).is_none() {
+                    return Err(format!(
+                        "instance of class {} is not callable. (no __call__ method)",
+                        class.borrow().name
+                    ));
+                }
+                self.invoke_from_class(class, name, arg_count)?;
             }
             other => {
                 return Err(format!(
-                    "Instruction is \"OP_CALL\". not found identifer name.({})",
-                    other
+                    "{} ({}) is not callable.",
+                    other,
+                    other.type_name()
                 ))
             }
         }
         Ok(())
     }
 
+    // 演算子の左辺がインスタンスで、対応するメソッド (__add__ など) が定義されていれば呼び出す。
+    // 左辺にない場合は、右辺のインスタンスの反射メソッド (__radd__ など) を左辺を引数にして呼び出す。
+    // どちらも見つからない場合は None を返し、通常の演算にフォールバックする。
+    fn invoke_operator(&mut self, method: &str, arg_count: usize) -> Option<Result<(), String>> {
+        let index = self.stack.len() - (arg_count + 1);
+        if let Value::Instance(instance) = self.stack.get(index) {
+            let class = instance.borrow().class.clone();
+            let name = Rc::new(method.to_string());
+            if class.borrow().find_method(&name).is_some() {
+                return Some(self.invoke_from_class(class, name, arg_count));
+            }
+        }
+        if arg_count != 1 {
+            return None;
+        }
+        let class = match self.stack.last() {
+            Value::Instance(instance) => instance.borrow().class.clone(),
+            _ => return None,
+        };
+        let name = Rc::new(reflected_operator(method)?.to_string());
+        class.borrow().find_method(&name)?;
+        // 右辺をレシーバ、左辺を引数にする
+        let left = self.stack.get(index).clone();
+        let right = self.stack.pop();
+        *self.stack.get_mut(index) = right;
+        self.stack.push(left);
+        Some(self.invoke_from_class(class, name, arg_count))
+    }
+
+    // 引数なしでメソッドを呼び出し、呼び出したフレームが戻るまで実行して戻り値を返す
+    fn call_method(&mut self, receiver: Value, name: &str) -> Result<Value, String> {
+        let frame_count = self.frame_count;
+        self.stack.push(receiver);
+        self.invoke(Rc::new(name.to_string()), 0)?;
+        self.finish_call(frame_count, name)?;
+        Ok(self.stack.pop())
+    }
+
+    // 呼び出したフレームが戻るまで実行する。戻り値はスタックの先頭に残る
+    fn finish_call(&mut self, frame_count: usize, name: &str) -> Result<(), String> {
+        while self.frame_count > frame_count {
+            match self.step() {
+                InterpretResult::Continue => {}
+                InterpretResult::RuntimeError(e) => return Err(e.message),
+                _ => return Err(format!("method \"{}\" ended unexpectedly.", name)),
+            }
+        }
+        Ok(())
+    }
+
     fn invoke(&mut self, name: Rc<String>, arg_count: usize) -> Result<(), String> {
         let index = self.stack.len() - (arg_count + 1) as usize;
         let receiver = self.stack.get(index).clone();
@@ -854,42 +1894,260 @@ impl VM {
                     }
                 };
             }
-            _ => todo!(),
+            Value::Generator(generator) => {
+                if arg_count != 0 {
+                    return Err(format!(
+                        "{}: expected arg_count eq 0 but found {}.",
+                        name, arg_count
+                    ));
+                }
+                let value = match name.as_str() {
+                    "next" => self.generator_next(generator)?.unwrap_or(Value::Null),
+                    "done" => Value::Boolean(self.generator_done(generator)?),
+                    _ => return Err(format!("generator has no method \"{}\".", name)),
+                };
+                self.stack.set_index(index);
+                self.stack.push(value);
+            }
+            other => {
+                return Err(format!(
+                    "{} ({}) has no method \"{}\".",
+                    other,
+                    other.type_name(),
+                    name
+                ))
+            }
         };
         Ok(())
     }
 
+    // ジェネレータ関数ならフレームを積まずにジェネレータを返す
+    fn call_closure(&mut self, closure: Rc<ClosureObject>, index: usize) -> Result<(), String> {
+        if closure.function.is_generator {
+            let slots = self.stack.get_slice(index).to_vec();
+            let generator = GeneratorObject::new(closure, slots);
+            self.stack.set_index(index);
+            self.stack
+                .push(Value::Generator(Rc::new(RefCell::new(generator))));
+            return Ok(());
+        }
+        self.check_frame_limit()?;
+        let frame = CallFrame::new(closure, 0, index);
+        self.frame_push(frame);
+        Ok(())
+    }
+
+    // 呼び出しが深すぎる場合は、フレームを積む前に実行時エラーにする
+    fn check_frame_limit(&self) -> Result<(), String> {
+        if self.frame_count >= self.frames.len() {
+            return Err(format!(
+                "stack overflow. (call depth exceeded {})",
+                self.frames.len()
+            ));
+        }
+        Ok(())
+    }
+
+    // 値スタックにあと count 個積めない場合は、積む前に実行時エラーにする
+    fn check_stack_limit(&self, count: usize) -> Result<(), String> {
+        if self.stack.len() + count > self.stack.max() {
+            return Err(format!(
+                "stack overflow. (stack size exceeded {})",
+                self.stack.max()
+            ));
+        }
+        Ok(())
+    }
+
+    // ジェネレータを次の yield まで実行する。関数から戻った場合は None を返す
+    fn resume_generator(
+        &mut self,
+        generator: Rc<RefCell<GeneratorObject>>,
+    ) -> Result<Option<Value>, String> {
+        let (closure, ip, slots, upvalues) = {
+            let mut generator = generator.borrow_mut();
+            if generator.done {
+                return Ok(None);
+            }
+            // yield せずに戻った場合は終了として扱う。OP_YIELD で false に戻る
+            generator.done = true;
+            (
+                Rc::clone(&generator.closure),
+                generator.ip,
+                std::mem::take(&mut generator.slots),
+                std::mem::take(&mut generator.upvalues),
+            )
+        };
+        self.check_frame_limit()?;
+        self.check_stack_limit(slots.len())?;
+        let frame_count = self.frame_count;
+        let sp = self.stack.len();
+        for slot in slots {
+            self.stack.push(slot);
+        }
+        self.reopen_upvalues(sp, upvalues);
+        self.frame_push(CallFrame::new(closure, ip, sp));
+        self.generators.push(Rc::clone(&generator));
+        while self.frame_count > frame_count {
+            match self.step() {
+                InterpretResult::Continue => {}
+                InterpretResult::RuntimeError(e) => {
+                    self.generators.pop();
+                    return Err(e.message);
+                }
+                _ => {
+                    self.generators.pop();
+                    return Err("generator ended unexpectedly.".to_string());
+                }
+            }
+        }
+        self.generators.pop();
+        let value = self.stack.pop();
+        if generator.borrow().done {
+            Ok(None)
+        } else {
+            Ok(Some(value))
+        }
+    }
+
+    fn generator_next(
+        &mut self,
+        generator: Rc<RefCell<GeneratorObject>>,
+    ) -> Result<Option<Value>, String> {
+        let peeked = generator.borrow_mut().peeked.take();
+        match peeked {
+            Some(value) => Ok(Some(value)),
+            None => self.resume_generator(generator),
+        }
+    }
+
+    // 終了したかどうかは次の yield まで実行しないと分からないので、値を先読みしておく
+    fn generator_done(&mut self, generator: Rc<RefCell<GeneratorObject>>) -> Result<bool, String> {
+        if generator.borrow().peeked.is_some() {
+            return Ok(false);
+        }
+        match self.resume_generator(Rc::clone(&generator))? {
+            Some(value) => {
+                generator.borrow_mut().peeked = Some(value);
+                Ok(false)
+            }
+            None => Ok(true),
+        }
+    }
+
+    // 未定義のグローバル変数名に最も近い定義済みの名前を探す
+    fn suggest_global(&self, name: &str) -> Option<Rc<String>> {
+        let threshold = (name.chars().count() / 3).clamp(1, 3);
+        self.globals
+            .keys()
+            .map(|key| (edit_distance(name, key), key))
+            .filter(|(distance, _)| *distance <= threshold)
+            .min_by(|(a, a_key), (b, b_key)| a.cmp(b).then_with(|| a_key.cmp(b_key)))
+            .map(|(_, key)| Rc::clone(key))
+    }
+
     fn invoke_from_class(
         &mut self,
         class: Rc<RefCell<ClassObject>>,
         name: Rc<String>,
         arg_count: usize,
     ) -> Result<(), String> {
-        let index = self.stack.len() - (arg_count + 1) as usize;
-        let bound_method = match class.borrow().methods.get(&name) {
-            Some(bound_method) => bound_method.clone(),
-            None => panic!("undefined method {}", name),
-        };
-        match bound_method {
-            Value::Closure(closure) => {
-                if arg_count != closure.function.arity as usize {
+        let bound_method = match class.borrow().find_method(&name) {
+            Some(bound_method) => bound_method,
+            // init が定義されていない親クラスの super.init() は、暗黙の引数なし初期化子として
+            // 何もせずレシーバ (this) を結果として残す
+            None if name.as_str() == "init" => {
+                if arg_count != 0 {
                     return Err(format!(
-                        "expected arg_count eq {} but found {}.",
-                        closure.function.arity, arg_count
+                        "{}.init: expected arg_count eq 0 but found {}.",
+                        class.borrow().name,
+                        arg_count
                     ));
                 }
-                let frame = CallFrame::new(Rc::clone(&closure), 0, index);
-                self.frame_push(frame);
+                return Ok(());
             }
-            invalid => return Err(format!("invalid: {:?}", invalid)),
+            None => {
+                return Err(format!(
+                    "undefined method \"{}\" on {}.",
+                    name,
+                    class.borrow().name
+                ))
+            }
+        };
+        match bound_method {
+            Value::Closure(closure) => self.call_method_closure(&class, closure, arg_count),
+            invalid => Err(format!("invalid: {:?}", invalid)),
+        }
+    }
+
+    // レシーバと引数が積まれた状態で、クラスから見つけたメソッドを呼び出す
+    fn call_method_closure(
+        &mut self,
+        class: &Rc<RefCell<ClassObject>>,
+        closure: Rc<ClosureObject>,
+        arg_count: usize,
+    ) -> Result<(), String> {
+        if arg_count != closure.function.arity as usize {
+            return Err(format!(
+                "{}.{}: expected arg_count eq {} but found {}.",
+                class.borrow().name,
+                closure.function.name,
+                closure.function.arity,
+                arg_count
+            ));
         }
+        let index = self.stack.len() - (arg_count + 1);
+        self.call_closure(closure, index)?;
         Ok(())
     }
 
+    // OP_INVOKE の呼び出し。インスタンスのメソッドはインラインキャッシュから探し、
+    // フィールドの呼び出しやキャッシュできない場合は invoke と同じように扱う
+    fn invoke_at(
+        &mut self,
+        offset: usize,
+        name: Rc<String>,
+        arg_count: usize,
+    ) -> Result<(), String> {
+        let index = self.stack.len() - (arg_count + 1);
+        let class = match self.stack.get(index) {
+            Value::Instance(instance) if !instance.borrow().fields.contains_key(&name) => {
+                instance.borrow().class.clone()
+            }
+            _ => return self.invoke(name, arg_count),
+        };
+        match self.find_method_cached(offset, &class, &name) {
+            Some(closure) => self.call_method_closure(&class, closure, arg_count),
+            None => self.invoke_from_class(class, name, arg_count),
+        }
+    }
+
+    // 実行中の関数の offset の位置の命令から、クラスのメソッドを探す。
+    // 前回と同じクラスならキャッシュを使い、違えば探し直してキャッシュを置き換える
+    fn find_method_cached(
+        &mut self,
+        offset: usize,
+        class: &Rc<RefCell<ClassObject>>,
+        name: &Rc<String>,
+    ) -> Option<Rc<ClosureObject>> {
+        let function = &Self::current_frame(&mut self.frames, self.frame_count)
+            .closure
+            .function;
+        if let Some(method) = self.inline_cache.get(function, offset, class) {
+            return Some(method);
+        }
+        let method = match class.borrow().find_method(name) {
+            Some(Value::Closure(method)) => method,
+            _ => return None,
+        };
+        self.inline_cache.insert(function, offset, class, &method);
+        Some(method)
+    }
+
     fn bind_method(&mut self, class: Rc<RefCell<ClassObject>>, name: Rc<String>) -> Option<()> {
-        let method = match class.borrow().methods.get(&name) {
+        let method = match class.borrow().find_method(&name) {
             Some(method) => match method {
-                Value::Closure(closure) => closure.clone(),
+                Value::Closure(closure) => closure,
                 _ => return None,
             },
             None => return None,
@@ -926,7 +2184,8 @@ impl VM {
     //     }
     // }
 
-    fn close_upvalues(&mut self) {
+    // last 以降のスタックの位置を指している上位値を閉じる。リストは位置の降順に並んでいる
+    fn close_upvalues(&mut self, last: usize) {
         let mut current_upvalue: Option<Rc<RefCell<UpvalueObject>>> = self.open_upvalue.clone();
         loop {
             match current_upvalue {
@@ -940,6 +2199,9 @@ impl VM {
                             continue;
                         }
                     };
+                    if current_upvalue_location < last {
+                        break;
+                    }
                     let current_upvalue_closed = self.stack.get(current_upvalue_location).clone();
                     current_upvalue_obj.borrow_mut().closed = Some(current_upvalue_closed);
                     current_upvalue_obj.borrow_mut().location = None;
@@ -953,6 +2215,36 @@ impl VM {
         self.open_upvalue = current_upvalue;
     }
 
+    // 位置が last 以降の開いている上位値と、last からの相対位置
+    fn open_upvalues_from(&self, last: usize) -> Vec<(usize, Rc<RefCell<UpvalueObject>>)> {
+        let mut upvalues = Vec::new();
+        let mut current_upvalue = self.open_upvalue.clone();
+        while let Some(upvalue) = current_upvalue {
+            let location = upvalue.borrow().location;
+            if let Some(location) = location {
+                if location < last {
+                    break;
+                }
+                upvalues.push((location - last, Rc::clone(&upvalue)));
+            }
+            current_upvalue = upvalue.borrow().next.clone();
+        }
+        upvalues
+    }
+
+    // ジェネレータの中断時に閉じた上位値を、再開したフレームのスロットで開き直す。
+    // 中断中にクロージャから書き換えられた値はスロットに戻す。
+    // upvalues は位置の大きい順なので、逆順に先頭へ繋げば開いている上位値の並びは保たれる
+    fn reopen_upvalues(&mut self, sp: usize, upvalues: Vec<(usize, Rc<RefCell<UpvalueObject>>)>) {
+        for (offset, upvalue) in upvalues.into_iter().rev() {
+            let value = upvalue.borrow_mut().closed.take().unwrap_or(Value::Null);
+            *self.stack.get_mut(sp + offset) = value;
+            upvalue.borrow_mut().location = Some(sp + offset);
+            upvalue.borrow_mut().next = self.open_upvalue.take();
+            self.open_upvalue = Some(upvalue);
+        }
+    }
+
     fn capture_upvalue(&mut self, index: usize) -> Rc<RefCell<UpvalueObject>> {
         let mut prev_upvalue: Option<Rc<RefCell<UpvalueObject>>> = None;
         let mut current_upvalue: Option<Rc<RefCell<UpvalueObject>>> = self.open_upvalue.clone();
@@ -1002,213 +2294,1245 @@ impl VM {
         return result;
     }
 
-    fn frame_push(&mut self, frame: CallFrame) {
-        self.frames[self.frame_count] = frame;
-        self.frame_count += 1;
+    fn frame_push(&mut self, frame: CallFrame) {
+        self.frames[self.frame_count] = frame;
+        self.frame_count += 1;
+    }
+
+    fn frame_pop(&mut self) -> &CallFrame {
+        self.frame_count -= 1;
+        slot(&self.frames, self.frame_count, self.frames.len(), "frame")
+    }
+
+    fn frame_last(&self) -> &CallFrame {
+        slot(
+            &self.frames,
+            self.frame_count - 1,
+            self.frame_count,
+            "frame",
+        )
+    }
+
+    fn frame_last_mut(&mut self) -> &mut CallFrame {
+        slot_mut(
+            &mut self.frames,
+            self.frame_count - 1,
+            self.frame_count,
+            "frame",
+        )
+    }
+
+    fn get_current_ip_mut(&mut self) -> &mut usize {
+        &mut self.frame_last_mut().ip
+    }
+
+    // 位置引数に続いて積まれた名前付き引数を、呼び出される関数の仮引数の順に並べ替える。
+    // 並べ替えた後の引数の数を返す
+    fn reorder_arguments(
+        &mut self,
+        positional: usize,
+        names: Vec<Rc<String>>,
+    ) -> Result<usize, String> {
+        let base = self.stack.len() - positional - names.len();
+        let function = match self.stack.get(base - 1) {
+            Value::Closure(closure) => Rc::clone(&closure.function),
+            Value::BoundMethod(bound) => Rc::clone(&bound.borrow().method.function),
+            Value::Class(class) => match class.borrow().find_method(&Rc::new("init".to_string())) {
+                Some(Value::Closure(init)) => Rc::clone(&init.function),
+                _ => {
+                    return Err(format!(
+                        "{}.init: named arguments are given but no init.",
+                        class.borrow().name
+                    ))
+                }
+            },
+            invalid => {
+                return Err(format!(
+                    "named arguments are not supported for {}.",
+                    invalid.type_name()
+                ))
+            }
+        };
+        if positional > function.params.len() {
+            return Err(format!(
+                "{}: expected arg_count eq {} but found {}.",
+                function.name,
+                function.arity,
+                positional + names.len()
+            ));
+        }
+
+        let mut arguments: Vec<Option<Value>> = vec![None; function.params.len()];
+        for (i, argument) in self.stack.get_slice(base).iter().enumerate() {
+            if i < positional {
+                arguments[i] = Some(argument.clone());
+                continue;
+            }
+            let name = &names[i - positional];
+            let position = match function.params.iter().position(|p| p == name.as_str()) {
+                Some(position) => position,
+                None => {
+                    return Err(format!(
+                        "{}: unknown parameter name \"{}\".",
+                        function.name, name
+                    ))
+                }
+            };
+            if arguments[position].is_some() {
+                return Err(format!(
+                    "{}: parameter \"{}\" is given more than once.",
+                    function.name, name
+                ));
+            }
+            arguments[position] = Some(argument.clone());
+        }
+
+        self.stack.set_index(base);
+        for (argument, name) in arguments.into_iter().zip(function.params.iter()) {
+            match argument {
+                Some(argument) => self.stack.push(argument),
+                None => return Err(format!("{}: missing argument \"{}\".", function.name, name)),
+            }
+        }
+        Ok(function.params.len())
+    }
+
+    fn read_constant(frame: &mut CallFrame) -> Value {
+        let index: usize = match frame.closure.function.chunk.get_instruction(frame.ip) {
+            Some(c) => *c as usize,
+            None => panic!(),
+        };
+        frame.ip += 1;
+
+        frame.closure.function.chunk.get_constant(index)
+    }
+
+    fn read_byte(frame: &mut CallFrame) -> Option<u8> {
+        let index = match frame.closure.function.chunk.get_instruction(frame.ip) {
+            Some(c) => Some(*c),
+            None => return None,
+        };
+        frame.ip += 1;
+        index
+    }
+
+    fn read_local_index(frame: &mut CallFrame) -> Option<usize> {
+        let index: usize = match frame.closure.function.chunk.get_instruction(frame.ip) {
+            Some(c) => *c as usize,
+            None => return None,
+        };
+        frame.ip += 1;
+        Some(index)
+    }
+
+    fn read_jump(frame: &mut CallFrame) -> Option<usize> {
+        let index: usize = match frame.closure.function.chunk.read_u16(frame.ip) {
+            Some(c) => c as usize,
+            None => return None,
+        };
+        frame.ip += 2;
+        Some(index)
+    }
+
+    // 現在のフレーム。self.frames だけを借用するので、スタックやグローバル変数と同時に使える
+    fn current_frame(frames: &mut [CallFrame], frame_count: usize) -> &mut CallFrame {
+        slot_mut(frames, frame_count - 1, frame_count, "frame")
+    }
+
+    fn read_string_ref(frame: &mut CallFrame) -> &Rc<String> {
+        let index = match frame.closure.function.chunk.get_instruction(frame.ip) {
+            Some(c) => *c as usize,
+            None => panic!(),
+        };
+        frame.ip += 1;
+
+        match frame.closure.function.chunk.get_constant_ref(index) {
+            Value::String(value) => value,
+            _ => panic!(),
+        }
+    }
+
+    fn read_string(frame: &mut CallFrame) -> Rc<String> {
+        match Self::read_constant(frame) {
+            Value::String(value) => value,
+            _ => panic!(),
+        }
+    }
+}
+
+// レーベンシュタイン距離
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let cost = if a_char == *b_char { 0 } else { 1 };
+            current[j + 1] = (previous[j] + cost)
+                .min(previous[j + 1] + 1)
+                .min(current[j] + 1);
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+fn range(n: &[Value]) -> Value {
+    if n.len() == 1 {
+        let stop = match &n[0] {
+            Value::Integer(v) => *v,
+            _ => panic!(),
+        };
+        let mut values: Vec<Value> = Vec::new();
+        for i in 0..stop {
+            values.push(Value::Integer(i));
+        }
+        Value::Array(Rc::new(RefCell::new(values)))
+    } else if n.len() == 2 {
+        let start = match &n[0] {
+            Value::Integer(v) => *v,
+            _ => panic!(),
+        };
+        let stop = match &n[1] {
+            Value::Integer(v) => *v,
+            _ => panic!(),
+        };
+        let mut values: Vec<Value> = Vec::new();
+        for i in start..stop {
+            values.push(Value::Integer(i));
+        }
+        Value::Array(Rc::new(RefCell::new(values)))
+    } else if n.len() == 3 {
+        let start = match &n[0] {
+            Value::Integer(v) => *v,
+            _ => panic!(),
+        };
+        let stop = match &n[1] {
+            Value::Integer(v) => *v,
+            _ => panic!(),
+        };
+        let step = match &n[2] {
+            Value::Integer(v) => *v as usize,
+            _ => panic!(),
+        };
+        let mut values: Vec<Value> = Vec::new();
+        for i in (start..stop).step_by(step) {
+            values.push(Value::Integer(i));
+        }
+        Value::Array(Rc::new(RefCell::new(values)))
+    } else {
+        Value::Null
+    }
+}
+
+fn get(n: &[Value]) -> Value {
+    if n.len() != 2 {
+        return Value::Null;
+    }
+    let array = match n[0].as_array() {
+        Some(v) => v,
+        None => panic!(),
+    };
+    if let Value::Integer(n) = n[1] {
+        match array.borrow().get(n as usize) {
+            Some(v) => return v.clone(),
+            None => return Value::Null,
+        }
+    }
+    Value::Null
+}
+
+fn last(n: &[Value]) -> Value {
+    if n.len() != 1 {
+        return Value::Null;
+    }
+    let array = match n[0].as_array() {
+        Some(v) => v,
+        None => panic!(),
+    };
+    return array.borrow().last().unwrap().clone();
+}
+
+fn first(n: &[Value]) -> Value {
+    if n.len() != 1 {
+        return Value::Null;
+    }
+    let array = match n[0].as_array() {
+        Some(v) => v,
+        None => panic!(),
+    };
+    return array.borrow().first().unwrap().clone();
+}
+
+fn rest(n: &[Value]) -> Value {
+    if n.len() != 1 {
+        return Value::Null;
+    }
+    let array = match n[0].as_array() {
+        Some(v) => v,
+        None => panic!(),
+    };
+    return Value::Array(Rc::new(RefCell::new(
+        array
+            .borrow()
+            .iter()
+            .skip(1)
+            .map(|v| v.clone())
+            .collect::<Vec<_>>(),
+    )));
+}
+
+fn str(n: &[Value]) -> Value {
+    if n.len() != 1 {
+        return Value::Null;
+    }
+    return Value::String(Rc::new(format!("{}", &n[0])));
+}
+
+// if や while と同じ基準 (null と false だけが偽) で真偽値に変換する
+fn bool(n: &[Value]) -> Value {
+    if n.len() != 1 {
+        return Value::Null;
+    }
+    Value::Boolean(!n[0].is_falsy())
+}
+
+// 右辺のインスタンスで呼ぶ演算子メソッドの名前。比較は左右を入れ替えた向きのメソッドになる
+fn reflected_operator(method: &str) -> Option<&'static str> {
+    match method {
+        "__add__" => Some("__radd__"),
+        "__sub__" => Some("__rsub__"),
+        "__mul__" => Some("__rmul__"),
+        "__div__" => Some("__rdiv__"),
+        "__mod__" => Some("__rmod__"),
+        "__pow__" => Some("__rpow__"),
+        "__lt__" => Some("__gt__"),
+        "__gt__" => Some("__lt__"),
+        "__eq__" => Some("__eq__"),
+        _ => None,
+    }
+}
+
+// 別のファイルをコンパイルし、現在のグローバル変数のもとでその場で実行する
+fn include(vm: &mut VM, n: &[Value]) -> Result<Value, String> {
+    let path = match n {
+        [Value::String(path)] => path,
+        _ => return Err("include: expected a file path string.".to_string()),
+    };
+    let source = std::fs::read_to_string(path.as_str())
+        .map_err(|e| format!("include: can't read \"{}\".({})", path, e))?;
+    let function = Compiler::compile_script(path, &source)?;
+    vm.run_script(function).map_err(|e| e.message)?;
+    Ok(Value::Null)
+}
+
+// == と同じ基準で比較し、一致しなければ実行時エラーにする
+fn assert_eq(_: &mut VM, n: &[Value]) -> Result<Value, String> {
+    let (actual, expected) = match n {
+        [actual, expected] => (actual, expected),
+        _ => {
+            return Err(format!(
+                "assert_eq: expected 2 arguments but found {}.",
+                n.len()
+            ))
+        }
+    };
+    if actual != expected {
+        return Err(format!(
+            "assert_eq failed. expected: {} ({}), actual: {} ({})",
+            expected,
+            expected.type_name(),
+            actual,
+            actual.type_name()
+        ));
+    }
+    Ok(Value::Null)
+}
+
+fn append(n: &[Value]) -> Value {
+    if n.len() < 2 {
+        return Value::Null;
+    }
+    let array = match &n[0] {
+        Value::Array(v) => (*Rc::clone(v)).clone(),
+        _ => panic!(),
+    };
+    for v in &n[1..] {
+        array.borrow_mut().push(v.clone());
+    }
+    return Value::Array(Rc::new(array));
+}
+
+fn len(n: &[Value]) -> Value {
+    if n.is_empty() {
+        return Value::Null;
+    }
+    match n[0].len() {
+        Some(len) => Value::Integer(len as i64),
+        None => Value::Null,
+    }
+}
+
+fn now(n: &[Value]) -> Value {
+    if !n.is_empty() {
+        return Value::Null;
+    }
+    Value::DateTime(LocalTime::now())
+}
+
+fn ord(n: &[Value]) -> Value {
+    if n.len() != 1 {
+        return Value::Null;
+    }
+    match &n[0] {
+        Value::String(s) => match s.chars().next() {
+            Some(c) => Value::Integer(c as i64),
+            None => Value::Null,
+        },
+        _ => Value::Null,
+    }
+}
+
+fn chr(n: &[Value]) -> Value {
+    if n.len() != 1 {
+        return Value::Null;
+    }
+    let code = match &n[0] {
+        Value::Integer(code) => *code,
+        _ => return Value::Null,
+    };
+    // サロゲートや範囲外のコードポイントは文字にできない
+    match u32::try_from(code).ok().and_then(char::from_u32) {
+        Some(c) => Value::String(Rc::new(c.to_string())),
+        None => Value::Null,
+    }
+}
+
+fn trim(n: &[Value]) -> Value {
+    if n.len() != 1 {
+        return Value::Null;
+    }
+    match &n[0] {
+        Value::String(s) => Value::String(Rc::new(s.trim().to_string())),
+        _ => Value::Null,
+    }
+}
+
+fn starts_with(n: &[Value]) -> Value {
+    if n.len() != 2 {
+        return Value::Null;
+    }
+    match (&n[0], &n[1]) {
+        (Value::String(s), Value::String(prefix)) => Value::Boolean(s.starts_with(prefix.as_str())),
+        _ => Value::Boolean(false),
+    }
+}
+
+fn ends_with(n: &[Value]) -> Value {
+    if n.len() != 2 {
+        return Value::Null;
+    }
+    match (&n[0], &n[1]) {
+        (Value::String(s), Value::String(suffix)) => Value::Boolean(s.ends_with(suffix.as_str())),
+        _ => Value::Boolean(false),
+    }
+}
+
+fn replace(n: &[Value]) -> Value {
+    if n.len() != 3 {
+        return Value::Null;
+    }
+    match (&n[0], &n[1], &n[2]) {
+        (Value::String(s), Value::String(from), Value::String(to)) => {
+            // 空文字列での置換は何もしない
+            if from.is_empty() {
+                return Value::String(Rc::clone(s));
+            }
+            Value::String(Rc::new(s.replace(from.as_str(), to.as_str())))
+        }
+        _ => Value::Null,
+    }
+}
+
+fn repeat(n: &[Value]) -> Value {
+    if n.len() != 2 {
+        return Value::Null;
+    }
+    // 負の回数は0回として扱う
+    let count = match &n[1] {
+        Value::Integer(count) => (*count).max(0) as usize,
+        _ => return Value::Null,
+    };
+    match &n[0] {
+        Value::String(s) => Value::String(Rc::new(s.repeat(count))),
+        Value::Array(array) => {
+            let array = array.borrow();
+            let mut result = Vec::with_capacity(array.len() * count);
+            for _ in 0..count {
+                result.extend(array.iter().cloned());
+            }
+            Value::Array(Rc::new(RefCell::new(result)))
+        }
+        _ => Value::Null,
+    }
+}
+
+// 引数を要素とする集合を作る。キーにできない値が含まれていれば null
+fn set(n: &[Value]) -> Value {
+    let mut set = BTreeSet::new();
+    for value in n {
+        match Key::from_value(value) {
+            Some(key) => {
+                set.insert(key);
+            }
+            None => return Value::Null,
+        }
+    }
+    Value::Set(Rc::new(RefCell::new(set)))
+}
+
+fn set_add(n: &[Value]) -> Value {
+    if n.len() != 2 {
+        return Value::Null;
+    }
+    match (&n[0], Key::from_value(&n[1])) {
+        (Value::Set(set), Some(key)) => Value::Boolean(set.borrow_mut().insert(key)),
+        _ => Value::Null,
+    }
+}
+
+fn set_has(n: &[Value]) -> Value {
+    if n.len() != 2 {
+        return Value::Null;
+    }
+    match (&n[0], Key::from_value(&n[1])) {
+        (Value::Set(set), Some(key)) => Value::Boolean(set.borrow().contains(&key)),
+        (Value::Set(_), None) => Value::Boolean(false),
+        _ => Value::Null,
+    }
+}
+
+fn set_remove(n: &[Value]) -> Value {
+    if n.len() != 2 {
+        return Value::Null;
+    }
+    match (&n[0], Key::from_value(&n[1])) {
+        (Value::Set(set), Some(key)) => Value::Boolean(set.borrow_mut().remove(&key)),
+        (Value::Set(_), None) => Value::Boolean(false),
+        _ => Value::Null,
+    }
+}
+
+// in 演算子と同じ。調べられない組み合わせは null
+fn contains(n: &[Value]) -> Value {
+    match n {
+        [container, item] => match container.contains(item) {
+            Ok(b) => Value::Boolean(b),
+            Err(_) => Value::Null,
+        },
+        _ => Value::Null,
+    }
+}
+
+// キーと値を交互に並べた引数からマップを作る。同じキーは後の値で上書きするが、順序は最初の位置のまま。
+// 引数が奇数個の場合やキーにできない値が含まれていれば null
+fn map_of(n: &[Value]) -> Value {
+    if !n.len().is_multiple_of(2) {
+        return Value::Null;
+    }
+    let mut map = OrderedMap::new();
+    for pair in n.chunks(2) {
+        match Key::from_value(&pair[0]) {
+            Some(key) => {
+                map.insert(key, pair[1].clone());
+            }
+            None => return Value::Null,
+        }
+    }
+    Value::Map(Rc::new(RefCell::new(map)))
+}
+
+// キーがなければ3つ目の引数 (省略時は null) を返す
+fn map_get(n: &[Value]) -> Value {
+    let (map, key, default) = match n {
+        [Value::Map(map), key] => (map, key, Value::Null),
+        [Value::Map(map), key, default] => (map, key, default.clone()),
+        _ => return Value::Null,
+    };
+    match Key::from_value(key) {
+        Some(key) => map.borrow().get(&key).cloned().unwrap_or(default),
+        None => default,
+    }
+}
+
+// 前の値を返す。新しいキーなら null
+fn map_set(n: &[Value]) -> Value {
+    match n {
+        [Value::Map(map), key, value] => match Key::from_value(key) {
+            Some(key) => map
+                .borrow_mut()
+                .insert(key, value.clone())
+                .unwrap_or(Value::Null),
+            None => Value::Null,
+        },
+        _ => Value::Null,
+    }
+}
+
+fn map_has(n: &[Value]) -> Value {
+    match n {
+        [Value::Map(map), key] => match Key::from_value(key) {
+            Some(key) => Value::Boolean(map.borrow().contains_key(&key)),
+            None => Value::Boolean(false),
+        },
+        _ => Value::Null,
+    }
+}
+
+// 取り除いた値を返す。キーがなければ null
+fn map_remove(n: &[Value]) -> Value {
+    match n {
+        [Value::Map(map), key] => match Key::from_value(key) {
+            Some(key) => map.borrow_mut().remove(&key).unwrap_or(Value::Null),
+            None => Value::Null,
+        },
+        _ => Value::Null,
+    }
+}
+
+// キーを追加した順に並べた配列
+fn keys(n: &[Value]) -> Value {
+    match n {
+        [Value::Map(map)] => Value::Array(Rc::new(RefCell::new(
+            map.borrow().iter().map(|(key, _)| key.to_value()).collect(),
+        ))),
+        _ => Value::Null,
+    }
+}
+
+// keys と同じ順に並べた値の配列
+fn values(n: &[Value]) -> Value {
+    match n {
+        [Value::Map(map)] => Value::Array(Rc::new(RefCell::new(
+            map.borrow()
+                .iter()
+                .map(|(_, value)| value.clone())
+                .collect(),
+        ))),
+        _ => Value::Null,
+    }
+}
+
+// 配列・集合・マップ・インスタンスの1段目だけをコピーする
+fn copy(n: &[Value]) -> Value {
+    if n.len() != 1 {
+        return Value::Null;
+    }
+    match &n[0] {
+        Value::Array(array) => Value::Array(Rc::new(RefCell::new(array.borrow().clone()))),
+        Value::Set(set) => Value::Set(Rc::new(RefCell::new(set.borrow().clone()))),
+        Value::Map(map) => Value::Map(Rc::new(RefCell::new(map.borrow().clone()))),
+        Value::Bytes(bytes) => Value::Bytes(Rc::new(RefCell::new(bytes.borrow().clone()))),
+        Value::Instance(instance) => {
+            Value::Instance(Rc::new(RefCell::new(instance.borrow().clone())))
+        }
+        other => other.clone(),
+    }
+}
+
+fn deep_copy(n: &[Value]) -> Value {
+    if n.len() != 1 {
+        return Value::Null;
+    }
+    deep_copy_value(&n[0], &mut HashMap::new())
+}
+
+// copied はコピー元のアドレスからコピー先への対応。
+// 循環参照や共有されている値は、同じコピー先を指すようにする
+fn deep_copy_value(value: &Value, copied: &mut HashMap<usize, Value>) -> Value {
+    match value {
+        Value::Array(array) => {
+            let address = Rc::as_ptr(array) as usize;
+            if let Some(copy) = copied.get(&address) {
+                return copy.clone();
+            }
+            let copy = Rc::new(RefCell::new(Vec::with_capacity(array.borrow().len())));
+            copied.insert(address, Value::Array(Rc::clone(&copy)));
+            for element in array.borrow().iter() {
+                let element = deep_copy_value(element, copied);
+                copy.borrow_mut().push(element);
+            }
+            Value::Array(copy)
+        }
+        Value::Map(map) => {
+            let address = Rc::as_ptr(map) as usize;
+            if let Some(copy) = copied.get(&address) {
+                return copy.clone();
+            }
+            let copy = Rc::new(RefCell::new(OrderedMap::new()));
+            copied.insert(address, Value::Map(Rc::clone(&copy)));
+            for (key, value) in map.borrow().iter() {
+                let value = deep_copy_value(value, copied);
+                copy.borrow_mut().insert(key.clone(), value);
+            }
+            Value::Map(copy)
+        }
+        Value::Instance(instance) => {
+            let address = Rc::as_ptr(instance) as usize;
+            if let Some(copy) = copied.get(&address) {
+                return copy.clone();
+            }
+            let copy = Rc::new(RefCell::new(InstanceObject::new(
+                instance.borrow().class.clone(),
+            )));
+            copied.insert(address, Value::Instance(Rc::clone(&copy)));
+            for (name, field) in instance.borrow().fields.iter() {
+                let field = deep_copy_value(field, copied);
+                copy.borrow_mut().fields.insert(Rc::clone(name), field);
+            }
+            Value::Instance(copy)
+        }
+        // 集合の要素は変更できない値だけなので、そのままコピーすればよい
+        Value::Set(set) => Value::Set(Rc::new(RefCell::new(set.borrow().clone()))),
+        Value::Bytes(bytes) => Value::Bytes(Rc::new(RefCell::new(bytes.borrow().clone()))),
+        other => other.clone(),
+    }
+}
+
+fn enumerate(n: &[Value]) -> Value {
+    if n.len() != 1 {
+        return Value::Null;
+    }
+    let array = match &n[0] {
+        Value::Array(array) => array,
+        _ => return Value::Null,
+    };
+    let pairs = array
+        .borrow()
+        .iter()
+        .enumerate()
+        .map(|(i, v)| {
+            Value::Array(Rc::new(RefCell::new(vec![
+                Value::Integer(i as i64),
+                v.clone(),
+            ])))
+        })
+        .collect::<Vec<_>>();
+    Value::Array(Rc::new(RefCell::new(pairs)))
+}
+
+// 短い方の長さに合わせる
+fn zip(n: &[Value]) -> Value {
+    if n.len() != 2 {
+        return Value::Null;
+    }
+    let (a, b) = match (&n[0], &n[1]) {
+        (Value::Array(a), Value::Array(b)) => (a, b),
+        _ => return Value::Null,
+    };
+    let pairs = a
+        .borrow()
+        .iter()
+        .zip(b.borrow().iter())
+        .map(|(a, b)| Value::Array(Rc::new(RefCell::new(vec![a.clone(), b.clone()]))))
+        .collect::<Vec<_>>();
+    Value::Array(Rc::new(RefCell::new(pairs)))
+}
+
+// 負の個数は空の配列になる
+fn fill(n: &[Value]) -> Value {
+    if n.len() != 2 {
+        return Value::Null;
+    }
+    let count = match &n[0] {
+        Value::Integer(count) => (*count).max(0) as usize,
+        _ => return Value::Null,
+    };
+    Value::Array(Rc::new(RefCell::new(vec![n[1].clone(); count])))
+}
+
+// [start, end) の範囲をコピーした配列を返す。
+// start と end は 0 から配列の長さの範囲に丸め、start が end 以上なら空の配列になる
+fn slice(n: &[Value]) -> Value {
+    if n.len() != 3 {
+        return Value::Null;
+    }
+    let (array, start, end) = match (&n[0], &n[1], &n[2]) {
+        (Value::Array(array), Value::Integer(start), Value::Integer(end)) => (array, start, end),
+        _ => return Value::Null,
+    };
+    let array = array.borrow();
+    let len = array.len() as i64;
+    let start = (*start).clamp(0, len) as usize;
+    let end = (*end).clamp(0, len) as usize;
+    let values = if start < end {
+        array[start..end].to_vec()
+    } else {
+        Vec::new()
+    };
+    Value::Array(Rc::new(RefCell::new(values)))
+}
+
+// 整数は整数のまま、浮動小数点数は浮動小数点数のまま返す
+fn abs(n: &[Value]) -> Value {
+    if n.len() != 1 {
+        return Value::Null;
+    }
+    match &n[0] {
+        Value::Integer(i) => Value::Integer(i.wrapping_abs()),
+        Value::Float(f) => Value::Float(f.abs()),
+        _ => Value::Null,
+    }
+}
+
+// 負なら -1、0 なら 0、正なら 1 を整数で返す
+fn sign(n: &[Value]) -> Value {
+    if n.len() != 1 {
+        return Value::Null;
     }
+    match &n[0] {
+        Value::Integer(i) => Value::Integer(i.signum()),
+        Value::Float(f) if f.is_nan() => Value::Null,
+        Value::Float(f) if *f == 0.0 => Value::Integer(0),
+        Value::Float(f) => Value::Integer(f.signum() as i64),
+        _ => Value::Null,
+    }
+}
 
-    fn frame_pop(&mut self) -> &CallFrame {
-        self.frame_count -= 1;
-        unsafe { self.frames.get_unchecked(self.frame_count) }
+// 整数は浮動小数点数に変換する。数値以外は None
+fn to_float(value: &Value) -> Option<f64> {
+    match value {
+        Value::Integer(i) => Some(*i as f64),
+        Value::Float(f) => Some(*f),
+        _ => None,
     }
+}
 
-    fn frame_last(&self) -> &CallFrame {
-        unsafe { self.frames.get_unchecked(self.frame_count - 1) }
+fn sin(n: &[Value]) -> Value {
+    if n.len() != 1 {
+        return Value::Null;
     }
+    match to_float(&n[0]) {
+        Some(x) => Value::Float(x.sin()),
+        None => Value::Null,
+    }
+}
 
-    fn frame_last_mut(&mut self) -> &mut CallFrame {
-        unsafe { self.frames.get_unchecked_mut(self.frame_count - 1) }
+fn cos(n: &[Value]) -> Value {
+    if n.len() != 1 {
+        return Value::Null;
+    }
+    match to_float(&n[0]) {
+        Some(x) => Value::Float(x.cos()),
+        None => Value::Null,
     }
+}
 
-    fn get_current_ip(&self) -> usize {
-        self.frame_last().ip
+fn tan(n: &[Value]) -> Value {
+    if n.len() != 1 {
+        return Value::Null;
+    }
+    match to_float(&n[0]) {
+        Some(x) => Value::Float(x.tan()),
+        None => Value::Null,
     }
+}
 
-    fn get_current_ip_mut(&mut self) -> &mut usize {
-        &mut self.frame_last_mut().ip
+fn atan2(n: &[Value]) -> Value {
+    if n.len() != 2 {
+        return Value::Null;
+    }
+    match (to_float(&n[0]), to_float(&n[1])) {
+        (Some(y), Some(x)) => Value::Float(y.atan2(x)),
+        _ => Value::Null,
     }
+}
 
-    fn read_constant(frame: &mut CallFrame) -> Value {
-        let index: usize = match frame.closure.function.chunk.get_instruction(frame.ip) {
-            Some(c) => *c as usize,
-            None => panic!(),
-        };
-        frame.ip += 1;
+fn exp(n: &[Value]) -> Value {
+    if n.len() != 1 {
+        return Value::Null;
+    }
+    match to_float(&n[0]) {
+        Some(x) => Value::Float(x.exp()),
+        None => Value::Null,
+    }
+}
 
-        frame.closure.function.chunk.get_constant(index)
+// 定義域外 (0 以下の数や 1 の底) は NaN ではなく null を返す
+fn log(n: &[Value]) -> Value {
+    let x = match n.first().and_then(to_float) {
+        Some(x) if x > 0.0 => x,
+        _ => return Value::Null,
+    };
+    match n.len() {
+        1 => Value::Float(x.ln()),
+        2 => match to_float(&n[1]) {
+            Some(base) if base > 0.0 && base != 1.0 => Value::Float(x.log(base)),
+            _ => Value::Null,
+        },
+        _ => Value::Null,
     }
+}
 
-    fn read_byte(frame: &mut CallFrame) -> Option<u8> {
-        let index = match frame.closure.function.chunk.get_instruction(frame.ip) {
-            Some(c) => Some(*c),
-            None => return None,
-        };
-        frame.ip += 1;
-        index
+fn log2(n: &[Value]) -> Value {
+    if n.len() != 1 {
+        return Value::Null;
     }
+    match to_float(&n[0]) {
+        Some(x) if x > 0.0 => Value::Float(x.log2()),
+        _ => Value::Null,
+    }
+}
 
-    fn read_local_index(frame: &mut CallFrame) -> Option<usize> {
-        let index: usize = match frame.closure.function.chunk.get_instruction(frame.ip) {
-            Some(c) => *c as usize,
-            None => return None,
-        };
-        frame.ip += 1;
-        Some(index)
+fn log10(n: &[Value]) -> Value {
+    if n.len() != 1 {
+        return Value::Null;
+    }
+    match to_float(&n[0]) {
+        Some(x) if x > 0.0 => Value::Float(x.log10()),
+        _ => Value::Null,
     }
+}
 
-    fn read_jump(frame: &mut CallFrame) -> Option<usize> {
-        let index: usize = match frame.closure.function.chunk.read_u16(frame.ip) {
-            Some(c) => c as usize,
-            None => return None,
-        };
-        frame.ip += 2;
-        Some(index)
+// 負の数は "-0xff" のように符号を先頭に付ける
+fn to_hex(n: &[Value]) -> Value {
+    if n.len() != 1 {
+        return Value::Null;
     }
+    match &n[0] {
+        Value::Integer(i) if *i < 0 => Value::String(Rc::new(format!("-0x{:x}", i.unsigned_abs()))),
+        Value::Integer(i) => Value::String(Rc::new(format!("0x{:x}", i))),
+        _ => Value::Null,
+    }
+}
 
-    fn read_string(frame: &mut CallFrame) -> Rc<String> {
-        match Self::read_constant(frame) {
-            Value::String(value) => value,
-            _ => panic!(),
-        }
+fn to_binary(n: &[Value]) -> Value {
+    if n.len() != 1 {
+        return Value::Null;
+    }
+    match &n[0] {
+        Value::Integer(i) if *i < 0 => Value::String(Rc::new(format!("-0b{:b}", i.unsigned_abs()))),
+        Value::Integer(i) => Value::String(Rc::new(format!("0b{:b}", i))),
+        _ => Value::Null,
     }
 }
 
-fn range(n: &[Value]) -> Value {
-    if n.len() == 1 {
-        let stop = match &n[0] {
-            Value::Integer(v) => *v,
-            _ => panic!(),
-        };
-        let mut values: Vec<Value> = Vec::new();
-        for i in 0..stop {
-            values.push(Value::Integer(i));
-        }
-        Value::Array(Rc::new(RefCell::new(values)))
-    } else if n.len() == 2 {
-        let start = match &n[0] {
-            Value::Integer(v) => *v,
-            _ => panic!(),
-        };
-        let stop = match &n[1] {
-            Value::Integer(v) => *v,
-            _ => panic!(),
-        };
-        let mut values: Vec<Value> = Vec::new();
-        for i in start..stop {
-            values.push(Value::Integer(i));
-        }
-        Value::Array(Rc::new(RefCell::new(values)))
-    } else if n.len() == 3 {
-        let start = match &n[0] {
-            Value::Integer(v) => *v,
-            _ => panic!(),
-        };
-        let stop = match &n[1] {
-            Value::Integer(v) => *v,
-            _ => panic!(),
-        };
-        let step = match &n[2] {
-            Value::Integer(v) => *v as usize,
-            _ => panic!(),
-        };
-        let mut values: Vec<Value> = Vec::new();
-        for i in (start..stop).step_by(step) {
-            values.push(Value::Integer(i));
-        }
-        Value::Array(Rc::new(RefCell::new(values)))
-    } else {
-        Value::Null
+// 文字列の UTF-8 のバイト列
+fn to_bytes(n: &[Value]) -> Value {
+    match n {
+        [Value::String(s)] => Value::Bytes(Rc::new(RefCell::new(s.as_bytes().to_vec()))),
+        _ => Value::Null,
     }
 }
 
-fn get(n: &[Value]) -> Value {
-    if n.len() != 2 {
-        return Value::Null;
+// UTF-8 として正しくないバイト列は null
+fn from_bytes(n: &[Value]) -> Value {
+    match n {
+        [Value::Bytes(bytes)] => match String::from_utf8(bytes.borrow().clone()) {
+            Ok(s) => Value::String(Rc::new(s)),
+            Err(_) => Value::Null,
+        },
+        _ => Value::Null,
     }
-    let array = match &n[0] {
-        Value::Array(v) => v,
-        _ => panic!(),
+}
+
+const BASE64_TABLE: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+// 文字列は UTF-8 のバイト列としてエンコードする
+fn base64_encode(n: &[Value]) -> Value {
+    let bytes = match n {
+        [Value::String(s)] => s.as_bytes().to_vec(),
+        [Value::Bytes(bytes)] => bytes.borrow().clone(),
+        _ => return Value::Null,
     };
-    if let Value::Integer(n) = n[1] {
-        match array.borrow().get(n as usize) {
-            Some(v) => return v.clone(),
-            None => return Value::Null,
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let b = [
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(BASE64_TABLE[(n >> (18 - i * 6)) as usize & 0x3f] as char);
+            } else {
+                encoded.push('=');
+            }
         }
     }
-    Value::Null
+    Value::String(Rc::new(encoded))
 }
 
-fn last(n: &[Value]) -> Value {
-    if n.len() != 1 {
+// デコード結果はバイト列。正しくない入力は null
+fn base64_decode(n: &[Value]) -> Value {
+    let s = match n {
+        [Value::String(s)] => s.trim_end_matches('='),
+        _ => return Value::Null,
+    };
+    if !n[0].len().unwrap_or(0).is_multiple_of(4) || s.len() % 4 == 1 {
         return Value::Null;
     }
-    let array = match &n[0] {
-        Value::Array(v) => v,
-        _ => panic!(),
+    let mut decoded = Vec::with_capacity(s.len() / 4 * 3);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for c in s.bytes() {
+        let v = match BASE64_TABLE.iter().position(|b| *b == c) {
+            Some(v) => v as u32,
+            None => return Value::Null,
+        };
+        buffer = buffer << 6 | v;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+    Value::Bytes(Rc::new(RefCell::new(decoded)))
+}
+
+// 文字列は UTF-8 のバイト列としてハッシュ値を計算し、16 進数の文字列で返す
+#[cfg(feature = "hash")]
+fn digest<D: sha2::Digest>(n: &[Value]) -> Value {
+    let digest = match n {
+        [Value::String(s)] => D::digest(s.as_bytes()),
+        [Value::Bytes(bytes)] => D::digest(bytes.borrow().as_slice()),
+        _ => return Value::Null,
     };
-    return array.borrow().last().unwrap().clone();
+    Value::String(Rc::new(
+        digest.iter().map(|b| format!("{:02x}", b)).collect(),
+    ))
 }
 
-fn first(n: &[Value]) -> Value {
-    if n.len() != 1 {
-        return Value::Null;
+#[cfg(feature = "hash")]
+fn sha256(n: &[Value]) -> Value {
+    digest::<sha2::Sha256>(n)
+}
+
+#[cfg(feature = "hash")]
+fn md5(n: &[Value]) -> Value {
+    digest::<md5::Md5>(n)
+}
+
+// パターンが正しくなければ null
+#[cfg(feature = "regex")]
+fn regex_match(n: &[Value]) -> Value {
+    match n {
+        [Value::String(s), Value::String(pattern)] => match regex::Regex::new(pattern) {
+            Ok(re) => Value::Boolean(re.is_match(s)),
+            Err(_) => Value::Null,
+        },
+        _ => Value::Null,
     }
-    let array = match &n[0] {
-        Value::Array(v) => v,
-        _ => panic!(),
+}
+
+// 一致した部分をすべて置き換える。repl の中では $1 などでキャプチャを参照できる
+#[cfg(feature = "regex")]
+fn regex_replace(n: &[Value]) -> Value {
+    match n {
+        [Value::String(s), Value::String(pattern), Value::String(repl)] => {
+            match regex::Regex::new(pattern) {
+                Ok(re) => Value::String(Rc::new(re.replace_all(s, repl.as_str()).into_owned())),
+                Err(_) => Value::Null,
+            }
+        }
+        _ => Value::Null,
+    }
+}
+
+// 文字数が width になるまで fill の先頭の文字で埋める。left なら左側を埋める
+fn pad(n: &[Value], left: bool) -> Value {
+    let (s, width, fill) = match n {
+        [Value::String(s), Value::Integer(width), Value::String(fill)] => (s, *width, fill),
+        _ => return Value::Null,
     };
-    return array.borrow().first().unwrap().clone();
+    let fill = match fill.chars().next() {
+        Some(c) => c,
+        None => return Value::Null,
+    };
+    let len = s.chars().count();
+    if width <= len as i64 {
+        return Value::String(Rc::clone(s));
+    }
+    let padding = fill.to_string().repeat(width as usize - len);
+    if left {
+        Value::String(Rc::new(padding + s))
+    } else {
+        Value::String(Rc::new(s.to_string() + &padding))
+    }
 }
 
-fn rest(n: &[Value]) -> Value {
-    if n.len() != 1 {
-        return Value::Null;
+fn pad_left(n: &[Value]) -> Value {
+    pad(n, true)
+}
+
+fn pad_right(n: &[Value]) -> Value {
+    pad(n, false)
+}
+
+// 添字はバイトではなく文字単位
+fn char_at(n: &[Value]) -> Value {
+    match n {
+        [Value::String(s), Value::Integer(i)] if *i >= 0 => match s.chars().nth(*i as usize) {
+            Some(c) => Value::String(Rc::new(c.to_string())),
+            None => Value::Null,
+        },
+        _ => Value::Null,
     }
-    let array = match &n[0] {
-        Value::Array(v) => v,
-        _ => panic!(),
+}
+
+fn chars(n: &[Value]) -> Value {
+    match n {
+        [Value::String(s)] => Value::Array(Rc::new(RefCell::new(
+            s.chars()
+                .map(|c| Value::String(Rc::new(c.to_string())))
+                .collect(),
+        ))),
+        _ => Value::Null,
+    }
+}
+
+// print より詳しい表示。文字列は引用符付きで、インスタンスはフィールドも表示する
+fn repr(n: &[Value]) -> Value {
+    match n {
+        [value] => Value::String(Rc::new(value.repr())),
+        _ => Value::Null,
+    }
+}
+
+// 比べられるのは数値同士、文字列同士、日時同士だけ。整数と小数は数値として比べる
+fn compare(a: &Value, b: &Value) -> Option<Ordering> {
+    match (a, b) {
+        (Value::Integer(_), Value::Integer(_))
+        | (Value::Float(_), Value::Float(_))
+        | (Value::String(_), Value::String(_))
+        | (Value::DateTime(_), Value::DateTime(_)) => a.partial_cmp(b),
+        (Value::Integer(a), Value::Float(b)) => (*a as f64).partial_cmp(b),
+        (Value::Float(a), Value::Integer(b)) => a.partial_cmp(&(*b as f64)),
+        _ => None,
+    }
+}
+
+// 引数の中で一番 ordering 側にある値。配列を1つだけ渡した場合はその要素から選ぶ。
+// 比べられない組み合わせが混ざっている場合は null
+fn extreme(n: &[Value], ordering: Ordering) -> Value {
+    let values = match n {
+        [Value::Array(array)] => array.borrow().clone(),
+        _ => n.to_vec(),
     };
-    return Value::Array(Rc::new(RefCell::new(
-        array
-            .borrow()
-            .iter()
-            .skip(1)
-            .map(|v| v.clone())
-            .collect::<Vec<_>>(),
-    )));
+    let mut values = values.into_iter();
+    let mut result = match values.next() {
+        Some(value) => value,
+        None => return Value::Null,
+    };
+    for value in values {
+        match compare(&value, &result) {
+            Some(o) if o == ordering => result = value,
+            Some(_) => {}
+            None => return Value::Null,
+        }
+    }
+    // 1つだけの場合も比べられる値かどうかを確かめる
+    match compare(&result, &result) {
+        Some(_) => result,
+        None => Value::Null,
+    }
 }
 
-fn str(n: &[Value]) -> Value {
-    if n.len() != 1 {
-        return Value::Null;
+fn min(n: &[Value]) -> Value {
+    extreme(n, Ordering::Less)
+}
+
+fn max(n: &[Value]) -> Value {
+    extreme(n, Ordering::Greater)
+}
+
+// 整数は 64 ビットの 2 の補数として数える。負の数は符号ビットも 1 になる
+fn popcount(n: &[Value]) -> Value {
+    match n {
+        [Value::Integer(i)] => Value::Integer(i.count_ones() as i64),
+        _ => Value::Null,
     }
-    return Value::String(Rc::new(format!("{}", &n[0])));
 }
 
-fn append(n: &[Value]) -> Value {
-    if n.len() < 2 {
-        return Value::Null;
+fn leading_zeros(n: &[Value]) -> Value {
+    match n {
+        [Value::Integer(i)] => Value::Integer(i.leading_zeros() as i64),
+        _ => Value::Null,
     }
-    let array = match &n[0] {
-        Value::Array(v) => (*Rc::clone(v)).clone(),
-        _ => panic!(),
+}
+
+// 0 と負の数は false
+fn is_power_of_two(n: &[Value]) -> Value {
+    match n {
+        [Value::Integer(i)] => Value::Boolean(*i > 0 && (*i as u64).is_power_of_two()),
+        _ => Value::Null,
+    }
+}
+
+// 小数点以下を digits 桁にした文字列。digits は 0 から 100 まで
+fn fixed_string(n: &[Value]) -> Option<String> {
+    let (x, digits) = match n {
+        [Value::Integer(x), Value::Integer(digits)] => (*x as f64, *digits),
+        [Value::Float(x), Value::Integer(digits)] => (*x, *digits),
+        _ => return None,
     };
-    for v in &n[1..] {
-        array.borrow_mut().push(v.clone());
+    if !(0..=100).contains(&digits) {
+        return None;
     }
-    return Value::Array(Rc::new(array));
+    Some(format!("{:.*}", digits as usize, x))
 }
 
-fn len(n: &[Value]) -> Value {
-    if n.is_empty() {
-        return Value::Null;
+fn fixed(n: &[Value]) -> Value {
+    match fixed_string(n) {
+        Some(s) => Value::String(Rc::new(s)),
+        None => Value::Null,
     }
-    if let Value::Array(n) = &n[0] {
-        return Value::Integer(n.borrow().len() as i64);
+}
+
+// fixed と同じ丸め方にするため、文字列にしてから読み直す
+fn round_to(n: &[Value]) -> Value {
+    match fixed_string(n).and_then(|s| s.parse::<f64>().ok()) {
+        Some(x) => Value::Float(x),
+        None => Value::Null,
     }
-    return Value::Null;
 }
 
-fn now(_: &[Value]) -> Value {
-    Value::DateTime(LocalTime::now())
+// parse_int(s) は10進数、parse_int(s, radix) は 2〜36 進数として読む。
+// to_hex / to_binary の出力を読み戻せるよう、基数に合う "0x" "0o" "0b" の接頭辞は読み飛ばす
+fn parse_int(n: &[Value]) -> Value {
+    let (s, radix) = match n {
+        [Value::String(s)] => (s, 10),
+        [Value::String(s), Value::Integer(radix)] if (2..=36).contains(radix) => (s, *radix as u32),
+        _ => return Value::Null,
+    };
+    let s = s.trim();
+    let (negative, digits) = match s.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, s.strip_prefix('+').unwrap_or(s)),
+    };
+    let prefix = match radix {
+        16 => Some("0x"),
+        8 => Some("0o"),
+        2 => Some("0b"),
+        _ => None,
+    };
+    let digits = match prefix {
+        Some(prefix) => digits.strip_prefix(prefix).unwrap_or(digits),
+        None => digits,
+    };
+    // 符号は上で取り除いたので、数字以外の文字が残っていれば失敗にする
+    if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+        return Value::Null;
+    }
+    let value = if negative {
+        i64::from_str_radix(&format!("-{}", digits), radix)
+    } else {
+        i64::from_str_radix(digits, radix)
+    };
+    match value {
+        Ok(value) => Value::Integer(value),
+        Err(_) => Value::Null,
+    }
 }
@@ -0,0 +1,44 @@
+use super::{frame::CallFrame, FRAME_MAX, STACK_MAX, VM};
+
+// 上限を指定して VM を作る。指定しなかった項目は VM::new と同じ値になる
+pub struct VMBuilder {
+    frame_max: usize,
+    stack_max: usize,
+    instruction_limit: Option<usize>,
+}
+
+impl VMBuilder {
+    pub fn new() -> Self {
+        Self {
+            frame_max: FRAME_MAX,
+            stack_max: STACK_MAX,
+            instruction_limit: None,
+        }
+    }
+
+    // 呼び出しの深さの上限。超えると実行時エラーになる
+    pub fn frame_max(mut self, frame_max: usize) -> Self {
+        self.frame_max = frame_max;
+        self
+    }
+
+    // 値スタックに積める値の数。ローカル変数と計算途中の値が全ての呼び出しで共有する
+    pub fn stack_max(mut self, stack_max: usize) -> Self {
+        self.stack_max = stack_max;
+        self
+    }
+
+    // VM::set_instruction_limit と同じ
+    pub fn instruction_limit(mut self, limit: usize) -> Self {
+        self.instruction_limit = Some(limit);
+        self
+    }
+
+    pub fn build(self, frame: CallFrame) -> VM {
+        let mut vm = VM::with_limits(frame, self.frame_max, self.stack_max);
+        if let Some(limit) = self.instruction_limit {
+            vm.set_instruction_limit(limit);
+        }
+        vm
+    }
+}
@@ -0,0 +1,91 @@
+use crate::compiler::object::{ClassObject, ClosureObject, FunctionObject};
+use rustc_hash::FxHashMap;
+use std::{
+    cell::RefCell,
+    rc::{Rc, Weak},
+};
+
+// メソッド呼び出しの位置ごとに、直前に見たクラスと見つかったメソッドを覚えておく (単相インラインキャッシュ)。
+// 同じ位置で同じクラスが続く間は、クラスとスーパークラスのメソッド表を引かずに済む
+pub struct InlineCache {
+    // 呼び出し位置は (関数のアドレス, 命令の位置)
+    entries: FxHashMap<(usize, usize), Entry>,
+    // エントリがこの数に達したら、解放済みの関数やクラスのエントリを取り除く
+    sweep_at: usize,
+}
+
+// 掃除を始めるエントリ数の最小値
+const SWEEP_MIN: usize = 256;
+
+// 関数とクラスは Weak で持つ。Weak が残っている間はアドレスが再利用されないので、
+// アドレスの比較だけで同じものかどうかを判定できる。循環参照にもならない
+struct Entry {
+    function: Weak<FunctionObject>,
+    class: Weak<RefCell<ClassObject>>,
+    method: Weak<ClosureObject>,
+}
+
+impl Entry {
+    // 関数、クラス、メソッドのどれかが解放されたエントリは二度と使われない
+    fn is_alive(&self) -> bool {
+        self.function.strong_count() > 0
+            && self.class.strong_count() > 0
+            && self.method.strong_count() > 0
+    }
+}
+
+impl InlineCache {
+    pub fn new() -> Self {
+        Self {
+            entries: FxHashMap::default(),
+            sweep_at: SWEEP_MIN,
+        }
+    }
+
+    // 呼び出し位置で前回と同じクラスならメソッドを返す
+    pub fn get(
+        &self,
+        function: &Rc<FunctionObject>,
+        offset: usize,
+        class: &Rc<RefCell<ClassObject>>,
+    ) -> Option<Rc<ClosureObject>> {
+        let entry = self.entries.get(&(Rc::as_ptr(function) as usize, offset))?;
+        if entry.class.as_ptr() != Rc::as_ptr(class) {
+            return None;
+        }
+        entry.method.upgrade()
+    }
+
+    // 前回と違うクラスだった場合は上書きする
+    pub fn insert(
+        &mut self,
+        function: &Rc<FunctionObject>,
+        offset: usize,
+        class: &Rc<RefCell<ClassObject>>,
+        method: &Rc<ClosureObject>,
+    ) {
+        if self.entries.len() >= self.sweep_at {
+            self.sweep();
+        }
+        self.entries.insert(
+            (Rc::as_ptr(function) as usize, offset),
+            Entry {
+                function: Rc::downgrade(function),
+                class: Rc::downgrade(class),
+                method: Rc::downgrade(method),
+            },
+        );
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.sweep_at = SWEEP_MIN;
+    }
+
+    // 解放済みのエントリを取り除く。Weak を手放すので、そのアドレスは再利用できるようになる。
+    // 残ったエントリの2倍になるまでは次の掃除をしない
+    fn sweep(&mut self) {
+        self.entries.retain(|_, entry| entry.is_alive());
+        self.sweep_at = (self.entries.len() * 2).max(SWEEP_MIN);
+    }
+}
@@ -1,4 +1,5 @@
-use super::value::{Value, ConstantArray};
+use super::value::{ConstantArray, Value};
+use std::fmt::{self, Write};
 
 pub const OP_RETURN: u8 = 0x00;
 pub const OP_CONSTANT: u8 = 0x01;
@@ -46,12 +47,83 @@ pub const OP_CONSTANT0: u8 = 0x2A;
 pub const OP_JUMP_IF_RANGE_END: u8 = 0x2B;
 pub const OP_COUNTUP: u8 = 0x2C;
 pub const OP_RANGE: u8 = 0x2D;
+pub const OP_GET_ITER: u8 = 0x2E;
+pub const OP_YIELD: u8 = 0x2F;
+pub const OP_ARRAY_PUSH: u8 = 0x30;
+pub const OP_CALL_NAMED: u8 = 0x31;
+// 0 から 255 までの整数を定数表を使わずに積む。被演算子がそのまま値になる
+pub const OP_CONSTANT_SMALL: u8 = 0x32;
+// x in container。container に x が含まれるかどうかを積む
+pub const OP_CONTAINS: u8 = 0x33;
+
+// 命令コードに対応する名前。逆アセンブルやプロファイラの出力で使う。
+pub fn opcode_name(opcode: u8) -> Option<&'static str> {
+    let name = match opcode {
+        OP_RETURN => "OP_RETURN",
+        OP_CONSTANT => "OP_CONSTANT",
+        OP_NEGATIVE => "OP_NEGATIVE",
+        OP_ADD => "OP_ADD",
+        OP_SUBTRACT => "OP_SUBTRACT",
+        OP_MULTIPLY => "OP_MULTIPLY",
+        OP_DIVIDE => "OP_DIVIDE",
+        OP_TRUE => "OP_TRUE",
+        OP_FALSE => "OP_FALSE",
+        OP_NULL => "OP_NULL",
+        OP_NOT => "OP_NOT",
+        OP_GREATER => "OP_GREATER",
+        OP_LESS => "OP_LESS",
+        OP_EQUAL => "OP_EQUAL",
+        OP_PRINT => "OP_PRINT",
+        OP_POP => "OP_POP",
+        OP_DEFINE_GLOBAL => "OP_DEFINE_GLOBAL",
+        OP_GET_GLOBAL => "OP_GET_GLOBAL",
+        OP_SET_GLOBAL => "OP_SET_GLOBAL",
+        OP_GET_LOCAL => "OP_GET_LOCAL",
+        OP_SET_LOCAL => "OP_SET_LOCAL",
+        OP_JUMP_IF_FALSE => "OP_JUMP_IF_FALSE",
+        OP_JUMP => "OP_JUMP",
+        OP_LOOP => "OP_LOOP",
+        OP_CALL => "OP_CALL",
+        OP_ARRAY => "OP_ARRAY",
+        OP_INDEX_CALL => "OP_INDEX_CALL",
+        OP_REM => "OP_REM",
+        OP_POW => "OP_POW",
+        OP_CLOSURE => "OP_CLOSURE",
+        OP_CLOSE_UPVALUE => "OP_CLOSE_UPVALUE",
+        OP_GET_UPVALUE => "OP_GET_UPVALUE",
+        OP_SET_UPVALUE => "OP_SET_UPVALUE",
+        OP_CLASS => "OP_CLASS",
+        OP_GET_PROP => "OP_GET_PROP",
+        OP_SET_PROP => "OP_SET_PROP",
+        OP_METHOD => "OP_METHOD",
+        OP_INVOKE => "OP_INVOKE",
+        OP_INHERIT => "OP_INHERIT",
+        OP_SUPER_INVOKE => "OP_SUPER_INVOKE",
+        OP_GET_SUPER => "OP_SUPER_GET_PROP",
+        OP_INDEX_SET => "OP_INDEX_SET",
+        OP_CONSTANT0 => "OP_CONSTANT0",
+        OP_JUMP_IF_RANGE_END => "OP_JUMP_IF_RANGE_END",
+        OP_COUNTUP => "OP_COUNTUP",
+        OP_RANGE => "OP_RANGE",
+        OP_GET_ITER => "OP_GET_ITER",
+        OP_YIELD => "OP_YIELD",
+        OP_ARRAY_PUSH => "OP_ARRAY_PUSH",
+        OP_CALL_NAMED => "OP_CALL_NAMED",
+        OP_CONSTANT_SMALL => "OP_CONSTANT_SMALL",
+        OP_CONTAINS => "OP_CONTAINS",
+        _ => return None,
+    };
+    Some(name)
+}
 
 #[derive(Debug, Clone, PartialEq, PartialOrd)]
 pub struct Chunk {
     code: Vec<u8>,
     constants: ConstantArray,
     lines: Vec<u32>,
+    // プレリュードや import したファイルから生成した命令の範囲 (開始, 終了)。
+    // 行番号は実行しているプログラムのソースの行ではないので、エラーの位置には使わない
+    external: Vec<(usize, usize)>,
 }
 
 impl Chunk {
@@ -60,6 +132,7 @@ impl Chunk {
             code: Vec::new(),
             constants: ConstantArray::new(),
             lines: Vec::new(),
+            external: Vec::new(),
         }
     }
 
@@ -127,6 +200,29 @@ impl Chunk {
         self.code.get_mut(index)
     }
 
+    pub fn get_line(&self, index: usize) -> Option<u32> {
+        self.lines.get(index).copied()
+    }
+
+    // start から現在の末尾までの命令を、他のソースから生成したものとして記録する
+    pub fn mark_external(&mut self, start: usize) {
+        if start < self.code.len() {
+            self.external.push((start, self.code.len()));
+        }
+    }
+
+    // 実行しているプログラムのソースでの行。他のソースから生成した命令は None
+    pub fn get_source_line(&self, index: usize) -> Option<u32> {
+        if self
+            .external
+            .iter()
+            .any(|(start, end)| (*start..*end).contains(&index))
+        {
+            return None;
+        }
+        self.get_line(index)
+    }
+
     pub fn get_instruction_len(&self) -> usize {
         self.code.len()
     }
@@ -143,83 +239,105 @@ impl Chunk {
         self.constants.get(index)
     }
 
+    pub fn get_constant_ref(&self, index: usize) -> &Value {
+        self.constants.get_ref(index)
+    }
+
     pub fn get_constant_len(&self) -> usize {
         self.constants.len()
     }
 
     pub fn disassemble(&self, name: &str) {
-        println!("== {} ==", name);
+        print!("{}", self.disassembly(name));
+    }
+
+    pub fn disassemble_instruction(&self, offset: usize) -> usize {
+        let mut out = String::new();
+        let offset = self.write_instruction(&mut out, offset).unwrap();
+        print!("{}", out);
+        offset
+    }
+
+    // 逆アセンブルの結果を文字列で返す。生成されたバイトコードの確認やテストで使う
+    pub fn disassembly(&self, name: &str) -> String {
+        let mut out = String::new();
+        // String への書き込みは失敗しない
+        self.write_disassembly(&mut out, name).unwrap();
+        out
+    }
+
+    pub fn write_disassembly(&self, out: &mut dyn Write, name: &str) -> fmt::Result {
+        writeln!(out, "== {} ==", name)?;
         let mut offset = 0;
         while offset < self.code.len() {
-            offset = self.disassemble_instruction(offset);
+            offset = self.write_instruction(out, offset)?;
+        }
+
+        // 定数に含まれる関数も続けて出力する
+        for index in 0..self.constants.len() {
+            if let Value::Function(function) = self.constants.get(index) {
+                function.chunk.write_disassembly(out, &function.name)?;
+            }
         }
+        Ok(())
     }
 
-    pub fn disassemble_instruction(&self, offset: usize) -> usize {
-        print!("{:04X}   | ", offset);
+    // 1 命令分を書き込み、次の命令の位置を返す
+    pub fn write_instruction(
+        &self,
+        out: &mut dyn Write,
+        offset: usize,
+    ) -> Result<usize, fmt::Error> {
+        // 直前の命令と同じ行なら行番号の代わりに | を表示する
+        let line = self.get_line(offset);
+        if offset > 0 && line == self.get_line(offset - 1) {
+            write!(out, "{:04X}      | ", offset)?;
+        } else {
+            match line {
+                Some(line) => write!(out, "{:04X} {:>4} | ", offset, line)?,
+                None => write!(out, "{:04X}    ? | ", offset)?,
+            }
+        }
         let instruction = match self.code.get(offset) {
             Some(inst) => inst,
             None => {
-                println!("out of code index.({:04X})", offset);
-                return 0;
-            },
+                writeln!(out, "out of code index.({:04X})", offset)?;
+                return Ok(0);
+            }
+        };
+
+        let name = match opcode_name(*instruction) {
+            Some(name) => name,
+            None => {
+                writeln!(out, "no match \"{:02X}\"", instruction)?;
+                return Ok(offset + 1);
+            }
         };
 
         match *instruction {
-            OP_RETURN => self.simple_instruction("OP_RETURN", offset),
-            OP_CONSTANT => self.constant_instruction("OP_CONSTANT", offset),
-            OP_NEGATIVE => self.simple_instruction("OP_NEGATIVE", offset),
-            OP_ADD => self.simple_instruction("OP_ADD", offset),
-            OP_SUBTRACT => self.simple_instruction("OP_SUBTRACT", offset),
-            OP_MULTIPLY => self.simple_instruction("OP_MULTIPLY", offset),
-            OP_DIVIDE => self.simple_instruction("OP_DIVIDE", offset),
-            OP_TRUE => self.simple_instruction("OP_TRUE", offset),
-            OP_FALSE => self.simple_instruction("OP_FALSE", offset),
-            OP_NULL => self.simple_instruction("OP_NULL", offset),
-            OP_NOT => self.simple_instruction("OP_NOT", offset),
-            OP_GREATER => self.simple_instruction("OP_GREATER", offset),
-            OP_LESS => self.simple_instruction("OP_LESS", offset),
-            OP_EQUAL => self.simple_instruction("OP_EQUAL", offset),
-            OP_PRINT => self.simple_instruction("OP_PRINT", offset),
-            OP_POP => self.simple_instruction("OP_POP", offset),
-            OP_DEFINE_GLOBAL => self.simple_instruction("OP_DEFINE_GLOBAL", offset),
-            OP_GET_GLOBAL => self.simple_instruction("OP_GET_GLOBAL", offset),
-            OP_SET_GLOBAL => self.simple_instruction("OP_SET_GLOBAL", offset),
-            OP_GET_LOCAL => self.simple_instruction("OP_GET_LOCAL", offset),
-            OP_SET_LOCAL => self.simple_instruction("OP_SET_LOCAL", offset),
-            OP_JUMP_IF_FALSE => self.jump_instruction("OP_JUMP_IF_FALSE", offset),
-            OP_JUMP => self.jump_instruction("OP_JUMP", offset),
-            OP_LOOP => self.loop_instruction("OP_LOOP", offset),
-            OP_CALL => self.simple_instruction("OP_CALL", offset),
-            OP_ARRAY => self.simple_instruction("OP_ARRAY", offset),
-            OP_INDEX_CALL => self.simple_instruction("OP_INDEX_CALL", offset),
-            OP_REM => self.simple_instruction("OP_REM", offset),
-            OP_POW => self.simple_instruction("OP_POW", offset),
-            OP_CLOSURE => self.simple_instruction("OP_CLOSURE", offset),
-            OP_CLOSE_UPVALUE => self.simple_instruction("OP_CLOSE_UPVALUE", offset),
-            OP_GET_UPVALUE => self.simple_instruction("OP_GET_UPVALUE", offset),
-            OP_SET_UPVALUE => self.simple_instruction("OP_SET_UPVALUE", offset),
-            OP_CLASS => self.simple_instruction("OP_CLASS", offset),
-            OP_GET_PROP => self.simple_instruction("OP_GET_PROP", offset),
-            OP_SET_PROP => self.simple_instruction("OP_SET_PROP", offset),
-            OP_METHOD => self.simple_instruction("OP_SET_PROP", offset),
-            OP_INVOKE => self.simple_instruction("OP_INVOKE", offset),
-            OP_INHERIT => self.simple_instruction("OP_INHERIT", offset),
-            OP_SUPER_INVOKE => self.simple_instruction("OP_SUPER_INVOKE", offset),
-            OP_GET_SUPER => self.simple_instruction("OP_SUPER_GET_PROP", offset),
-            OP_INDEX_SET => self.simple_instruction("OP_INDEX_SET", offset),
-            OP_CONSTANT0 => self.simple_instruction("OP_FOR", offset),
-            OP_JUMP_IF_RANGE_END => self.simple_instruction("OP_JUMP_IF_RANGE_END", offset),
-            OP_COUNTUP => self.simple_instruction("OP_COUNTUP", offset),
-            OP_RANGE => self.simple_instruction("OP_RANGE", offset),
-            _ => {
-                println!("no match \"{:02X}\"", instruction);
-                return offset + 1;
+            OP_CONSTANT | OP_DEFINE_GLOBAL | OP_GET_GLOBAL | OP_SET_GLOBAL | OP_CLASS
+            | OP_GET_PROP | OP_SET_PROP | OP_METHOD | OP_GET_SUPER => {
+                self.constant_instruction(out, name, offset)
+            }
+            OP_CLOSURE => self.closure_instruction(out, name, offset),
+            OP_GET_LOCAL | OP_SET_LOCAL | OP_CALL | OP_ARRAY | OP_GET_UPVALUE | OP_SET_UPVALUE
+            | OP_COUNTUP | OP_PRINT | OP_CONSTANT_SMALL => self.byte_instruction(out, name, offset),
+            OP_INVOKE | OP_SUPER_INVOKE => self.invoke_instruction(out, name, offset),
+            OP_CALL_NAMED => self.call_named_instruction(out, name, offset),
+            OP_JUMP_IF_FALSE | OP_JUMP | OP_JUMP_IF_RANGE_END => {
+                self.jump_instruction(out, name, offset)
             }
+            OP_LOOP => self.loop_instruction(out, name, offset),
+            _ => self.simple_instruction(out, name, offset),
         }
     }
 
-    fn constant_instruction(&self, name: &str, offset: usize) -> usize {
+    fn constant_instruction(
+        &self,
+        out: &mut dyn Write,
+        name: &str,
+        offset: usize,
+    ) -> Result<usize, fmt::Error> {
         let index = match self.code.get(offset + 1) {
             Some(i) => match i.clone().try_into() {
                 Ok(i) => i,
@@ -227,11 +345,120 @@ impl Chunk {
             },
             None => panic!("out of index constant value."),
         };
-        println!("{} {:?}", name, self.constants.get(index));
-        return offset + 2;
+        writeln!(out, "{} {:?}", name, self.constants.get(index))?;
+        return Ok(offset + 2);
+    }
+
+    fn byte_instruction(
+        &self,
+        out: &mut dyn Write,
+        name: &str,
+        offset: usize,
+    ) -> Result<usize, fmt::Error> {
+        let operand = match self.read_u8(offset + 1) {
+            Some(operand) => operand,
+            None => panic!("out of index operand value."),
+        };
+        writeln!(out, "{} {}", name, operand)?;
+        Ok(offset + 2)
+    }
+
+    // 定数(メソッド名)と引数の数を持つ命令
+    fn invoke_instruction(
+        &self,
+        out: &mut dyn Write,
+        name: &str,
+        offset: usize,
+    ) -> Result<usize, fmt::Error> {
+        let index = match self.read_u8(offset + 1) {
+            Some(index) => index as usize,
+            None => panic!("out of index constant value."),
+        };
+        let arg_count = match self.read_u8(offset + 2) {
+            Some(arg_count) => arg_count,
+            None => panic!("out of index arg count value."),
+        };
+        writeln!(
+            out,
+            "{} ({} args) {:?}",
+            name,
+            arg_count,
+            self.constants.get(index)
+        )?;
+        Ok(offset + 3)
+    }
+
+    // 位置引数の数、名前付き引数の数に続いて、名前付き引数ごとに名前の定数が並ぶ
+    fn call_named_instruction(
+        &self,
+        out: &mut dyn Write,
+        name: &str,
+        offset: usize,
+    ) -> Result<usize, fmt::Error> {
+        let positional = match self.read_u8(offset + 1) {
+            Some(count) => count,
+            None => panic!("out of index arg count value."),
+        };
+        let named = match self.read_u8(offset + 2) {
+            Some(count) => count as usize,
+            None => panic!("out of index arg count value."),
+        };
+        let names = (0..named)
+            .map(|i| match self.read_u8(offset + 3 + i) {
+                Some(index) => format!("{}", self.constants.get(index as usize)),
+                None => panic!("out of index constant value."),
+            })
+            .collect::<Vec<_>>();
+        writeln!(out, "{} ({} args) [{}]", name, positional, names.join(", "))?;
+        Ok(offset + 3 + named)
+    }
+
+    // 関数の定数に続いて、上位値ごとに is_local と index の2バイトが並ぶ
+    fn closure_instruction(
+        &self,
+        out: &mut dyn Write,
+        name: &str,
+        offset: usize,
+    ) -> Result<usize, fmt::Error> {
+        let index = match self.read_u8(offset + 1) {
+            Some(index) => index as usize,
+            None => panic!("out of index constant value."),
+        };
+        let function = self.constants.get(index);
+        writeln!(out, "{} {}", name, function)?;
+
+        let upvalue_count = match &function {
+            Value::Function(function) => function.upvalue_count,
+            _ => 0,
+        };
+        let mut offset = offset + 2;
+        for _ in 0..upvalue_count {
+            let is_local = match self.read_u8(offset) {
+                Some(is_local) => is_local,
+                None => panic!("out of index upvalue value."),
+            };
+            let index = match self.read_u8(offset + 1) {
+                Some(index) => index,
+                None => panic!("out of index upvalue value."),
+            };
+            writeln!(
+                out,
+                "{:04X}      |     {} {}",
+                offset,
+                if is_local == 1 { "local" } else { "upvalue" },
+                index
+            )?;
+            offset += 2;
+        }
+        Ok(offset)
     }
 
-    fn jump_instruction(&self, name: &str, offset: usize) -> usize {
+    fn jump_instruction(
+        &self,
+        out: &mut dyn Write,
+        name: &str,
+        offset: usize,
+    ) -> Result<usize, fmt::Error> {
         let index: usize = match self.read_u16(offset + 1) {
             Some(c) => match c.try_into() {
                 Ok(i) => i,
@@ -239,18 +466,24 @@ impl Chunk {
             },
             None => panic!("out of index jump offset value."),
         };
-        println!(
+        writeln!(
+            out,
             "{} ({:04X} + {:04X} + {:04X} -> {:04X})",
             name,
             offset,
             3,
             index,
             offset + 3 + index
-        );
-        return offset + 3;
+        )?;
+        return Ok(offset + 3);
     }
 
-    fn loop_instruction(&self, name: &str, offset: usize) -> usize {
+    fn loop_instruction(
+        &self,
+        out: &mut dyn Write,
+        name: &str,
+        offset: usize,
+    ) -> Result<usize, fmt::Error> {
         let index: usize = match self.read_u16(offset + 1) {
             Some(c) => match c.try_into() {
                 Ok(i) => i,
@@ -258,19 +491,25 @@ impl Chunk {
             },
             None => panic!("out of index jump offset value."),
         };
-        println!(
+        writeln!(
+            out,
             "{} ({:04X} + {:04X} - {:04X} -> {:04X})",
             name,
             offset,
             3,
             index,
             offset + 3 - index
-        );
-        return offset + 3;
+        )?;
+        return Ok(offset + 3);
     }
 
-    fn simple_instruction(&self, name: &str, offset: usize) -> usize {
-        println!("{}", name);
-        return offset + 1;
+    fn simple_instruction(
+        &self,
+        out: &mut dyn Write,
+        name: &str,
+        offset: usize,
+    ) -> Result<usize, fmt::Error> {
+        writeln!(out, "{}", name)?;
+        return Ok(offset + 1);
     }
 }
@@ -0,0 +1,73 @@
+use super::value::Value;
+use std::{cmp::Ordering, rc::Rc};
+
+// 集合などのキーとして使える値。
+// 順序は null < 真偽値 < 整数 < 浮動小数点数 < 文字列 で、同じ種類の中では値の順。
+#[derive(Debug, Clone)]
+pub enum Key {
+    Null,
+    Boolean(bool),
+    Integer(i64),
+    Float(f64),
+    String(Rc<String>),
+}
+
+impl Key {
+    // 配列やインスタンスなど、キーにできない値は None
+    pub fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::Null => Some(Key::Null),
+            Value::Boolean(b) => Some(Key::Boolean(*b)),
+            Value::Integer(i) => Some(Key::Integer(*i)),
+            Value::Float(f) => Some(Key::Float(*f)),
+            Value::String(s) => Some(Key::String(Rc::clone(s))),
+            _ => None,
+        }
+    }
+
+    pub fn to_value(&self) -> Value {
+        match self {
+            Key::Null => Value::Null,
+            Key::Boolean(b) => Value::Boolean(*b),
+            Key::Integer(i) => Value::Integer(*i),
+            Key::Float(f) => Value::Float(*f),
+            Key::String(s) => Value::String(Rc::clone(s)),
+        }
+    }
+
+    fn rank(&self) -> u8 {
+        match self {
+            Key::Null => 0,
+            Key::Boolean(_) => 1,
+            Key::Integer(_) => 2,
+            Key::Float(_) => 3,
+            Key::String(_) => 4,
+        }
+    }
+}
+
+impl PartialEq for Key {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Key {}
+
+impl PartialOrd for Key {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Key {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (Key::Boolean(a), Key::Boolean(b)) => a.cmp(b),
+            (Key::Integer(a), Key::Integer(b)) => a.cmp(b),
+            (Key::Float(a), Key::Float(b)) => a.total_cmp(b),
+            (Key::String(a), Key::String(b)) => a.cmp(b),
+            _ => self.rank().cmp(&other.rank()),
+        }
+    }
+}
@@ -0,0 +1,83 @@
+use super::{key::Key, value::Value};
+use std::{cmp::Ordering, collections::BTreeMap};
+
+// キーから値への対応。keys、values、for での繰り返しは、キーを最初に追加した順になる。
+// 既にあるキーの値を変えても順序は変わらず、削除したキーは順序から取り除く
+#[derive(Debug, Clone, Default)]
+pub struct OrderedMap {
+    entries: Vec<(Key, Value)>,
+    // キーから entries の位置
+    index: BTreeMap<Key, usize>,
+}
+
+impl OrderedMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn get(&self, key: &Key) -> Option<&Value> {
+        self.index.get(key).map(|i| &self.entries[*i].1)
+    }
+
+    pub fn contains_key(&self, key: &Key) -> bool {
+        self.index.contains_key(key)
+    }
+
+    // 新しいキーは末尾に追加する。既にあったキーなら前の値を返す
+    pub fn insert(&mut self, key: Key, value: Value) -> Option<Value> {
+        match self.index.get(&key) {
+            Some(i) => Some(std::mem::replace(&mut self.entries[*i].1, value)),
+            None => {
+                self.index.insert(key.clone(), self.entries.len());
+                self.entries.push((key, value));
+                None
+            }
+        }
+    }
+
+    // 後ろの要素を詰めるので、残りの順序は変わらない
+    pub fn remove(&mut self, key: &Key) -> Option<Value> {
+        let i = self.index.remove(key)?;
+        let (_, value) = self.entries.remove(i);
+        for position in self.index.values_mut() {
+            if *position > i {
+                *position -= 1;
+            }
+        }
+        Some(value)
+    }
+
+    // 追加した順の index 番目のキー。for での繰り返しに使う
+    pub fn key_at(&self, index: usize) -> Option<&Key> {
+        self.entries.get(index).map(|(key, _)| key)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Key, &Value)> {
+        self.entries.iter().map(|(key, value)| (key, value))
+    }
+}
+
+// 同じキーに同じ値が対応していれば等しい。追加した順は比べない
+impl PartialEq for OrderedMap {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len()
+            && self
+                .iter()
+                .all(|(key, value)| other.get(key) == Some(value))
+    }
+}
+
+// 大小の順序はないので、等しい場合だけ比べられる
+impl PartialOrd for OrderedMap {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        if self == other {
+            Some(Ordering::Equal)
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,37 @@
+use std::{cell::RefCell, io::Write, rc::Rc};
+
+// print の出力を溜めておくメモリ上のバッファ。
+// VM に渡した側とクローンを共有するので、実行後に内容を取り出せる。
+//
+//     let output = vm.capture_output();
+//     vm.interpret();
+//     assert_eq!(output.contents(), "465\n");
+#[derive(Debug, Clone, Default)]
+pub struct OutputBuffer {
+    buffer: Rc<RefCell<Vec<u8>>>,
+}
+
+impl OutputBuffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.buffer.borrow()).into_owned()
+    }
+
+    pub fn clear(&self) {
+        self.buffer.borrow_mut().clear();
+    }
+}
+
+impl Write for OutputBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.buffer.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
@@ -31,7 +31,19 @@ impl Table {
         self.identifer.insert(key, value)
     }
 
-    pub fn find(&mut self, key: &String) -> Option<&Value> {
+    pub fn find(&self, key: &String) -> Option<&Value> {
         self.identifer.get(key)
     }
+
+    pub fn get_mut(&mut self, key: &String) -> Option<&mut Value> {
+        self.identifer.get_mut(key)
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &Rc<String>> {
+        self.identifer.keys()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Rc<String>, &Value)> {
+        self.identifer.iter()
+    }
 }
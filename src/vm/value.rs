@@ -1,36 +1,117 @@
+use super::{key::Key, map::OrderedMap, VM};
 use crate::compiler::object::{
-    BoundMethodObject, ClassObject, ClosureObject, FunctionObject, InstanceObject,
+    BoundMethodObject, ClassObject, ClosureObject, FunctionObject, GeneratorObject, InstanceObject,
 };
 use chrono::{DateTime, Local as LocalTime};
 use std::{
     cell::RefCell,
+    cmp::Ordering,
+    collections::BTreeSet,
     fmt::Display,
     ops::{Add, Div, Mul, Neg, Not, Rem, Sub},
     rc::Rc,
 };
 
-#[derive(Debug, Clone, PartialEq, PartialOrd)]
+#[derive(Debug, Clone)]
 pub enum Value {
     Float(f64),
     Integer(i64),
     String(Rc<String>),
     Boolean(bool),
     Array(Rc<RefCell<Vec<Value>>>),
+    // 要素はキーの順序 (Key を参照) で並ぶ
+    Set(Rc<RefCell<BTreeSet<Key>>>),
+    // 要素はキーを追加した順で並ぶ (OrderedMap を参照)
+    Map(Rc<RefCell<OrderedMap>>),
+    // バイナリデータ。添字アクセスでは 0〜255 の整数になる
+    Bytes(Rc<RefCell<Vec<u8>>>),
     Function(Rc<FunctionObject>),
     Closure(Rc<ClosureObject>),
     Native {
         function: fn(args: &[Value]) -> Value,
     },
+    // VM を操作する必要があるネイティブ関数。エラーは実行時エラーになる
+    VmNative {
+        function: fn(vm: &mut VM, args: &[Value]) -> Result<Value, String>,
+    },
     DateTime(DateTime<LocalTime>),
     Class(Rc<RefCell<ClassObject>>),
     Instance(Rc<RefCell<InstanceObject>>),
     BoundMethod(Rc<RefCell<BoundMethodObject>>),
+    Generator(Rc<RefCell<GeneratorObject>>),
     Null,
 }
 
+// ネイティブ関数は関数ポインタのアドレスで比べる。
+// 同じ関数でもアドレスが一致する保証はないため、等しくないと判定されることがある
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Value::Float(a), Value::Float(b)) => a == b,
+            (Value::Integer(a), Value::Integer(b)) => a == b,
+            (Value::String(a), Value::String(b)) => a == b,
+            (Value::Boolean(a), Value::Boolean(b)) => a == b,
+            (Value::Array(a), Value::Array(b)) => a == b,
+            (Value::Set(a), Value::Set(b)) => a == b,
+            (Value::Map(a), Value::Map(b)) => a == b,
+            (Value::Bytes(a), Value::Bytes(b)) => a == b,
+            (Value::Function(a), Value::Function(b)) => a == b,
+            (Value::Closure(a), Value::Closure(b)) => a == b,
+            (Value::Native { function: a }, Value::Native { function: b }) => {
+                std::ptr::fn_addr_eq(*a, *b)
+            }
+            (Value::VmNative { function: a }, Value::VmNative { function: b }) => {
+                std::ptr::fn_addr_eq(*a, *b)
+            }
+            (Value::DateTime(a), Value::DateTime(b)) => a == b,
+            (Value::Class(a), Value::Class(b)) => a == b,
+            (Value::Instance(a), Value::Instance(b)) => a == b,
+            (Value::BoundMethod(a), Value::BoundMethod(b)) => a == b,
+            (Value::Generator(a), Value::Generator(b)) => a == b,
+            (Value::Null, Value::Null) => true,
+            _ => false,
+        }
+    }
+}
+
+// 同じ種類の値同士だけ順序を持つ。ネイティブ関数は等しいかどうかしか分からない
+impl PartialOrd for Value {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        match (self, other) {
+            (Value::Float(a), Value::Float(b)) => a.partial_cmp(b),
+            (Value::Integer(a), Value::Integer(b)) => a.partial_cmp(b),
+            (Value::String(a), Value::String(b)) => a.partial_cmp(b),
+            (Value::Boolean(a), Value::Boolean(b)) => a.partial_cmp(b),
+            (Value::Array(a), Value::Array(b)) => a.partial_cmp(b),
+            (Value::Set(a), Value::Set(b)) => a.partial_cmp(b),
+            (Value::Map(a), Value::Map(b)) => a.partial_cmp(b),
+            (Value::Bytes(a), Value::Bytes(b)) => a.partial_cmp(b),
+            (Value::Function(a), Value::Function(b)) => a.partial_cmp(b),
+            (Value::Closure(a), Value::Closure(b)) => a.partial_cmp(b),
+            (Value::DateTime(a), Value::DateTime(b)) => a.partial_cmp(b),
+            (Value::Class(a), Value::Class(b)) => a.partial_cmp(b),
+            (Value::Instance(a), Value::Instance(b)) => a.partial_cmp(b),
+            (Value::BoundMethod(a), Value::BoundMethod(b)) => a.partial_cmp(b),
+            (Value::Generator(a), Value::Generator(b)) => a.partial_cmp(b),
+            (Value::Null, Value::Null) => Some(Ordering::Equal),
+            (Value::Native { .. }, Value::Native { .. })
+            | (Value::VmNative { .. }, Value::VmNative { .. })
+                if self == other =>
+            {
+                Some(Ordering::Equal)
+            }
+            _ => None,
+        }
+    }
+}
+
 impl Display for Value {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
+            // 整数と区別できるように、整数値の浮動小数点数にも小数点を付ける (2.0)
+            Value::Float(value) if value.is_finite() && value.fract() == 0.0 => {
+                write!(f, "{:.1}", value)
+            }
             Value::Float(value) => write!(f, "{}", value),
             Value::Integer(value) => write!(f, "{}", value),
             Value::String(value) => write!(f, "{}", value),
@@ -45,6 +126,36 @@ impl Display for Value {
                     .collect::<Vec<_>>()
                     .join(", ")
             ),
+            Value::Set(value) => write!(
+                f,
+                "{{{}}}",
+                value
+                    .borrow()
+                    .iter()
+                    .map(|v| format!("{}", v.to_value()))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            Value::Map(value) => write!(
+                f,
+                "{{{}}}",
+                value
+                    .borrow()
+                    .iter()
+                    .map(|(k, v)| format!("{}: {}", k.to_value(), v))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            Value::Bytes(value) => write!(
+                f,
+                "bytes[{}]",
+                value
+                    .borrow()
+                    .iter()
+                    .map(|b| format!("{:02x}", b))
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            ),
             Value::Function(value) => {
                 write!(f, "function {} param_len={}", value.name, value.arity)
             }
@@ -58,6 +169,7 @@ impl Display for Value {
                 )
             }
             Value::Native { function } => write!(f, "native function {:?}", function),
+            Value::VmNative { function } => write!(f, "native function {:?}", function),
             Value::DateTime(value) => write!(f, "{}", value.format("%Y/%m/%d %H:%M:%S.%6f")),
             Value::Class(value) => write!(f, "class {}", value.borrow().name),
             Value::Instance(value) => write!(
@@ -71,6 +183,9 @@ impl Display for Value {
                 &value.borrow().reciever,
                 &value.borrow().method.function.name
             ),
+            Value::Generator(value) => {
+                write!(f, "generator {}", value.borrow().closure.function.name)
+            }
             Value::Null => write!(f, "null"),
         }
     }
@@ -81,6 +196,31 @@ pub enum CalcError {
     Invalid(String),
 }
 
+impl CalcError {
+    // 演算できない型の組み合わせ。演算の名前と両辺の型を示す (例: cannot add boolean and integer: true + 1)
+    pub fn unsupported(operation: &str, symbol: &str, a: &Value, b: &Value) -> Self {
+        CalcError::Invalid(format!(
+            "cannot {} {} and {}: {} {} {}",
+            operation,
+            a.type_name(),
+            b.type_name(),
+            a,
+            symbol,
+            b
+        ))
+    }
+
+    pub fn unsupported_unary(operation: &str, symbol: &str, a: &Value) -> Self {
+        CalcError::Invalid(format!(
+            "cannot {} {}: {}{}",
+            operation,
+            a.type_name(),
+            symbol,
+            a
+        ))
+    }
+}
+
 impl Display for CalcError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -103,17 +243,158 @@ impl Value {
             _ => false,
         }
     }
+
+    // 配列ならその中身を返す。配列を受け取る命令やネイティブ関数で使う
+    pub fn as_array(&self) -> Option<Rc<RefCell<Vec<Value>>>> {
+        match self {
+            Value::Array(array) => Some(Rc::clone(array)),
+            _ => None,
+        }
+    }
+
+    // 要素数。文字列はバイト数ではなく文字数を返す。長さを持たない値は None
+    pub fn len(&self) -> Option<usize> {
+        match self {
+            Value::Array(array) => Some(array.borrow().len()),
+            Value::String(string) => Some(string.chars().count()),
+            Value::Set(set) => Some(set.borrow().len()),
+            Value::Map(map) => Some(map.borrow().len()),
+            Value::Bytes(bytes) => Some(bytes.borrow().len()),
+            _ => None,
+        }
+    }
+
+    // item が含まれるかどうか。配列は要素、文字列は部分文字列、集合とマップはキーを調べる
+    pub fn contains(&self, item: &Value) -> Result<bool, CalcError> {
+        match (self, item) {
+            (Value::Array(array), item) => Ok(array.borrow().contains(item)),
+            (Value::String(s), Value::String(item)) => Ok(s.contains(item.as_str())),
+            (Value::Set(set), item) => Ok(match Key::from_value(item) {
+                Some(key) => set.borrow().contains(&key),
+                None => false,
+            }),
+            (Value::Map(map), item) => Ok(match Key::from_value(item) {
+                Some(key) => map.borrow().contains_key(&key),
+                None => false,
+            }),
+            (Value::Bytes(bytes), Value::Integer(b)) => {
+                Ok((0..=255).contains(b) && bytes.borrow().contains(&(*b as u8)))
+            }
+            (container, item) => Err(CalcError::Invalid(format!(
+                "cannot test membership of {} in {}: {} in {}",
+                item.type_name(),
+                container.type_name(),
+                item,
+                container
+            ))),
+        }
+    }
+
+    // エラーメッセージなどで使う型名
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Float(_) => "float",
+            Value::Integer(_) => "integer",
+            Value::String(_) => "string",
+            Value::Boolean(_) => "boolean",
+            Value::Array(_) => "array",
+            Value::Set(_) => "set",
+            Value::Map(_) => "map",
+            Value::Bytes(_) => "bytes",
+            Value::Function(_) => "function",
+            Value::Closure(_) => "closure",
+            Value::Native { .. } | Value::VmNative { .. } => "native function",
+            Value::DateTime(_) => "datetime",
+            Value::Class(_) => "class",
+            Value::Instance(_) => "instance",
+            Value::BoundMethod(_) => "method",
+            Value::Generator(_) => "generator",
+            Value::Null => "null",
+        }
+    }
+
+    // デバッグ用の表示。文字列は引用符で囲み、インスタンスはフィールドの値も表示する。
+    // 自分自身を含む配列、マップ、インスタンスは、2 度目に出てきたところを ... で省略する
+    pub fn repr(&self) -> String {
+        let mut visiting = Vec::new();
+        self.repr_inner(&mut visiting)
+    }
+
+    fn repr_inner(&self, visiting: &mut Vec<*const ()>) -> String {
+        match self {
+            Value::String(value) => format!("{:?}", value),
+            Value::Array(value) => {
+                let ptr = Rc::as_ptr(value) as *const ();
+                if visiting.contains(&ptr) {
+                    return "[...]".to_string();
+                }
+                visiting.push(ptr);
+                let elements = value
+                    .borrow()
+                    .iter()
+                    .map(|v| v.repr_inner(visiting))
+                    .collect::<Vec<_>>();
+                visiting.pop();
+                format!("[{}]", elements.join(", "))
+            }
+            Value::Set(value) => format!(
+                "{{{}}}",
+                value
+                    .borrow()
+                    .iter()
+                    .map(|v| v.to_value().repr_inner(visiting))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            Value::Map(value) => {
+                let ptr = Rc::as_ptr(value) as *const ();
+                if visiting.contains(&ptr) {
+                    return "{...}".to_string();
+                }
+                visiting.push(ptr);
+                let entries = value
+                    .borrow()
+                    .iter()
+                    .map(|(k, v)| {
+                        format!(
+                            "{}: {}",
+                            k.to_value().repr_inner(visiting),
+                            v.repr_inner(visiting)
+                        )
+                    })
+                    .collect::<Vec<_>>();
+                visiting.pop();
+                format!("{{{}}}", entries.join(", "))
+            }
+            Value::Instance(value) => {
+                let instance = value.borrow();
+                let class_name = instance.class.borrow().name.clone();
+                let ptr = Rc::as_ptr(value) as *const ();
+                if visiting.contains(&ptr) {
+                    return format!("{}(...)", class_name);
+                }
+                visiting.push(ptr);
+                // フィールドの順序は決まっていないので名前順に並べる
+                let mut fields = instance.fields.iter().collect::<Vec<_>>();
+                fields.sort_by(|a, b| a.0.cmp(b.0));
+                let fields = fields
+                    .into_iter()
+                    .map(|(name, v)| format!("{}: {}", name, v.repr_inner(visiting)))
+                    .collect::<Vec<_>>();
+                visiting.pop();
+                format!("{}({})", class_name, fields.join(", "))
+            }
+            other => format!("{}", other),
+        }
+    }
 }
 
+// 値を持つ側の演算は参照の演算に任せる。エラーの文言を 1 か所にまとめるため
 impl Neg for Value {
     type Output = Result<Self, CalcError>;
 
     fn neg(self) -> Self::Output {
-        match self {
-            Value::Float(a) => Ok(Value::Float(-a)),
-            Value::Integer(a) => Ok(Value::Integer(-a)),
-            other @ _ => Err(CalcError::Invalid(format!("-{}", other))),
-        }
+        -&self
     }
 }
 
@@ -124,7 +405,7 @@ impl<'a> Neg for &'a Value {
         match self {
             Value::Float(a) => Ok(Value::Float(-a)),
             Value::Integer(a) => Ok(Value::Integer(-a)),
-            other @ _ => Err(CalcError::Invalid(format!("-{}", other))),
+            other @ _ => Err(CalcError::unsupported_unary("negate", "-", other)),
         }
     }
 }
@@ -149,37 +430,7 @@ impl Add for Value {
     type Output = Result<Self, CalcError>;
 
     fn add(self, rhs: Self) -> Self::Output {
-        match self {
-            Value::Float(a) => match rhs {
-                Value::Float(b) => Ok(Value::Float(a + b)),
-                Value::Integer(b) => Ok(Value::Float(a + (b as f64))),
-                Value::String(b) => Ok(Value::String(Rc::new(format!("{}{}", a, b)))),
-                other @ _ => Err(CalcError::Invalid(format!("{} + {}", self, other))),
-            },
-            Value::Integer(a) => match rhs {
-                Value::Float(b) => Ok(Value::Float((a as f64) + b)),
-                Value::Integer(b) => Ok(Value::Integer(a + b)),
-                Value::String(b) => Ok(Value::String(Rc::new(format!("{}{}", a, b)))),
-                other @ _ => Err(CalcError::Invalid(format!("{} + {}", self, other))),
-            },
-            Value::String(a) => match rhs {
-                Value::Float(b) => Ok(Value::String(Rc::new(format!("{}{}", a, b)))),
-                Value::Integer(b) => Ok(Value::String(Rc::new(format!("{}{}", a, b)))),
-                Value::String(b) => Ok(Value::String(Rc::new(format!("{}{}", a, b)))),
-                Value::Boolean(b) => Ok(Value::String(Rc::new(format!("{}{}", a, b)))),
-                b @ Value::Array(_) => Ok(Value::String(Rc::new(format!("{}{}", a, b)))),
-                other @ _ => Err(CalcError::Invalid(format!(
-                    "{} + {}",
-                    Value::String(a),
-                    other
-                ))),
-            },
-            Value::Boolean(a) => match rhs {
-                Value::String(b) => Ok(Value::String(Rc::new(format!("{}{}", a, b)))),
-                other @ _ => Err(CalcError::Invalid(format!("{} + {}", self, other))),
-            },
-            a @ _ => Err(CalcError::Invalid(format!("{} + {}", a, rhs))),
-        }
+        &self + &rhs
     }
 }
 
@@ -191,32 +442,44 @@ impl<'a> Add for &'a Value {
             Value::Float(a) => match rhs {
                 Value::Float(b) => Ok(Value::Float(a + b)),
                 Value::Integer(b) => Ok(Value::Float(a + (*b as f64))),
-                Value::String(b) => Ok(Value::String(Rc::new(format!("{}{}", a, b)))),
-                other @ _ => Err(CalcError::Invalid(format!("{} + {}", self, other))),
+                Value::String(b) => Ok(Value::String(Rc::new(format!("{}{}", self, b)))),
+                other @ _ => Err(CalcError::unsupported("add", "+", self, other)),
             },
             Value::Integer(a) => match rhs {
                 Value::Float(b) => Ok(Value::Float((*a as f64) + b)),
                 Value::Integer(b) => Ok(Value::Integer(a + b)),
                 Value::String(b) => Ok(Value::String(Rc::new(format!("{}{}", a, b)))),
-                other @ _ => Err(CalcError::Invalid(format!("{} + {}", self, other))),
+                other @ _ => Err(CalcError::unsupported("add", "+", self, other)),
             },
             Value::String(a) => match rhs {
-                Value::Float(b) => Ok(Value::String(Rc::new(format!("{}{}", a, b)))),
+                Value::Float(_) => Ok(Value::String(Rc::new(format!("{}{}", a, rhs)))),
                 Value::Integer(b) => Ok(Value::String(Rc::new(format!("{}{}", a, b)))),
                 Value::String(b) => Ok(Value::String(Rc::new(format!("{}{}", a, b)))),
                 Value::Boolean(b) => Ok(Value::String(Rc::new(format!("{}{}", a, b)))),
                 b @ Value::Array(_) => Ok(Value::String(Rc::new(format!("{}{}", a, b)))),
-                other @ _ => Err(CalcError::Invalid(format!(
-                    "{} + {}",
-                    Value::String(a.clone()),
-                    other
-                ))),
+                other @ _ => Err(CalcError::unsupported(
+                    "add",
+                    "+",
+                    &Value::String(a.clone()),
+                    other,
+                )),
             },
             Value::Boolean(a) => match rhs {
                 Value::String(b) => Ok(Value::String(Rc::new(format!("{}{}", a, b)))),
-                other @ _ => Err(CalcError::Invalid(format!("{} + {}", self, other))),
+                other @ _ => Err(CalcError::unsupported("add", "+", self, other)),
+            },
+            Value::Set(a) => match rhs {
+                Value::Set(b) => Ok(Value::Set(Rc::new(RefCell::new(
+                    a.borrow().union(&b.borrow()).cloned().collect(),
+                )))),
+                other => Err(CalcError::unsupported(
+                    "add",
+                    "+",
+                    &Value::Set(a.clone()),
+                    other,
+                )),
             },
-            a @ _ => Err(CalcError::Invalid(format!("{} + {}", a, rhs))),
+            a @ _ => Err(CalcError::unsupported("add", "+", a, rhs)),
         }
     }
 }
@@ -225,19 +488,7 @@ impl Sub for Value {
     type Output = Result<Self, CalcError>;
 
     fn sub(self, rhs: Self) -> Self::Output {
-        match self {
-            Value::Float(a) => match rhs {
-                Value::Float(b) => Ok(Value::Float(a - b)),
-                Value::Integer(b) => Ok(Value::Float(a - (b as f64))),
-                other @ _ => Err(CalcError::Invalid(format!("{} - {}", self, other))),
-            },
-            Value::Integer(a) => match rhs {
-                Value::Float(b) => Ok(Value::Float((a as f64) - b)),
-                Value::Integer(b) => Ok(Value::Integer(a - b)),
-                other @ _ => Err(CalcError::Invalid(format!("{} - {}", self, other))),
-            },
-            other @ _ => Err(CalcError::Invalid(format!("{} - {}", other, rhs))),
-        }
+        &self - &rhs
     }
 }
 
@@ -249,14 +500,25 @@ impl<'a> Sub for &'a Value {
             Value::Float(a) => match rhs {
                 Value::Float(b) => Ok(Value::Float(a - b)),
                 Value::Integer(b) => Ok(Value::Float(a - (*b as f64))),
-                other @ _ => Err(CalcError::Invalid(format!("{} - {}", self, other))),
+                other @ _ => Err(CalcError::unsupported("subtract", "-", self, other)),
             },
             Value::Integer(a) => match rhs {
                 Value::Float(b) => Ok(Value::Float((*a as f64) - b)),
                 Value::Integer(b) => Ok(Value::Integer(a - b)),
-                other @ _ => Err(CalcError::Invalid(format!("{} - {}", self, other))),
+                other @ _ => Err(CalcError::unsupported("subtract", "-", self, other)),
             },
-            other @ _ => Err(CalcError::Invalid(format!("{} - {}", other, rhs))),
+            Value::Set(a) => match rhs {
+                Value::Set(b) => Ok(Value::Set(Rc::new(RefCell::new(
+                    a.borrow().difference(&b.borrow()).cloned().collect(),
+                )))),
+                other => Err(CalcError::unsupported(
+                    "subtract",
+                    "-",
+                    &Value::Set(a.clone()),
+                    other,
+                )),
+            },
+            other @ _ => Err(CalcError::unsupported("subtract", "-", other, rhs)),
         }
     }
 }
@@ -265,19 +527,7 @@ impl Mul for Value {
     type Output = Result<Self, CalcError>;
 
     fn mul(self, rhs: Self) -> Self::Output {
-        match self {
-            Value::Float(a) => match rhs {
-                Value::Float(b) => Ok(Value::Float(a * b)),
-                Value::Integer(b) => Ok(Value::Float(a * (b as f64))),
-                other @ _ => Err(CalcError::Invalid(format!("{} - {}", self, other))),
-            },
-            Value::Integer(a) => match rhs {
-                Value::Float(b) => Ok(Value::Float((a as f64) * b)),
-                Value::Integer(b) => Ok(Value::Integer(a * b)),
-                other @ _ => Err(CalcError::Invalid(format!("{} - {}", self, other))),
-            },
-            other @ _ => Err(CalcError::Invalid(format!("{} - {}", other, rhs))),
-        }
+        &self * &rhs
     }
 }
 
@@ -289,14 +539,25 @@ impl<'a> Mul for &'a Value {
             Value::Float(a) => match rhs {
                 Value::Float(b) => Ok(Value::Float(a * b)),
                 Value::Integer(b) => Ok(Value::Float(a * (*b as f64))),
-                other @ _ => Err(CalcError::Invalid(format!("{} - {}", self, other))),
+                other @ _ => Err(CalcError::unsupported("multiply", "*", self, other)),
             },
             Value::Integer(a) => match rhs {
                 Value::Float(b) => Ok(Value::Float((*a as f64) * b)),
                 Value::Integer(b) => Ok(Value::Integer(a * b)),
-                other @ _ => Err(CalcError::Invalid(format!("{} - {}", self, other))),
+                other @ _ => Err(CalcError::unsupported("multiply", "*", self, other)),
             },
-            other @ _ => Err(CalcError::Invalid(format!("{} - {}", other, rhs))),
+            Value::Set(a) => match rhs {
+                Value::Set(b) => Ok(Value::Set(Rc::new(RefCell::new(
+                    a.borrow().intersection(&b.borrow()).cloned().collect(),
+                )))),
+                other => Err(CalcError::unsupported(
+                    "multiply",
+                    "*",
+                    &Value::Set(a.clone()),
+                    other,
+                )),
+            },
+            other @ _ => Err(CalcError::unsupported("multiply", "*", other, rhs)),
         }
     }
 }
@@ -305,19 +566,7 @@ impl Div for Value {
     type Output = Result<Self, CalcError>;
 
     fn div(self, rhs: Self) -> Self::Output {
-        match self {
-            Value::Float(a) => match rhs {
-                Value::Float(b) => Ok(Value::Float(a / b)),
-                Value::Integer(b) => Ok(Value::Float(a / (b as f64))),
-                other @ _ => Err(CalcError::Invalid(format!("{} - {}", self, other))),
-            },
-            Value::Integer(a) => match rhs {
-                Value::Float(b) => Ok(Value::Float((a as f64) / b)),
-                Value::Integer(b) => Ok(Value::Integer(a / b)),
-                other @ _ => Err(CalcError::Invalid(format!("{} - {}", self, other))),
-            },
-            other @ _ => Err(CalcError::Invalid(format!("{} - {}", other, rhs))),
-        }
+        &self / &rhs
     }
 }
 
@@ -329,14 +578,14 @@ impl<'a> Div for &'a Value {
             Value::Float(a) => match rhs {
                 Value::Float(b) => Ok(Value::Float(a / b)),
                 Value::Integer(b) => Ok(Value::Float(a / (*b as f64))),
-                other @ _ => Err(CalcError::Invalid(format!("{} - {}", self, other))),
+                other @ _ => Err(CalcError::unsupported("divide", "/", self, other)),
             },
             Value::Integer(a) => match rhs {
                 Value::Float(b) => Ok(Value::Float((*a as f64) / b)),
                 Value::Integer(b) => Ok(Value::Integer(a / b)),
-                other @ _ => Err(CalcError::Invalid(format!("{} - {}", self, other))),
+                other @ _ => Err(CalcError::unsupported("divide", "/", self, other)),
             },
-            other @ _ => Err(CalcError::Invalid(format!("{} - {}", other, rhs))),
+            other @ _ => Err(CalcError::unsupported("divide", "/", other, rhs)),
         }
     }
 }
@@ -345,19 +594,7 @@ impl Rem for Value {
     type Output = Result<Self, CalcError>;
 
     fn rem(self, rhs: Self) -> Self::Output {
-        match self {
-            Value::Float(a) => match rhs {
-                Value::Float(b) => Ok(Value::Float(a % b)),
-                Value::Integer(b) => Ok(Value::Float(a % (b as f64))),
-                other @ _ => Err(CalcError::Invalid(format!("{} - {}", self, other))),
-            },
-            Value::Integer(a) => match rhs {
-                Value::Float(b) => Ok(Value::Float((a as f64) % b)),
-                Value::Integer(b) => Ok(Value::Integer(a % b)),
-                other @ _ => Err(CalcError::Invalid(format!("{} - {}", self, other))),
-            },
-            other @ _ => Err(CalcError::Invalid(format!("{} - {}", other, rhs))),
-        }
+        &self % &rhs
     }
 }
 
@@ -369,19 +606,62 @@ impl<'a> Rem for &'a Value {
             Value::Float(a) => match rhs {
                 Value::Float(b) => Ok(Value::Float(a % b)),
                 Value::Integer(b) => Ok(Value::Float(a % (*b as f64))),
-                other @ _ => Err(CalcError::Invalid(format!("{} - {}", self, other))),
+                other @ _ => Err(CalcError::unsupported(
+                    "take the remainder of",
+                    "%",
+                    self,
+                    other,
+                )),
             },
             Value::Integer(a) => match rhs {
                 Value::Float(b) => Ok(Value::Float((*a as f64) % b)),
                 Value::Integer(b) => Ok(Value::Integer(a % b)),
-                other @ _ => Err(CalcError::Invalid(format!("{} - {}", self, other))),
+                other @ _ => Err(CalcError::unsupported(
+                    "take the remainder of",
+                    "%",
+                    self,
+                    other,
+                )),
             },
-            other @ _ => Err(CalcError::Invalid(format!("{} - {}", other, rhs))),
+            other @ _ => Err(CalcError::unsupported(
+                "take the remainder of",
+                "%",
+                other,
+                rhs,
+            )),
         }
     }
 }
 
+// デバッグビルドでは範囲外のアクセスを位置付きで panic させ、リリースビルドでは検査を省く。
+// len は配列の大きさではなく、値が入っている範囲の長さ
+#[inline(always)]
+pub fn slot<'a, T>(values: &'a [T], index: usize, len: usize, name: &str) -> &'a T {
+    if cfg!(debug_assertions) {
+        match values[..len].get(index) {
+            Some(value) => value,
+            None => panic!("{} index out of range.({} >= {})", name, index, len),
+        }
+    } else {
+        unsafe { values.get_unchecked(index) }
+    }
+}
+
+#[inline(always)]
+pub fn slot_mut<'a, T>(values: &'a mut [T], index: usize, len: usize, name: &str) -> &'a mut T {
+    if cfg!(debug_assertions) {
+        match values[..len].get_mut(index) {
+            Some(value) => value,
+            None => panic!("{} index out of range.({} >= {})", name, index, len),
+        }
+    } else {
+        unsafe { values.get_unchecked_mut(index) }
+    }
+}
+
 const CONSTANT_LEN: usize = 256;
+// 上限を超えたかどうかは命令の実行前に調べるので、1命令で積む分だけ上限より多く確保しておく
+const STACK_MARGIN: usize = 16;
 
 #[derive(Debug, Clone, PartialEq, PartialOrd)]
 pub struct ConstantArray {
@@ -403,7 +683,11 @@ impl ConstantArray {
     }
 
     pub fn get(&self, index: usize) -> Value {
-        unsafe { self.values.get_unchecked(index).clone() }
+        self.get_ref(index).clone()
+    }
+
+    pub fn get_ref(&self, index: usize) -> &Value {
+        slot(&self.values, index, self.index, "constant")
     }
 
     pub fn len(&self) -> usize {
@@ -411,39 +695,60 @@ impl ConstantArray {
     }
 }
 
-const STACK_LEN: usize = 256;
-
 #[derive(Debug, Clone)]
 pub struct StackArray {
-    values: [Value; STACK_LEN],
+    values: Box<[Value]>,
     index: usize,
+    // 積める値の数。超える前に VM が実行時エラーにする
+    max: usize,
 }
 
 impl StackArray {
-    pub fn new() -> Self {
+    pub fn new(size: usize) -> Self {
         Self {
-            values: vec![Value::Null; STACK_LEN].try_into().unwrap(),
+            values: vec![Value::Null; size + STACK_MARGIN].into_boxed_slice(),
             index: 0,
+            max: size,
         }
     }
 
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
     pub fn push(&mut self, value: Value) {
-        self.values[self.index] = value;
+        match self.values.get_mut(self.index) {
+            Some(slot) => *slot = value,
+            None => panic!("stack overflow.({} values)", self.values.len()),
+        }
         self.index += 1;
     }
 
+    // 取り出した位置には Null を残す。複製しないので参照カウントも増減しない
     pub fn pop(&mut self) -> Value {
-        self.index -= 1;
-        unsafe { self.values.get_unchecked(self.index).clone() }
+        self.index = self.top_index();
+        std::mem::replace(
+            slot_mut(&mut self.values, self.index, self.index + 1, "stack"),
+            Value::Null,
+        )
     }
 
     pub fn pop_index(&mut self) {
-        self.index -= 1;
+        self.index = self.top_index();
     }
 
     pub fn last(&self) -> &Value {
-        let index = self.index - 1;
-        unsafe { self.values.get_unchecked(index) }
+        let index = self.top_index();
+        slot(&self.values, index, self.index, "stack")
+    }
+
+    // 先頭の値の位置。空のスタックから取り出すのはコンパイラか命令列の不具合なので、
+    // 範囲外を読む前にその場で止める
+    fn top_index(&self) -> usize {
+        match self.index.checked_sub(1) {
+            Some(index) => index,
+            None => panic!("stack underflow."),
+        }
     }
 
     pub fn set_index(&mut self, index: usize) {
@@ -451,11 +756,11 @@ impl StackArray {
     }
 
     pub fn get(&self, index: usize) -> &Value {
-        unsafe { self.values.get_unchecked(index) }
+        slot(&self.values, index, self.index, "stack")
     }
 
     pub fn get_mut(&mut self, index: usize) -> &mut Value {
-        unsafe { self.values.get_unchecked_mut(index) }
+        slot_mut(&mut self.values, index, self.index, "stack")
     }
 
     pub fn len(&self) -> usize {